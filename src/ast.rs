@@ -15,6 +15,7 @@ pub enum Stmt<'a> {
     Return(Return<'a>),
     Block(Block<'a>),
     PutAt(PutAt<'a>),
+    With(With<'a>),
 }
 
 pub struct Import<'a>(pub Idents<'a>);
@@ -29,11 +30,13 @@ pub enum Term<'a> {
     Color(Color),
     Idents(Idents<'a>),
     Coord(Box<Coord<'a>>),
+    List(Vec<Term<'a>>),
     BinOp(Box<BinTerm<'a>>),
     UnOp(Box<UnTerm<'a>>),
     FnCall(Box<FnCall<'a>>),
     FnDef(FnDef<'a>),
     Block(Block<'a>),
+    Record(Vec<Assign<'a>>),
 }
 
 pub struct Num(pub f64, pub Option<Unit>);
@@ -78,9 +81,13 @@ pub enum UnOp {
     Neg,
 }
 
-pub struct FnCall<'a>(pub Term<'a>, pub Vec<Term<'a>>);
+/// A call with positional arguments, followed by named arguments.
+pub struct FnCall<'a>(pub Term<'a>, pub Vec<Term<'a>>, pub Vec<Assign<'a>>);
 
-pub struct FnDef<'a>(pub Vec<&'a str>, pub Block<'a>);
+pub struct FnDef<'a>(pub Vec<Param<'a>>, pub Block<'a>);
+
+/// A function parameter, with an optional default value.
+pub struct Param<'a>(pub &'a str, pub Option<Term<'a>>);
 
 pub struct Block<'a>(pub Vec<Stmt<'a>>);
 
@@ -88,6 +95,10 @@ pub struct Return<'a>(pub Term<'a>);
 
 pub struct PutAt<'a>(pub Term<'a>, pub Term<'a>);
 
+/// A block evaluated with some variables set, whose content is placed in the
+/// enclosing frame.
+pub struct With<'a>(pub Vec<Assign<'a>>, pub Block<'a>);
+
 // Pretty-printers.
 
 impl<'a> Print for Document<'a> {
@@ -107,6 +118,7 @@ impl<'a> Print for Stmt<'a> {
             Stmt::Return(ref r) => f.print(r),
             Stmt::Block(ref bk) => f.print(bk),
             Stmt::PutAt(ref pa) => f.print(pa),
+            Stmt::With(ref w) => f.print(w),
         }
     }
 }
@@ -147,11 +159,29 @@ impl<'a> Print for Term<'a> {
             Term::Color(ref col) => f.print(col),
             Term::Idents(ref is) => f.print(is),
             Term::Coord(ref coo) => f.print(coo),
+            Term::List(ref lst) => {
+                f.print("[");
+                let mut first = true;
+                for elem in lst {
+                    if !first { f.print(", "); }
+                    f.print(elem);
+                    first = false;
+                }
+                f.print("]");
+            }
             Term::BinOp(ref bop) => f.print(bop),
             Term::UnOp(ref unop) => f.print(unop),
             Term::FnCall(ref fc) => f.print(fc),
             Term::FnDef(ref fdf) => f.print(fdf),
             Term::Block(ref blk) => f.print(blk),
+            Term::Record(ref fields) => {
+                f.print("{ ");
+                for field in fields {
+                    f.print(field);
+                    f.print(", ");
+                }
+                f.print("}");
+            }
         }
     }
 }
@@ -247,6 +277,11 @@ impl<'a> Print for FnCall<'a> {
             f.print(arg);
             first = false;
         }
+        for arg in &self.2 {
+            if !first { f.print(", "); }
+            f.print(arg);
+            first = false;
+        }
         f.print(")");
     }
 }
@@ -265,6 +300,16 @@ impl<'a> Print for FnDef<'a> {
     }
 }
 
+impl<'a> Print for Param<'a> {
+    fn print(&self, f: &mut Formatter) {
+        f.print(self.0);
+        if let Some(ref default) = self.1 {
+            f.print(" = ");
+            f.print(default);
+        }
+    }
+}
+
 impl<'a> Print for Block<'a> {
     fn print(&self, f: &mut Formatter) {
         f.println("\n");
@@ -294,3 +339,16 @@ impl<'a> Print for PutAt<'a> {
         f.print(&self.1);
     }
 }
+
+impl<'a> Print for With<'a> {
+    fn print(&self, f: &mut Formatter) {
+        f.print("with ");
+        let mut first = true;
+        for assign in &self.0 {
+            if !first { f.print(", "); }
+            f.print(assign);
+            first = false;
+        }
+        f.print(&self.1);
+    }
+}
@@ -32,18 +32,21 @@ const USAGE: &'static str = "
 Pris, a language for designing slides.
 
 Usage:
-  pris [--output=<outfile>] [--] <infile>
+  pris [--output=<outfile>] [--strict] [--] <infile>
   pris (-h | --help)
 
 Options:
   -h --help              Show this screen.
   -o --output <outfile>  Write to the specified file, instead of infile.pdf.
+  --strict               Fail when a font or glyph is not available, instead
+                         of warning and substituting a different font.
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     arg_infile: String,
     flag_output: Option<String>,
+    flag_strict: bool,
 }
 
 fn main() {
@@ -53,6 +56,7 @@ fn main() {
 
     let mut input = Vec::new();
     let outfile;
+    let base_dir;
 
     // Allow reading from stdin by passing "-" as the input filename.
     if &args.arg_infile == "-" {
@@ -63,6 +67,9 @@ fn main() {
         } else {
             panic!("Specifying --output is required when reading from stdin.");
         }
+
+        // Without an input file, paths are relative to the working directory.
+        base_dir = PathBuf::new();
     } else {
         let infile = Path::new(&args.arg_infile);
         let f = File::open(infile)
@@ -75,6 +82,9 @@ fn main() {
         } else {
             infile.with_extension("pdf")
         };
+
+        // Paths in the source are relative to the directory of the source file.
+        base_dir = infile.parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::new());
     }
 
     let doc = parse_or_abort(&input);
@@ -83,9 +93,10 @@ fn main() {
 
     let mut frames = Vec::new();
     let mut fm = runtime::FontMap::new();
+    fm.set_strict(args.flag_strict);
 
     {
-        let mut stmt_interpreter = interpreter::StmtInterpreter::new(&mut fm);
+        let mut stmt_interpreter = interpreter::StmtInterpreter::new(&mut fm, &base_dir);
         for statement in &doc.0 {
             let result = match stmt_interpreter.eval_statement(statement) {
                 Ok(x) => x,
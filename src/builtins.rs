@@ -5,35 +5,72 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::mem;
 use std::rc::Rc;
 
 use ast::Idents;
 use cairo;
-use elements::{Element, FillPolygon, StrokePolygon, Text, Vec2};
-use error::{Error, Result};
+use data;
+use elements::{Color, Element, FillPath, FillPolygon, FontSpec, PathCommand, PlacedElement, Slant};
+use elements::{StrokePath, StrokePolygon, Text, Vec2};
+use error::{Error, Result, Warning};
 use freetype;
+use freetype::freetype_sys::{FT_Get_Sfnt_Table, TT_OS2, ft_sfnt_os2};
 use harfbuzz;
+use highlight;
+use highlight::SpanKind;
+use math;
+use outline;
 use pretty::Formatter;
 use rsvg;
-use runtime::{BoundingBox, Frame, Subframe, Val};
-use types::ValType;
+use runtime;
+use runtime::{BoundingBox, FontMap, Frame, NamedArgs, Subframe, Val, is_font_file};
+use types::{LenDim, ValType};
+use variations;
 
 // TODO: Put that somewhere else.
 use interpreter::ExprInterpreter;
 
+/// Binds the arguments of a builtin whose parameters are all required, but
+/// not of a single type, so the builtin checks the types itself.
+fn bind_required<'a>(fn_name: &str,
+                     params: &[&str],
+                     args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Vec<Val<'a>>> {
+    let num_given = (args.len() + named.len()) as u32;
+    let bound = runtime::bind_args(fn_name, params, args, named)?;
+    let mut vals = Vec::with_capacity(bound.len());
+    for (val, &name) in bound.into_iter().zip(params) {
+        match val {
+            Some(val) => vals.push(val),
+            None => return Err(Error::missing_arg(fn_name, name, params.len() as u32, num_given)),
+        }
+    }
+    Ok(vals)
+}
+
+/// Checks the arguments of a builtin against its parameters, given as names
+/// and types. Named arguments are moved to the position of their parameter,
+/// so afterwards `args` holds the value of every parameter, in order.
 fn validate_args<'a>(fn_name: &str,
-                     expected: &[ValType],
-                     actual: &[Val<'a>])
+                     params: &[(&str, ValType)],
+                     args: &mut Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
                      -> Result<()> {
-    // First check that we have exactly the right number of arguments.
-    if expected.len() != actual.len() {
-        return Err(Error::arity(fn_name, expected.len() as u32, actual.len() as u32))
-    }
+    // First bind the arguments to the parameters, which must all be given.
+    let names: Vec<&str> = params.iter().map(|&(name, _)| name).collect();
+    let positional = mem::replace(args, Vec::new());
+    *args = bind_required(fn_name, &names, positional, named)?;
 
     // Then check the type of each.
-    for (i, (ex, ac)) in expected.iter().zip(actual).enumerate() {
-        if *ex != ac.get_type() {
-            return Err(Error::arg_type(fn_name, *ex, ac.get_type(), i as u32))
+    for (i, (ac, &(_, ex))) in args.iter().zip(params).enumerate() {
+        if ex != ac.get_type() {
+            return Err(Error::arg_type(fn_name, ex, ac.get_type(), i as u32))
         }
     }
 
@@ -41,9 +78,10 @@ fn validate_args<'a>(fn_name: &str,
 }
 
 pub fn fit<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
-                   mut args: Vec<Val<'a>>)
+                   mut args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
                    -> Result<Val<'a>> {
-    validate_args("fit", &[ValType::Frame, ValType::Coord(1)], &args)?;
+    validate_args("fit", &[("frame", ValType::Frame), ("size", ValType::Coord(1))], &mut args, named)?;
     let frame = match args.remove(0) {
         Val::Frame(f) => f,
         _ => unreachable!(),
@@ -53,8 +91,32 @@ pub fn fit<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
         _ => unreachable!(),
     };
 
-    let bb = frame.get_bounding_box();
+    let scale = fit_scale(frame.get_bounding_box(), size)?;
+    Ok(Val::Frame(Rc::new(scale_frame(&frame, scale))))
+}
+
+pub fn align<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                     mut args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Val<'a>> {
+    validate_args("align", &[("frame", ValType::Frame), ("origin", ValType::Coord(1))], &mut args, named)?;
+    let frame = match args.remove(0) {
+        Val::Frame(f) => f,
+        _ => unreachable!(),
+    };
+    let origin = match args.remove(0) {
+        Val::Coord(x, y, 1) => Vec2::new(x, y),
+        _ => unreachable!(),
+    };
 
+    // Placing the result at a point puts the given point of the frame there,
+    // so `align(f, f.center)` can be centered on a location.
+    Ok(Val::Frame(Rc::new(frame.with_origin(origin))))
+}
+
+/// Returns the scale that makes a bounding box fit in a box of the given size,
+/// preserving the aspect ratio.
+fn fit_scale(bb: &BoundingBox, size: (f64, f64)) -> Result<f64> {
     // Avoid division by zero in the aspect ratio computation. Fitting into a
     // box of which either size has length 0 is nonsense anyway.
     if size.0 == 0.0 || size.1 == 0.0 {
@@ -83,6 +145,11 @@ pub fn fit<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
         return Err(Error::Other("Cannot fit a frame of size (0w, 0w).".into()))
     };
 
+    Ok(scale)
+}
+
+/// Returns a copy of the frame, scaled around its origin.
+fn scale_frame<'a>(frame: &Frame<'a>, scale: f64) -> Frame<'a> {
     let mut scaled_frame = Frame::from_env(frame.get_env().clone());
 
     // As the frame is immutable anyway, it would actually be possible to refer
@@ -98,13 +165,14 @@ pub fn fit<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
     scaled_frame.set_anchor(frame.get_anchor() * scale);
     scaled_frame.union_bounding_box(&frame.get_bounding_box().scale(scale));
 
-    Ok(Val::Frame(Rc::new(scaled_frame)))
+    scaled_frame
 }
 
 pub fn line<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
-                    mut args: Vec<Val<'a>>)
+                    mut args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
                     -> Result<Val<'a>> {
-    validate_args("line", &[ValType::Coord(1)], &args)?;
+    validate_args("line", &[("offset", ValType::Coord(1))], &mut args, named)?;
     let offset = match args.remove(0) {
         Val::Coord(x, y, 1) => Vec2::new(x, y),
         _ => unreachable!(),
@@ -128,9 +196,10 @@ pub fn line<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
 }
 
 pub fn fill_rectangle<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
-                              mut args: Vec<Val<'a>>)
+                              mut args: Vec<Val<'a>>,
+                              named: NamedArgs<'a>)
                               -> Result<Val<'a>> {
-    validate_args("fill_rectangle", &[ValType::Coord(1)], &args)?;
+    validate_args("fill_rectangle", &[("size", ValType::Coord(1))], &mut args, named)?;
     let (w, h) = match args.remove(0) {
         Val::Coord(x, y, 1) => (x, y),
         _ => unreachable!(),
@@ -157,10 +226,11 @@ pub fn fill_rectangle<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
 }
 
 pub fn str<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
-                   mut args: Vec<Val<'a>>)
+                   mut args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
                    -> Result<Val<'a>> {
     // TODO: Make this generic over the dimension?
-    validate_args("str", &[ValType::Num(0)], &args)?;
+    validate_args("str", &[("num", ValType::Num(0))], &mut args, named)?;
     let num = match args.remove(0) {
         Val::Num(x, _) => x,
         _ => unreachable!(),
@@ -169,41 +239,549 @@ pub fn str<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
     Ok(Val::Str(format!("{}", num)))
 }
 
-/// Typesets a single line of text.
+/// Returns the names of the fields of a record, in order.
+pub fn keys<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                    mut args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    validate_args("keys", &[("record", ValType::Record)], &mut args, named)?;
+    match args.remove(0) {
+        Val::Record(fields) => Ok(Val::List(fields.into_iter().map(|(k, _)| Val::Str(k)).collect())),
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the field of a record with the given name, which unlike
+/// `record.field` can be computed, for example from `keys`.
+pub fn get<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   mut args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    validate_args("get", &[("record", ValType::Record), ("name", ValType::Str)], &mut args, named)?;
+    let name = match args.remove(1) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+    let fields = match args.remove(0) {
+        Val::Record(fields) => fields,
+        _ => unreachable!(),
+    };
+    match runtime::get_field(&fields, &name) {
+        Some(val) => Ok(val.clone()),
+        None => Err(Error::value(format!("The record has no field '{}'.", name))),
+    }
+}
+
+/// Returns the first record with the fields of the second one added, where
+/// fields of the second record replace fields with the same name.
+pub fn merge<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                     mut args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Val<'a>> {
+    validate_args("merge", &[("record", ValType::Record), ("updates", ValType::Record)], &mut args, named)?;
+    match (args.remove(0), args.remove(0)) {
+        (Val::Record(mut fields), Val::Record(updates)) => {
+            for (name, val) in updates {
+                runtime::set_field(&mut fields, name, val);
+            }
+            Ok(Val::Record(fields))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Builds the error for a string variable that has an unsupported value.
+fn invalid_choice(var_name: &str, value: &str, choices: &[&str]) -> Error {
+    let mut fmt = Formatter::new();
+    fmt.print("'");
+    fmt.print(value);
+    fmt.print("' is not a valid value for '");
+    fmt.print(var_name);
+    fmt.print("'. Must be one of ");
+    for (i, choice) in choices.iter().enumerate() {
+        if i > 0 { fmt.print(", "); }
+        fmt.print("'");
+        fmt.print(*choice);
+        fmt.print("'");
+    }
+    fmt.print(".");
+    Error::value(fmt.into_string())
+}
+
+/// Reads the font to use from the 'font_family', 'font_weight', 'font_slant',
+/// 'font_stretch', and 'font_variations' variables.
 ///
-/// Returns the glyphs as well as the width of the line.
-fn typeset_line(ft_face: &mut freetype::Face<'static>,
-                font_size: f64,
-                text: &str)
-                -> (Vec<cairo::Glyph>, f64) {
-    // Shape the text using Harfbuzz: convert the UTF-8 string and input font
-    // into a list of glyphs with offsets.
-    let mut hb_font = harfbuzz::Font::from_ft_face(ft_face);
+/// This also makes the fonts in 'font_dirs' available, as those affect which
+/// font a family name refers to.
+fn lookup_font_spec<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>) -> Result<FontSpec> {
+    let env = interpreter.env;
+
+    for dir in env.lookup_str_list(&Idents(vec!["font_dirs"]))? {
+        interpreter.font_map.add_font_dir(&interpreter.base_dir.join(dir))?;
+    }
+
+    // The family can also be the path of a font file, relative to the source
+    // file. Make it absolute, so it does not depend on the working directory.
+    let mut family = env.lookup_str(&Idents(vec!["font_family"]))?;
+    if is_font_file(&family) {
+        family = interpreter.base_dir.join(family).to_string_lossy().into_owned();
+    }
+
+    let var_weight = Idents(vec!["font_weight"]);
+    let weight = match env.lookup(&var_weight)? {
+        Val::Num(w, 0) if w >= 100.0 && w <= 900.0 => w.round() as u32,
+        Val::Num(w, 0) => {
+            let msg = format!("'font_weight' must be between 100 and 900, but it is {}.", w);
+            return Err(Error::value(msg))
+        }
+        Val::Str(name) => match name.as_ref() {
+            "thin" => 100,
+            "extra-light" => 200,
+            "light" => 300,
+            "regular" => 400,
+            "medium" => 500,
+            "semi-bold" => 600,
+            "bold" => 700,
+            "extra-bold" => 800,
+            "black" => 900,
+            other => {
+                let choices = ["thin", "extra-light", "light", "regular", "medium",
+                               "semi-bold", "bold", "extra-bold", "black"];
+                return Err(invalid_choice("font_weight", other, &choices))
+            }
+        },
+        other => return Err(Error::var_type(&var_weight, ValType::Num(0), other.get_type())),
+    };
+
+    let slant = match env.lookup_str(&Idents(vec!["font_slant"]))?.as_ref() {
+        "roman" => Slant::Roman,
+        "italic" => Slant::Italic,
+        "oblique" => Slant::Oblique,
+        other => return Err(invalid_choice("font_slant", other, &["roman", "italic", "oblique"])),
+    };
+
+    // The stretch can be given as a percentage of the normal width, or by
+    // name. The names map to the percentages that OpenType uses.
+    let var_stretch = Idents(vec!["font_stretch"]);
+    let stretch = match env.lookup(&var_stretch)? {
+        Val::Num(x, 0) if x >= 50.0 && x <= 200.0 => x.round() as u32,
+        Val::Num(x, 0) => {
+            let msg = format!("'font_stretch' must be between 50 and 200, but it is {}.", x);
+            return Err(Error::value(msg))
+        }
+        Val::Str(name) => match name.as_ref() {
+            "ultra-condensed" => 50,
+            "extra-condensed" => 63,
+            "condensed" => 75,
+            "semi-condensed" => 87,
+            "normal" => 100,
+            "semi-expanded" => 113,
+            "expanded" => 125,
+            "extra-expanded" => 150,
+            "ultra-expanded" => 200,
+            other => {
+                let choices = ["ultra-condensed", "extra-condensed", "condensed",
+                               "semi-condensed", "normal", "semi-expanded",
+                               "expanded", "extra-expanded", "ultra-expanded"];
+                return Err(invalid_choice("font_stretch", other, &choices))
+            }
+        },
+        other => return Err(Error::var_type(&var_stretch, ValType::Num(0), other.get_type())),
+    };
+
+    // Variable fonts take coordinates on their axes as "wght=650, opsz=24".
+    // Whether the axes exist is checked when the font is loaded.
+    let settings = env.lookup_str(&Idents(vec!["font_variations"]))?;
+    let font_variations = match variations::parse(&settings) {
+        Ok(vs) => vs,
+        Err(msg) => return Err(Error::value(format!("Invalid 'font_variations': {}", msg))),
+    };
+
+    let spec = FontSpec {
+        family: family,
+        weight: weight,
+        slant: slant,
+        stretch: stretch,
+        variations: font_variations,
+    };
+    Ok(spec)
+}
+
+/// A sequence of glyphs that are all rendered with the same font.
+struct GlyphRun {
+    font: FontSpec,
+    glyphs: Vec<cairo::Glyph>,
+    /// The text that the glyphs represent.
+    text: String,
+    /// Mapping from the text to the glyphs.
+    clusters: Vec<cairo::TextCluster>,
+}
 
-    let mut hb_buffer = harfbuzz::Buffer::new(harfbuzz::Direction::LeftToRight);
-    hb_buffer.add_str(&text);
-    hb_buffer.shape(&mut hb_font);
+impl GlyphRun {
+    fn new(font: FontSpec) -> GlyphRun {
+        GlyphRun {
+            font: font,
+            glyphs: Vec::new(),
+            text: String::new(),
+            clusters: Vec::new(),
+        }
+    }
+}
+
+/// Settings that affect how glyphs are placed along a line.
+struct Layout {
+    /// Extra space between characters, in points.
+    letter_spacing: f64,
+    /// Extra space after every space character, in points.
+    word_spacing: f64,
+    /// Whether to apply the kerning from the font.
+    kerning: bool,
+    /// Whether lines run from top to bottom, rather than from left to right.
+    vertical: bool,
+}
+
+impl Layout {
+    /// Returns the OpenType features to shape with.
+    fn features(&self) -> &'static [&'static str] {
+        // Old-style numerals are enabled by default. Kerning is on by default
+        // in Harfbuzz, so it only needs mentioning to turn it off.
+        if self.kerning { &["onum"] } else { &["onum", "-kern"] }
+    }
+
+    fn direction(&self) -> harfbuzz::Direction {
+        if self.vertical {
+            harfbuzz::Direction::TopToBottom
+        } else {
+            harfbuzz::Direction::LeftToRight
+        }
+    }
+}
+
+/// Reads the 'letter_spacing', 'word_spacing', 'kerning' and 'writing_mode'
+/// variables.
+fn lookup_layout<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>) -> Result<Layout> {
+    let kerning = match interpreter.env.lookup_str(&Idents(vec!["kerning"]))?.as_ref() {
+        "on" => true,
+        "off" => false,
+        other => return Err(invalid_choice("kerning", other, &["on", "off"])),
+    };
+    // In vertical writing mode, lines are columns that run from top to
+    // bottom, and successive columns go from right to left. The alignment
+    // then applies vertically: "left" aligns the tops of the columns.
+    let vertical = match interpreter.env.lookup_str(&Idents(vec!["writing_mode"]))?.as_ref() {
+        "horizontal" => false,
+        "vertical" => true,
+        other => return Err(invalid_choice("writing_mode", other, &["horizontal", "vertical"])),
+    };
+    let layout = Layout {
+        letter_spacing: interpreter.env.lookup_len(&Idents(vec!["letter_spacing"]))?,
+        word_spacing: interpreter.env.lookup_len(&Idents(vec!["word_spacing"]))?,
+        kerning: kerning,
+        vertical: vertical,
+    };
+    Ok(layout)
+}
 
+/// Positions shaped glyphs along a line, starting at the given position.
+///
+/// Horizontal lines run along the x-axis with the baseline at y = 0. Vertical
+/// lines run down along the y-axis, with the glyphs centered on x = 0.
+///
+/// Letter spacing is inserted between clusters, so it does not separate
+/// the glyphs of a ligature or a character and its combining marks. When
+/// `line_start` is true, there is no space before the first cluster.
+///
+/// Returns the glyphs as well as the position after the last glyph.
+fn position_glyphs(hb_glyphs: &[harfbuzz::Glyph],
+                   text: &str,
+                   font_size: f64,
+                   layout: &Layout,
+                   start: f64,
+                   line_start: bool)
+                   -> (Vec<cairo::Glyph>, f64) {
     // Position all the glyphs: Harfbuzz gives offsets, but we need absolute
     // locations. Store them in the representation that Cairo expects.
-    let hb_glyphs = hb_buffer.glyphs();
     let mut cr_glyphs = Vec::with_capacity(hb_glyphs.len());
-    let (mut cur_x, mut cur_y) = (0.0, 0.0);
+    let mut cur = start;
 
     // Compensate for the fixed font size which is set for the Freetype font,
     // and apply the desired font size.
     let size_factor = font_size / 1000.0;
 
-    for hg in hb_glyphs {
-        cur_x += hg.x_offset as f64 * size_factor;
-        cur_y += hg.y_offset as f64 * size_factor;
-        let cg = cairo::Glyph::new(hg.codepoint as u64, cur_x, cur_y);
-        cur_x += hg.x_advance as f64 * size_factor;
-        cur_y += hg.y_advance as f64 * size_factor;
-        cr_glyphs.push(cg);
+    for (i, hg) in hb_glyphs.iter().enumerate() {
+        let is_new_cluster = match i {
+            0 => !line_start,
+            _ => hb_glyphs[i - 1].cluster != hg.cluster,
+        };
+        if is_new_cluster {
+            cur += layout.letter_spacing;
+        }
+
+        // Harfbuzz has the y-axis pointing up, whereas ours points down. The
+        // offsets only move the glyph, they do not affect the next glyph.
+        let x_offset = hg.x_offset as f64 * size_factor;
+        let y_offset = hg.y_offset as f64 * -size_factor;
+        let (x, y) = if layout.vertical {
+            (x_offset, cur + y_offset)
+        } else {
+            (cur + x_offset, y_offset)
+        };
+        cr_glyphs.push(cairo::Glyph::new(hg.codepoint as u64, x, y));
+
+        cur += if layout.vertical {
+            hg.y_advance as f64 * -size_factor
+        } else {
+            hg.x_advance as f64 * size_factor
+        };
+
+        if text[hg.cluster as usize..].starts_with(' ') {
+            cur += layout.word_spacing;
+        }
+    }
+
+    (cr_glyphs, cur)
+}
+
+/// Builds the clusters that map the shaped text to its glyphs.
+///
+/// Harfbuzz gives every glyph the byte offset of the first character it
+/// represents. Consecutive glyphs with the same offset form one cluster, which
+/// extends up to the offset of the next cluster.
+fn make_clusters(hb_glyphs: &[harfbuzz::Glyph], text_len: usize) -> Vec<cairo::TextCluster> {
+    let mut clusters = Vec::new();
+    let mut i = 0;
+    while i < hb_glyphs.len() {
+        let start = hb_glyphs[i].cluster as usize;
+        let num_glyphs = hb_glyphs[i..].iter().take_while(|g| g.cluster as usize == start).count();
+        let end = match hb_glyphs.get(i + num_glyphs) {
+            Some(g) => g.cluster as usize,
+            None => text_len,
+        };
+        clusters.push(cairo::TextCluster::new(end - start, num_glyphs));
+        i += num_glyphs;
+    }
+    clusters
+}
+
+#[test]
+fn make_clusters_groups_glyphs_by_cluster() {
+    let glyph = |cluster| harfbuzz::Glyph {
+        codepoint: 1,
+        cluster: cluster,
+        x_advance: 0,
+        y_advance: 0,
+        x_offset: 0,
+        y_offset: 0,
+    };
+    // Glyphs for "affix" with an "ffi" ligature, and an 'x' with a combining
+    // acute accent, which takes two bytes.
+    let text = "affix\u{301}";
+    let glyphs = [glyph(0), glyph(1), glyph(4), glyph(4)];
+    let clusters: Vec<_> = make_clusters(&glyphs, text.len()).iter()
+        .map(|c| (c.num_bytes(), c.num_glyphs()))
+        .collect();
+    assert_eq!(&clusters, &[(1, 1), (3, 1), (3, 2)]);
+}
+
+/// Returns the byte ranges of the shaped text that the font has no glyphs for.
+///
+/// Harfbuzz maps characters that are not in the font to glyph 0, the
+/// `.notdef` glyph. The ranges are derived from the glyph clusters, so they
+/// always start and end at a character boundary.
+fn find_missing_ranges(hb_glyphs: &[harfbuzz::Glyph], text_len: usize) -> Vec<(usize, usize)> {
+    let mut clusters: Vec<usize> = hb_glyphs.iter().map(|g| g.cluster as usize).collect();
+    clusters.sort();
+    clusters.dedup();
+
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (i, &start) in clusters.iter().enumerate() {
+        let end = if i + 1 < clusters.len() { clusters[i + 1] } else { text_len };
+        let is_missing = hb_glyphs.iter().any(|g| g.cluster as usize == start && g.codepoint == 0);
+        if !is_missing { continue }
+
+        // Merge adjacent ranges, so a run of missing characters can be shaped
+        // as a whole.
+        let is_adjacent = match ranges.last() {
+            Some(&(_, prev_end)) => prev_end == start,
+            None => false,
+        };
+        if is_adjacent {
+            ranges.last_mut().unwrap().1 = end;
+        } else {
+            ranges.push((start, end));
+        }
+    }
+
+    ranges
+}
+
+#[test]
+fn find_missing_ranges_merges_adjacent_clusters() {
+    let glyph = |codepoint, cluster| harfbuzz::Glyph {
+        codepoint: codepoint,
+        cluster: cluster,
+        x_advance: 0,
+        y_advance: 0,
+        x_offset: 0,
+        y_offset: 0,
+    };
+    // Glyphs for "ab??c?", where '?' is a character that takes two bytes.
+    let glyphs = [glyph(1, 0), glyph(2, 1), glyph(0, 2), glyph(0, 4), glyph(3, 6), glyph(0, 7)];
+    let ranges = find_missing_ranges(&glyphs, 9);
+    assert_eq!(&ranges, &[(2, 6), (7, 9)]);
+}
+
+/// Appends a segment to be shaped with the given font.
+///
+/// Merges the segment into the previous one if it uses the same font.
+fn push_segment(segments: &mut Vec<(usize, usize, FontSpec)>, start: usize, end: usize, font: &FontSpec) {
+    if let Some(prev) = segments.last_mut() {
+        if prev.1 == start && prev.2 == *font {
+            prev.1 = end;
+            return
+        }
+    }
+    segments.push((start, end, font.clone()));
+}
+
+/// Typesets a single line of text.
+///
+/// Characters that the font lacks are typeset in a fallback font, preferring
+/// the families in `font_fallback`. Every such character is recorded in
+/// `fallbacks`, together with the family used for it, if there was one.
+///
+/// Returns the glyph runs as well as the length of the line.
+fn typeset_line(font_map: &mut FontMap,
+                font: &FontSpec,
+                font_fallback: &[String],
+                font_size: f64,
+                layout: &Layout,
+                text: &str,
+                fallbacks: &mut Vec<(char, Option<String>)>)
+                -> Result<(Vec<GlyphRun>, f64)> {
+    let features = layout.features();
+    let direction = layout.direction();
+    let hb_glyphs = font_map.shape(font, direction, features, text)?;
+
+    let missing = find_missing_ranges(&hb_glyphs, text.len());
+
+    // In the common case, the font has all glyphs, and the shaped text can be
+    // used as-is.
+    if missing.is_empty() {
+        let (glyphs, width) = position_glyphs(&hb_glyphs, text, font_size, layout, 0.0, true);
+        let run = GlyphRun {
+            font: font.clone(),
+            glyphs: glyphs,
+            text: text.to_string(),
+            clusters: make_clusters(&hb_glyphs, text.len()),
+        };
+        return Ok((vec![run], width))
+    }
+
+    // Otherwise, split the line into segments that can each be shaped with a
+    // single font. Characters for which no fallback exists remain in the
+    // original font, and will render as the `.notdef` glyph.
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    for &(start, end) in &missing {
+        push_segment(&mut segments, pos, start, font);
+        for (i, ch) in text[start..end].char_indices() {
+            let fallback = font_map.get_fallback(ch, font, font_fallback);
+            if !fallbacks.iter().any(|&(c, _)| c == ch) {
+                fallbacks.push((ch, fallback.as_ref().map(|f| f.family.clone())));
+            }
+            let seg_font = fallback.unwrap_or(font.clone());
+            push_segment(&mut segments, start + i, start + i + ch.len_utf8(), &seg_font);
+        }
+        pos = end;
+    }
+    push_segment(&mut segments, pos, text.len(), font);
+
+    let mut runs = Vec::with_capacity(segments.len());
+    let mut cur_pos = 0.0;
+    for (start, end, seg_font) in segments {
+        if start == end { continue }
+        let seg_text = &text[start..end];
+        let seg_glyphs = font_map.shape(&seg_font, direction, features, seg_text)?;
+        let (glyphs, next_pos) = position_glyphs(&seg_glyphs, seg_text, font_size, layout,
+                                                 cur_pos, start == 0);
+        cur_pos = next_pos;
+        runs.push(GlyphRun {
+            font: seg_font,
+            glyphs: glyphs,
+            text: seg_text.to_string(),
+            clusters: make_clusters(&seg_glyphs, seg_text.len()),
+        });
+    }
+
+    Ok((runs, cur_pos))
+}
+
+/// Vertical metrics used to decorate text, in points relative to the baseline,
+/// with positive y pointing down.
+struct DecorationMetrics {
+    ascender: f64,
+    descender: f64,
+    underline_position: f64,
+    underline_thickness: f64,
+    strikethrough_position: f64,
+    strikethrough_thickness: f64,
+}
+
+/// Returns the strikeout position and size from the OS/2 table in font units,
+/// if the font has that table.
+fn get_strikeout(ft_face: &mut freetype::Face<'static>) -> Option<(f64, f64)> {
+    unsafe {
+        let os2 = FT_Get_Sfnt_Table(ft_face.raw_mut(), ft_sfnt_os2) as *const TT_OS2;
+        if os2.is_null() {
+            None
+        } else {
+            Some(((*os2).yStrikeoutPosition as f64, (*os2).yStrikeoutSize as f64))
+        }
     }
+}
+
+fn get_decoration_metrics(ft_face: &mut freetype::Face<'static>, font_size: f64) -> DecorationMetrics {
+    let (ascender, descender, underline_position, underline_thickness, units_per_em) = {
+        let raw = ft_face.raw_mut();
+        (raw.ascender as f64,
+         raw.descender as f64,
+         raw.underline_position as f64,
+         raw.underline_thickness as f64,
+         raw.units_per_EM as f64)
+    };
+    // Bitmap fonts have no units per em; treat their metrics as absent.
+    let to_points = |x: f64| if units_per_em > 0.0 { x / units_per_em * font_size } else { 0.0 };
+
+    // Fonts without underline metrics have a thickness of zero; use a
+    // reasonable default so the decoration is still visible.
+    let thickness = match to_points(underline_thickness) {
+        t if t > 0.0 => t,
+        _ => 0.05 * font_size,
+    };
+
+    // Without an OS/2 table, strike through at half the x-height instead,
+    // which is what most fonts specify anyway. Scaled glyph metrics are in
+    // units of 1/1000 em at the character size that faces are loaded at.
+    let (strikethrough_position, strikethrough_thickness) = match get_strikeout(ft_face) {
+        Some((position, size)) if size > 0.0 => (to_points(position), to_points(size)),
+        _ => {
+            let x_height = match ft_face.load_char('x' as usize, freetype::face::LoadFlag::empty()) {
+                Ok(..) => ft_face.glyph().metrics().horiBearingY as f64 / 1000.0 * font_size,
+                Err(..) => 0.5 * font_size,
+            };
+            (0.5 * x_height, thickness)
+        }
+    };
 
-    (cr_glyphs, cur_x)
+    DecorationMetrics {
+        ascender: -to_points(ascender),
+        descender: -to_points(descender),
+        underline_position: -to_points(underline_position),
+        underline_thickness: thickness,
+        strikethrough_position: -strikethrough_position,
+        strikethrough_thickness: strikethrough_thickness,
+    }
 }
 
 /// Split a string on newlines.
@@ -229,10 +807,25 @@ fn split_lines_returns_as_many_lines_as_newlines_plus_one() {
     assert_eq!(&lines, &["", "foo", "bar", ""]);
 }
 
+/// Converts a position relative to a line into frame coordinates.
+///
+/// The line starts at `line_pos` along the cross axis: down for horizontal
+/// lines, to the left for vertical lines. `along` is the distance along the
+/// line, and `across` the distance away from the baseline or center line, in
+/// the direction of y for horizontal lines, and x for vertical lines.
+fn line_point(vertical: bool, line_pos: f64, along: f64, across: f64) -> Vec2 {
+    if vertical {
+        Vec2::new(across - line_pos, along)
+    } else {
+        Vec2::new(along, line_pos + across)
+    }
+}
+
 pub fn t<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
-                 mut args: Vec<Val<'a>>)
+                 mut args: Vec<Val<'a>>,
+                 named: NamedArgs<'a>)
                  -> Result<Val<'a>> {
-    validate_args("t", &[ValType::Str], &args)?;
+    validate_args("t", &[("text", ValType::Str)], &mut args, named)?;
     let text = match args.remove(0) {
         Val::Str(s) => s,
         _ => unreachable!(),
@@ -241,23 +834,19 @@ pub fn t<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
 
     enum TextAlign { Left, Center, Right }
 
-    // Read the font details from the 'font_family' and 'font_style' variables,
-    // and locate the corresponding FreeType face. The line height is a bit of a
+    // Read the font details from the 'font_*' variables, and locate the
+    // corresponding FreeType face. The line height is a bit of a
     // problem; we could make it dimensionless and relative to the font size --
     // which would make it scale automatically -- but then specifying absolute
     // line heights would be a bit of a hassle. We could make it absolute, but
     // then it does not scale automatically. Or we could allow both here:
     // numbers have units, so we could figure out what to do. But my gut feeling
     // is that dynamic typing will be confusing in the end.
-    let font_family = interpreter.env.lookup_str(&Idents(vec!["font_family"]))?;
-    let font_style = interpreter.env.lookup_str(&Idents(vec!["font_style"]))?;
+    let font = lookup_font_spec(interpreter)?;
+    let font_fallback = interpreter.env.lookup_str_list(&Idents(vec!["font_fallback"]))?;
     let font_size = interpreter.env.lookup_len(&Idents(vec!["font_size"]))?;
     let line_height = interpreter.env.lookup_len(&Idents(vec!["line_height"]))?;
     let text_align = interpreter.env.lookup_str(&Idents(vec!["text_align"]))?;
-    let mut ft_face = match interpreter.font_map.get(&font_family, &font_style) {
-        Some(face) => face,
-        None => return Err(Error::missing_font(font_family, font_style)),
-    };
     let ta = match text_align.as_ref() {
         "left" => TextAlign::Left,
         "center" => TextAlign::Center,
@@ -265,43 +854,129 @@ pub fn t<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
         other => {
             // TODO: Move this error to an error at assignment time, not at
             // evaluation time. More type safety is more better.
-            let mut fmt = Formatter::new();
-            fmt.print("'");
-            fmt.print(other);
-            fmt.print("' is not a valid value for 'text_align'. ");
-            fmt.print("Must be one of 'left', 'center', 'right'.");
-            return Err(Error::value(fmt.into_string()))
+            return Err(invalid_choice("text_align", other, &["left", "center", "right"]))
         }
     };
-
-    // TODO: Extract this, warn properly, do not use Debug instance for
-    // printing.
-    if ft_face.family_name().as_ref() != Some(&font_family) {
-        println!("Warning: requested font family '{}', but loaded '{:?}'.",
-                 font_family, ft_face.family_name());
-    }
-    if ft_face.style_name().as_ref() != Some(&font_style) {
-        println!("Warning: requested font style '{}', but loaded '{:?}'.",
-                 font_style, ft_face.style_name());
+    let layout = lookup_layout(interpreter)?;
+    let vertical = layout.vertical;
+
+    // The decoration can combine underline and strikethrough, separated by
+    // spaces, as in "underline strikethrough".
+    let mut underline = false;
+    let mut strikethrough = false;
+    for decoration in interpreter.env.lookup_str(&Idents(vec!["text_decoration"]))?.split_whitespace() {
+        match decoration {
+            "none" => {}
+            "underline" => underline = true,
+            "strikethrough" => strikethrough = true,
+            other => {
+                let choices = ["none", "underline", "strikethrough"];
+                return Err(invalid_choice("text_decoration", other, &choices))
+            }
+        }
     }
 
-    let mut glyphs = Vec::new();
+    // The background is either a color, or "none" for no background.
+    let var_background = Idents(vec!["text_background"]);
+    let text_background = match interpreter.env.lookup(&var_background)? {
+        Val::Col(col) => Some(col),
+        Val::Str(ref s) if s == "none" => None,
+        Val::Str(other) => return Err(invalid_choice("text_background", &other, &["none"])),
+        other => return Err(Error::var_type(&var_background, ValType::Color, other.get_type())),
+    };
+
+    // Decorations use the metrics of the requested font, also for glyphs
+    // that come from a fallback font, so the lines are continuous.
+    let metrics = get_decoration_metrics(interpreter.font_map.get(&font)?, font_size);
+    let color = interpreter.env.lookup_color(&Idents(vec!["color"]))?;
+
+    // Glyphs are grouped per font, so every font results in a single text
+    // element. The requested font always comes first.
+    let mut runs = vec![GlyphRun::new(font.clone())];
+    // The run that the most recent cluster went into, which will also get
+    // the newline, so the line breaks are preserved when copying the text.
+    let mut last_run: Option<usize> = None;
+    let mut fallbacks = Vec::new();
+    let mut backgrounds = Vec::new();
+    let mut decorations = Vec::new();
     let mut max_width: f64 = 0.0;
     let mut min_offset: f64 = 0.0;
     let mut cur_x = 0.0;
     let mut cur_y = 0.0;
     for line in text_lines {
-        let (line_glyphs, width) = typeset_line(ft_face, font_size, line);
+        if let Some(r) = last_run {
+            let last_cluster = runs[r].clusters.pop().unwrap();
+            runs[r].clusters.push(last_cluster.extend_bytes(1));
+            runs[r].text.push('\n');
+        }
+
+        let (line_runs, width) = typeset_line(interpreter.font_map,
+                                              &font,
+                                              &font_fallback,
+                                              font_size,
+                                              &layout,
+                                              line,
+                                              &mut fallbacks)?;
 
-        // Apply x offset to enforce text alignment.
+        // Apply an offset along the line to enforce text alignment.
         let offset = match ta {
             TextAlign::Left => 0.0,
             TextAlign::Center => width * -0.5,
             TextAlign::Right => width * -1.0,
         };
 
-        for g in line_glyphs {
-            glyphs.push(g.offset(offset, cur_y));
+        let shift = line_point(vertical, cur_y, offset, 0.0);
+        for line_run in line_runs {
+            let index = match runs.iter().position(|r| r.font == line_run.font) {
+                Some(i) => i,
+                None => {
+                    runs.push(GlyphRun::new(line_run.font.clone()));
+                    runs.len() - 1
+                }
+            };
+            for g in line_run.glyphs {
+                runs[index].glyphs.push(g.offset(shift.x, shift.y));
+            }
+            if line_run.clusters.len() > 0 {
+                runs[index].text.push_str(&line_run.text);
+                runs[index].clusters.extend(line_run.clusters);
+                last_run = Some(index);
+            }
+        }
+
+        // Decorate the line over its full width, so the decoration is aligned
+        // with the text regardless of the text alignment. Vertical text has no
+        // font metrics for this, so it uses the em box, with the underline on
+        // the right side of the column.
+        let (bg_start, bg_end, underline_pos, strikethrough_pos) = if vertical {
+            (-0.5 * font_size, 0.5 * font_size, 0.5 * font_size, 0.0)
+        } else {
+            (metrics.ascender, metrics.descender,
+             metrics.underline_position, metrics.strikethrough_position)
+        };
+        let (a0, a1) = (offset, offset + width);
+        if let Some(background) = text_background {
+            backgrounds.push(FillPolygon {
+                color: background,
+                vertices: vec![
+                    line_point(vertical, cur_y, a0, bg_start),
+                    line_point(vertical, cur_y, a0, bg_end),
+                    line_point(vertical, cur_y, a1, bg_end),
+                    line_point(vertical, cur_y, a1, bg_start),
+                ],
+            });
+        }
+        let mut lines = Vec::new();
+        if underline { lines.push((underline_pos, metrics.underline_thickness)); }
+        if strikethrough { lines.push((strikethrough_pos, metrics.strikethrough_thickness)); }
+        for (pos, thickness) in lines {
+            if width == 0.0 { continue }
+            decorations.push(StrokePolygon {
+                color: color,
+                line_width: thickness,
+                close: false,
+                vertices: vec![line_point(vertical, cur_y, a0, pos), line_point(vertical, cur_y, a1, pos)],
+            });
         }
 
         max_width = max_width.max(width);
@@ -310,29 +985,283 @@ pub fn t<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
         cur_x = offset + width;
     }
 
-    let text_elem = Text {
-        color: interpreter.env.lookup_color(&Idents(vec!["color"]))?,
-        font_family: font_family,
-        font_style: font_style,
-        font_size: font_size,
-        glyphs: glyphs,
-    };
+    if fallbacks.len() > 0 {
+        interpreter.font_map.warn(Warning::font_fallback(font.family.clone(), fallbacks))?;
+    }
 
     let mut frame = Frame::new();
-    frame.place_element_on_last_subframe(Vec2::zero(), Element::Text(text_elem));
-    frame.set_anchor(Vec2::new(cur_x, cur_y - line_height));
 
-    let top_left = Vec2::new(min_offset, -line_height);
-    let size = Vec2::new(max_width, cur_y);
+    // The background goes behind the text, the decorations on top of it.
+    for background in backgrounds {
+        frame.place_element_on_last_subframe(Vec2::zero(), Element::FillPolygon(background));
+    }
+
+    for run in runs {
+        if run.glyphs.is_empty() { continue }
+        let text_elem = Text {
+            color: color,
+            font: run.font,
+            font_size: font_size,
+            glyphs: run.glyphs,
+            text: run.text,
+            clusters: run.clusters,
+        };
+        frame.place_element_on_last_subframe(Vec2::zero(), Element::Text(text_elem));
+    }
+
+    for decoration in decorations {
+        frame.place_element_on_last_subframe(Vec2::zero(), Element::StrokePolygon(decoration));
+    }
+
+    // The anchor is at the end of the last line.
+    let last_line = cur_y - line_height;
+    frame.set_anchor(line_point(vertical, last_line, cur_x, 0.0));
+
+    let (top_left, size) = if vertical {
+        (Vec2::new(-last_line - 0.5 * font_size, min_offset),
+         Vec2::new(last_line + font_size, max_width))
+    } else {
+        (Vec2::new(min_offset, -line_height), Vec2::new(max_width, cur_y))
+    };
     frame.union_bounding_box(&BoundingBox::new(top_left, size));
 
     Ok(Val::Frame(Rc::new(frame)))
 }
 
+/// Converts a path into a polyline, approximating every cubic Bézier curve by
+/// straight segments.
+fn flatten_path(commands: &[PathCommand]) -> Vec<Vec2> {
+    // This many segments per curve is more than enough to place glyphs,
+    // which are much wider than the error this leaves.
+    let steps = 32;
+    let mut points: Vec<Vec2> = Vec::new();
+    for command in commands {
+        match *command {
+            PathCommand::MoveTo(p) | PathCommand::LineTo(p) => points.push(p),
+            PathCommand::CurveTo(c1, c2, p3) => {
+                let p0 = match points.last() {
+                    Some(&p) => p,
+                    None => c1,
+                };
+                for i in 1..steps + 1 {
+                    let t = i as f64 / steps as f64;
+                    let s = 1.0 - t;
+                    points.push(p0 * (s * s * s) + c1 * (3.0 * s * s * t) +
+                                c2 * (3.0 * s * t * t) + p3 * (t * t * t));
+                }
+            }
+            PathCommand::Close => {
+                if let Some(&first) = points.first() {
+                    points.push(first);
+                }
+            }
+        }
+    }
+    points
+}
+
+#[test]
+fn flatten_path_samples_curves() {
+    let commands = [
+        PathCommand::MoveTo(Vec2::new(0.0, 0.0)),
+        PathCommand::CurveTo(Vec2::new(0.0, 1.0), Vec2::new(2.0, 1.0), Vec2::new(2.0, 0.0)),
+        PathCommand::LineTo(Vec2::new(3.0, 0.0)),
+    ];
+    let points = flatten_path(&commands);
+    assert_eq!(points.len(), 34);
+    // The curve is symmetric, so the middle point is halfway, at the top.
+    assert_eq!((points[16].x, points[16].y), (1.0, 0.75));
+    assert_eq!((points[32].x, points[32].y), (2.0, 0.0));
+    assert_eq!((points[33].x, points[33].y), (3.0, 0.0));
+}
+
+/// Reads a path for `text_on_path`. It starts with a coordinate, and every
+/// following element is either a coordinate to draw a line to, or a list of
+/// two control points and an end point for a cubic Bézier curve.
+fn get_path<'a>(vals: Vec<Val<'a>>) -> Result<Vec<PathCommand>> {
+    let mut commands = Vec::with_capacity(vals.len());
+    for val in vals {
+        let command = match val {
+            Val::Coord(x, y, 1) if commands.is_empty() => PathCommand::MoveTo(Vec2::new(x, y)),
+            Val::Coord(x, y, 1) => PathCommand::LineTo(Vec2::new(x, y)),
+            Val::List(ref points) if !commands.is_empty() && points.len() == 3 => {
+                let mut ps = Vec::with_capacity(3);
+                for point in points {
+                    match *point {
+                        Val::Coord(x, y, 1) => ps.push(Vec2::new(x, y)),
+                        ref other => {
+                            return Err(list_element_error("a curve", ValType::Coord(1), other.get_type()))
+                        }
+                    }
+                }
+                PathCommand::CurveTo(ps[0], ps[1], ps[2])
+            }
+            Val::List(ref points) if !commands.is_empty() => {
+                let msg = format!("Expected a curve of two control points and an end point, \
+                                   but it has {} points.", points.len());
+                return Err(Error::value(msg))
+            }
+            other => return Err(list_element_error("the path", ValType::Coord(1), other.get_type())),
+        };
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// Returns the point at distance `dist` along a polyline, and the angle of
+/// the segment it lies on, in radians.
+///
+/// Beyond the ends of the path, the first and last segment are extended.
+fn point_on_path(path: &[Vec2], dist: f64) -> (Vec2, f64) {
+    let mut start = 0.0;
+    for (i, w) in path.windows(2).enumerate() {
+        let (dx, dy) = (w[1].x - w[0].x, w[1].y - w[0].y);
+        let len = (dx * dx + dy * dy).sqrt();
+        let is_last = i + 2 == path.len();
+        if (dist < start + len || is_last) && len > 0.0 {
+            let t = (dist - start) / len;
+            return (Vec2::new(w[0].x + t * dx, w[0].y + t * dy), dy.atan2(dx))
+        }
+        start += len;
+    }
+    // All segments have zero length.
+    (path[0], 0.0)
+}
+
+#[test]
+fn point_on_path_follows_segments() {
+    let path = [Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0), Vec2::new(2.0, 2.0)];
+    let (p, angle) = point_on_path(&path, 1.0);
+    assert_eq!((p.x, p.y, angle), (1.0, 0.0, 0.0));
+    let (p, angle) = point_on_path(&path, 3.0);
+    assert_eq!((p.x, p.y), (2.0, 1.0));
+    assert_eq!(angle, ::std::f64::consts::PI * 0.5);
+    // Past the end, the last segment is extended.
+    let (p, _) = point_on_path(&path, 5.0);
+    assert_eq!((p.x, p.y), (2.0, 3.0));
+}
+
+pub fn text_on_path<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                            mut args: Vec<Val<'a>>,
+                            named: NamedArgs<'a>)
+                            -> Result<Val<'a>> {
+    validate_args("text_on_path", &[("path", ValType::List), ("text", ValType::Str)], &mut args, named)?;
+    let path_vals = match args.remove(0) {
+        Val::List(vals) => vals,
+        _ => unreachable!(),
+    };
+    let text = match args.remove(0) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+
+    // Curves are approximated by line segments, so the glyphs can be placed
+    // by their distance along the path.
+    let commands = get_path(path_vals)?;
+    if commands.len() < 2 {
+        let msg = format!("Expected a path of at least two points, but it has {}.", commands.len());
+        return Err(Error::value(msg))
+    }
+    let path = flatten_path(&commands);
+
+    // The text is set on a single line, the path determines the direction.
+    let font = lookup_font_spec(interpreter)?;
+    let font_fallback = interpreter.env.lookup_str_list(&Idents(vec!["font_fallback"]))?;
+    let font_size = interpreter.env.lookup_len(&Idents(vec!["font_size"]))?;
+    let color = interpreter.env.lookup_color(&Idents(vec!["color"]))?;
+    let layout = Layout { vertical: false, ..lookup_layout(interpreter)? };
+    let metrics = get_decoration_metrics(interpreter.font_map.get(&font)?, font_size);
+
+    let line = text.replace('\n', " ");
+    let mut fallbacks = Vec::new();
+    let (runs, width) = typeset_line(interpreter.font_map,
+                                     &font,
+                                     &font_fallback,
+                                     font_size,
+                                     &layout,
+                                     &line,
+                                     &mut fallbacks)?;
+    if fallbacks.len() > 0 {
+        interpreter.font_map.warn(Warning::font_fallback(font.family.clone(), fallbacks))?;
+    }
+
+    // Every glyph becomes a separate text element, so it can be rotated to
+    // follow the path. The first glyph of every cluster carries the text of
+    // the cluster, so the text remains selectable.
+    let mut glyphs = Vec::new();
+    for run in &runs {
+        let mut text_pos = 0;
+        let mut glyph_index = 0;
+        for cluster in &run.clusters {
+            let cluster_text = &run.text[text_pos..text_pos + cluster.num_bytes()];
+            for i in 0..cluster.num_glyphs() {
+                let text = if i == 0 { cluster_text } else { "" };
+                glyphs.push((&run.font, run.glyphs[glyph_index + i], text));
+            }
+            text_pos += cluster.num_bytes();
+            glyph_index += cluster.num_glyphs();
+        }
+    }
+
+    let mut frame = Frame::new();
+    let mut corners = Vec::with_capacity(glyphs.len() * 4);
+    for (i, &(glyph_font, glyph, glyph_text)) in glyphs.iter().enumerate() {
+        // Glyphs are placed such that the middle of their advance is on the
+        // path, and they are rotated along the path at that point.
+        let advance = match glyphs.get(i + 1) {
+            Some(&(_, next, _)) => next.x() - glyph.x(),
+            None => width - glyph.x(),
+        };
+        let (mid, angle) = point_on_path(&path, glyph.x() + advance * 0.5);
+        let (cos, sin) = (angle.cos(), angle.sin());
+        let origin = mid + Vec2::new(cos, sin) * (advance * -0.5);
+
+        let clusters = if glyph_text.is_empty() {
+            Vec::new()
+        } else {
+            vec![cairo::TextCluster::new(glyph_text.len(), 1)]
+        };
+        let text_elem = Text {
+            color: color,
+            font: glyph_font.clone(),
+            font_size: font_size,
+            glyphs: vec![glyph.offset(-glyph.x(), 0.0)],
+            text: glyph_text.to_string(),
+            clusters: clusters,
+        };
+        let placed = PlacedElement {
+            position: Vec2::zero(),
+            element: Element::Text(text_elem),
+        };
+        frame.place_element_on_last_subframe(origin, Element::Rotated(vec![placed], angle));
+
+        // The bounding box must contain the rotated box between the ascender
+        // and descender of every glyph.
+        for &(x, y) in &[(0.0, metrics.ascender), (advance, metrics.ascender),
+                         (0.0, metrics.descender), (advance, metrics.descender)] {
+            corners.push(origin + Vec2::new(x * cos - y * sin, x * sin + y * cos));
+        }
+    }
+
+    if corners.len() > 0 {
+        let x0 = corners.iter().fold(corners[0].x, |m, p| m.min(p.x));
+        let y0 = corners.iter().fold(corners[0].y, |m, p| m.min(p.y));
+        let x1 = corners.iter().fold(corners[0].x, |m, p| m.max(p.x));
+        let y1 = corners.iter().fold(corners[0].y, |m, p| m.max(p.y));
+        frame.union_bounding_box(&BoundingBox::new(Vec2::new(x0, y0), Vec2::new(x1 - x0, y1 - y0)));
+    }
+
+    // The anchor is at the end of the text, on the path.
+    frame.set_anchor(point_on_path(&path, width).0);
+
+    Ok(Val::Frame(Rc::new(frame)))
+}
+
 pub fn glyph<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
-                     mut args: Vec<Val<'a>>)
+                     mut args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
                      -> Result<Val<'a>> {
-    validate_args("glyph", &[ValType::Num(0)], &args)?;
+    validate_args("glyph", &[("index", ValType::Num(0))], &mut args, named)?;
     let index_f64 = match args.remove(0) {
         Val::Num(x, 0) => x,
         _ => unreachable!(),
@@ -348,14 +1277,10 @@ pub fn glyph<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
     // TODO: This was copy-pasted from the `t()` function. Extract the common
     // stuff.
 
-    let font_family = interpreter.env.lookup_str(&Idents(vec!["font_family"]))?;
-    let font_style = interpreter.env.lookup_str(&Idents(vec!["font_style"]))?;
+    let font = lookup_font_spec(interpreter)?;
     let font_size = interpreter.env.lookup_len(&Idents(vec!["font_size"]))?;
     let line_height = interpreter.env.lookup_len(&Idents(vec!["line_height"]))?;
-    let ft_face = match interpreter.font_map.get(&font_family, &font_style) {
-        Some(face) => face,
-        None => return Err(Error::missing_font(font_family, font_style)),
-    };
+    let ft_face = interpreter.font_map.get(&font)?;
 
     // Compensate for the fixed font size which is set for the Freetype font.
     // There is a 16.6 factor that `linear_hori_advance` adds according to the
@@ -375,10 +1300,12 @@ pub fn glyph<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
 
     let text_elem = Text {
         color: interpreter.env.lookup_color(&Idents(vec!["color"]))?,
-        font_family: font_family,
-        font_style: font_style,
+        font: font,
         font_size: font_size,
         glyphs: glyphs,
+        // A glyph by index does not correspond to any text.
+        text: String::new(),
+        clusters: Vec::new(),
     };
 
     let mut frame = Frame::new();
@@ -393,9 +1320,10 @@ pub fn glyph<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
 }
 
 pub fn image<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
-                     mut args: Vec<Val<'a>>)
+                     mut args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
                      -> Result<Val<'a>> {
-    validate_args("image", &[ValType::Str], &args)?;
+    validate_args("image", &[("path", ValType::Str)], &mut args, named)?;
     let path = match args.remove(0) {
         Val::Str(s) => s,
         _ => unreachable!(),
@@ -429,3 +1357,1922 @@ pub fn image<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
 
     Ok(Val::Frame(Rc::new(frame)))
 }
+
+/// Replaces the text in an element by its outline, recursing into groups.
+fn outline_element(font_map: &mut FontMap, element: &Element) -> Result<Element> {
+    match *element {
+        Element::Text(ref text) => Ok(Element::FillPath(outline::outline_text(font_map, text)?)),
+        Element::Scaled(ref elements, scale) => Ok(Element::Scaled(outline_elements(font_map, elements)?, scale)),
+        Element::Rotated(ref elements, angle) => Ok(Element::Rotated(outline_elements(font_map, elements)?, angle)),
+        ref other => Ok(other.clone()),
+    }
+}
+
+fn outline_elements(font_map: &mut FontMap, elements: &[PlacedElement]) -> Result<Vec<PlacedElement>> {
+    let mut result = Vec::with_capacity(elements.len());
+    for pe in elements {
+        result.push(PlacedElement {
+            position: pe.position,
+            element: outline_element(font_map, &pe.element)?,
+        });
+    }
+    Ok(result)
+}
+
+pub fn outline<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                       mut args: Vec<Val<'a>>,
+                       named: NamedArgs<'a>)
+                       -> Result<Val<'a>> {
+    validate_args("outline", &[("frame", ValType::Frame)], &mut args, named)?;
+    let frame = match args.remove(0) {
+        Val::Frame(f) => f,
+        _ => unreachable!(),
+    };
+
+    // Text is converted into filled paths with the same color, all other
+    // elements are kept as they are. The layout of the frame is unaffected.
+    let mut outlined_frame = Frame::from_env(frame.get_env().clone());
+    for subframe in frame.get_subframes() {
+        let mut new_sf = Subframe::new();
+        for pe in subframe.get_elements() {
+            new_sf.place_element(pe.position, outline_element(interpreter.font_map, &pe.element)?);
+        }
+        outlined_frame.push_subframe(new_sf);
+    }
+
+    outlined_frame.set_anchor(frame.get_anchor());
+    outlined_frame.union_bounding_box(frame.get_bounding_box());
+
+    Ok(Val::Frame(Rc::new(outlined_frame)))
+}
+
+/// Builds the error for a list that contains a value of the wrong type.
+fn list_element_error(list_name: &str, expected: ValType, actual: ValType) -> Error {
+    let mut fmt = Formatter::new();
+    fmt.print("Expected ");
+    fmt.print(list_name);
+    fmt.print(" to contain only values of type '");
+    fmt.print(expected);
+    fmt.print("', but found '");
+    fmt.print(actual);
+    fmt.print("'.");
+    Error::value(fmt.into_string())
+}
+
+/// Extracts the frames from a list argument.
+fn get_frames<'a>(list_name: &str, vals: Vec<Val<'a>>) -> Result<Vec<Rc<Frame<'a>>>> {
+    let mut frames = Vec::with_capacity(vals.len());
+    for val in vals {
+        match val {
+            Val::Frame(f) => frames.push(f),
+            other => return Err(list_element_error(list_name, ValType::Frame, other.get_type())),
+        }
+    }
+    Ok(frames)
+}
+
+#[derive(Copy, Clone)]
+enum HorizontalAlign { Left, Center, Right }
+
+#[derive(Copy, Clone)]
+enum VerticalAlign { Top, Center, Bottom, Baseline }
+
+/// Reads an optional alignment argument, which is left by default.
+fn get_horizontal_align<'a>(fn_name: &str,
+                            param: &str,
+                            val: Option<Val<'a>>,
+                            arg_num: u32)
+                            -> Result<HorizontalAlign> {
+    let name = match val {
+        Some(Val::Str(name)) => name,
+        Some(other) => return Err(Error::arg_type(fn_name, ValType::Str, other.get_type(), arg_num)),
+        None => return Ok(HorizontalAlign::Left),
+    };
+    match name.as_ref() {
+        "left" => Ok(HorizontalAlign::Left),
+        "center" => Ok(HorizontalAlign::Center),
+        "right" => Ok(HorizontalAlign::Right),
+        other => Err(invalid_choice(param, other, &["left", "center", "right"])),
+    }
+}
+
+/// Reads an optional alignment argument, which is baseline by default.
+fn get_vertical_align<'a>(fn_name: &str,
+                          param: &str,
+                          val: Option<Val<'a>>,
+                          arg_num: u32)
+                          -> Result<VerticalAlign> {
+    let name = match val {
+        Some(Val::Str(name)) => name,
+        Some(other) => return Err(Error::arg_type(fn_name, ValType::Str, other.get_type(), arg_num)),
+        None => return Ok(VerticalAlign::Baseline),
+    };
+    match name.as_ref() {
+        "top" => Ok(VerticalAlign::Top),
+        "center" => Ok(VerticalAlign::Center),
+        "bottom" => Ok(VerticalAlign::Bottom),
+        "baseline" => Ok(VerticalAlign::Baseline),
+        other => {
+            let choices = ["top", "center", "bottom", "baseline"];
+            Err(invalid_choice(param, other, &choices))
+        }
+    }
+}
+
+/// Reads the frames and the gap, the first two arguments of a stack.
+fn get_stack_args<'a>(fn_name: &str,
+                      bound: &mut Iterator<Item = Option<Val<'a>>>,
+                      num_given: u32)
+                      -> Result<(Vec<Rc<Frame<'a>>>, f64)> {
+    let frames = match bound.next().unwrap() {
+        Some(Val::List(vals)) => get_frames("the frames", vals)?,
+        Some(other) => return Err(Error::arg_type(fn_name, ValType::List, other.get_type(), 0)),
+        None => return Err(Error::missing_arg(fn_name, "frames", 3, num_given)),
+    };
+    let gap = match bound.next().unwrap() {
+        Some(Val::Num(x, 1)) => x,
+        Some(other) => return Err(Error::arg_type(fn_name, ValType::Num(1), other.get_type(), 1)),
+        None => return Err(Error::missing_arg(fn_name, "gap", 3, num_given)),
+    };
+    Ok((frames, gap))
+}
+
+/// Returns the x-offset that aligns a bounding box in a column of the given
+/// width, where the column starts at x = 0.
+fn align_x(bb: &BoundingBox, width: f64, align: HorizontalAlign) -> f64 {
+    match align {
+        HorizontalAlign::Left => -bb.x,
+        HorizontalAlign::Center => (width - bb.width) * 0.5 - bb.x,
+        HorizontalAlign::Right => width - bb.width - bb.x,
+    }
+}
+
+/// Returns the y-offset that aligns a bounding box in a row of the given
+/// height, where the row starts at y = 0. Baseline alignment puts the origin
+/// of the frame, which for text is on the baseline of the first line, at
+/// y = 0.
+fn align_y(bb: &BoundingBox, height: f64, align: VerticalAlign) -> f64 {
+    match align {
+        VerticalAlign::Top => -bb.y,
+        VerticalAlign::Center => (height - bb.height) * 0.5 - bb.y,
+        VerticalAlign::Bottom => height - bb.height - bb.y,
+        VerticalAlign::Baseline => 0.0,
+    }
+}
+
+#[test]
+fn align_x_and_align_y_position_box_in_cell() {
+    let bb = BoundingBox::new(Vec2::new(-1.0, -3.0), Vec2::new(2.0, 4.0));
+    assert_eq!(align_x(&bb, 10.0, HorizontalAlign::Left), 1.0);
+    assert_eq!(align_x(&bb, 10.0, HorizontalAlign::Center), 5.0);
+    assert_eq!(align_x(&bb, 10.0, HorizontalAlign::Right), 9.0);
+    assert_eq!(align_y(&bb, 10.0, VerticalAlign::Top), 3.0);
+    assert_eq!(align_y(&bb, 10.0, VerticalAlign::Center), 6.0);
+    assert_eq!(align_y(&bb, 10.0, VerticalAlign::Bottom), 9.0);
+    assert_eq!(align_y(&bb, 10.0, VerticalAlign::Baseline), 0.0);
+}
+
+pub fn hstack<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                      args: Vec<Val<'a>>,
+                      named: NamedArgs<'a>)
+                      -> Result<Val<'a>> {
+    let params = ["frames", "gap", "align"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("hstack", &params, args, named)?.into_iter();
+    let (frames, gap) = get_stack_args("hstack", &mut bound, num_given)?;
+    let align = get_vertical_align("hstack", "align", bound.next().unwrap(), 2)?;
+
+    // Frames are placed left to right, aligned in a row as high as the
+    // highest frame.
+    let height = frames.iter().fold(0.0, |h: f64, f| h.max(f.get_bounding_box().height));
+    let mut stack = Frame::new();
+    let mut x = 0.0;
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 { x += gap; }
+        let bb = frame.get_bounding_box();
+        stack.place_frame(frame, Vec2::new(x - bb.x, align_y(bb, height, align)));
+        x += bb.width;
+    }
+
+    // The anchor is at the right end of the row, so stacks can be adjoined.
+    stack.set_anchor(Vec2::new(x, 0.0));
+
+    Ok(Val::Frame(Rc::new(stack)))
+}
+
+pub fn vstack<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                      args: Vec<Val<'a>>,
+                      named: NamedArgs<'a>)
+                      -> Result<Val<'a>> {
+    let params = ["frames", "gap", "align"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("vstack", &params, args, named)?.into_iter();
+    let (frames, gap) = get_stack_args("vstack", &mut bound, num_given)?;
+    let align = get_horizontal_align("vstack", "align", bound.next().unwrap(), 2)?;
+
+    // Frames are placed top to bottom, aligned in a column as wide as the
+    // widest frame.
+    let width = frames.iter().fold(0.0, |w: f64, f| w.max(f.get_bounding_box().width));
+    let mut stack = Frame::new();
+    let mut y = 0.0;
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 { y += gap; }
+        let bb = frame.get_bounding_box();
+        stack.place_frame(frame, Vec2::new(align_x(bb, width, align), y - bb.y));
+        y += bb.height;
+    }
+
+    // The anchor is at the bottom left, below the last frame.
+    stack.set_anchor(Vec2::new(0.0, y));
+
+    Ok(Val::Frame(Rc::new(stack)))
+}
+
+pub fn zstack<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                      args: Vec<Val<'a>>,
+                      named: NamedArgs<'a>)
+                      -> Result<Val<'a>> {
+    let params = ["frames", "halign", "valign"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("zstack", &params, args, named)?.into_iter();
+    let frames = match bound.next().unwrap() {
+        Some(Val::List(vals)) => get_frames("the frames", vals)?,
+        Some(other) => return Err(Error::arg_type("zstack", ValType::List, other.get_type(), 0)),
+        None => return Err(Error::missing_arg("zstack", "frames", 3, num_given)),
+    };
+    let halign = get_horizontal_align("zstack", "halign", bound.next().unwrap(), 1)?;
+    let valign = get_vertical_align("zstack", "valign", bound.next().unwrap(), 2)?;
+
+    // Frames are placed on top of each other, the last one on top, aligned
+    // in a box as large as the largest frame.
+    let width = frames.iter().fold(0.0, |w: f64, f| w.max(f.get_bounding_box().width));
+    let height = frames.iter().fold(0.0, |h: f64, f| h.max(f.get_bounding_box().height));
+    let mut stack = Frame::new();
+    for frame in &frames {
+        let bb = frame.get_bounding_box();
+        stack.place_frame(frame, Vec2::new(align_x(bb, width, halign), align_y(bb, height, valign)));
+    }
+
+    // Like for a row, the anchor is at the right end of the baseline.
+    stack.set_anchor(Vec2::new(width, 0.0));
+
+    Ok(Val::Frame(Rc::new(stack)))
+}
+
+pub fn grid<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                    args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    // Only the frames and the number of columns are required. Without a
+    // cell size, every column is as wide as its widest frame, and every row
+    // as high as its highest frame.
+    let params = ["frames", "columns", "gap", "cell", "fit", "halign", "valign"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("grid", &params, args, named)?.into_iter();
+    let mut frames = match bound.next().unwrap() {
+        Some(Val::List(vals)) => get_frames("the frames", vals)?,
+        Some(other) => return Err(Error::arg_type("grid", ValType::List, other.get_type(), 0)),
+        None => return Err(Error::missing_arg("grid", "frames", 7, num_given)),
+    };
+    let columns = match bound.next().unwrap() {
+        Some(Val::Num(x, 0)) if x >= 1.0 && x.fract() == 0.0 => x as usize,
+        Some(Val::Num(x, 0)) => {
+            let msg = format!("Expected a positive whole number of columns, but found {}.", x);
+            return Err(Error::value(msg))
+        }
+        Some(other) => return Err(Error::arg_type("grid", ValType::Num(0), other.get_type(), 1)),
+        None => return Err(Error::missing_arg("grid", "columns", 7, num_given)),
+    };
+    let (gap_x, gap_y) = match bound.next().unwrap() {
+        Some(Val::Coord(x, y, 1)) => (x, y),
+        Some(other) => return Err(Error::arg_type("grid", ValType::Coord(1), other.get_type(), 2)),
+        None => (0.0, 0.0),
+    };
+    let cell = match bound.next().unwrap() {
+        Some(Val::Coord(x, y, 1)) => Some((x, y)),
+        Some(other) => return Err(Error::arg_type("grid", ValType::Coord(1), other.get_type(), 3)),
+        None => None,
+    };
+
+    // Fitting scales every frame to fit its cell.
+    let fit_cells = match bound.next().unwrap() {
+        Some(Val::Str(ref s)) if s == "on" => true,
+        Some(Val::Str(ref s)) if s == "off" => false,
+        Some(Val::Str(other)) => return Err(invalid_choice("fit", &other, &["on", "off"])),
+        Some(other) => return Err(Error::arg_type("grid", ValType::Str, other.get_type(), 4)),
+        None => false,
+    };
+    let halign = get_horizontal_align("grid", "halign", bound.next().unwrap(), 5)?;
+    let valign = get_vertical_align("grid", "valign", bound.next().unwrap(), 6)?;
+
+    if fit_cells {
+        let size = match cell {
+            Some(size) => size,
+            None => {
+                let msg = "Fitting frames in cells requires cells of a fixed size, \
+                           but the 'cell' argument of 'grid' was not given.".to_string();
+                return Err(Error::value(msg))
+            }
+        };
+        for frame in frames.iter_mut() {
+            let scale = fit_scale(frame.get_bounding_box(), size)?;
+            *frame = Rc::new(scale_frame(frame, scale));
+        }
+    }
+
+    let rows = (frames.len() + columns - 1) / columns;
+
+    // Measure the columns and rows. For baseline alignment, a row must fit
+    // the largest extent above and below the baseline.
+    let mut col_widths = vec![0.0_f64; columns];
+    let mut row_heights = vec![0.0_f64; rows];
+    let mut row_ascents = vec![0.0_f64; rows];
+    let mut row_descents = vec![0.0_f64; rows];
+    for (i, frame) in frames.iter().enumerate() {
+        let (r, c) = (i / columns, i % columns);
+        let bb = frame.get_bounding_box();
+        col_widths[c] = col_widths[c].max(bb.width);
+        row_heights[r] = row_heights[r].max(bb.height);
+        row_ascents[r] = row_ascents[r].max(-bb.y);
+        row_descents[r] = row_descents[r].max(bb.y + bb.height);
+    }
+    for r in 0..rows {
+        if let VerticalAlign::Baseline = valign {
+            row_heights[r] = row_ascents[r] + row_descents[r];
+        }
+    }
+    if let Some((cell_width, cell_height)) = cell {
+        col_widths = vec![cell_width; columns];
+        row_heights = vec![cell_height; rows];
+    }
+
+    let mut col_xs = Vec::with_capacity(columns);
+    let mut x = 0.0;
+    for (c, w) in col_widths.iter().enumerate() {
+        if c > 0 { x += gap_x; }
+        col_xs.push(x);
+        x += *w;
+    }
+    let mut row_ys = Vec::with_capacity(rows);
+    let mut y = 0.0;
+    for (r, h) in row_heights.iter().enumerate() {
+        if r > 0 { y += gap_y; }
+        row_ys.push(y);
+        y += *h;
+    }
+
+    let mut grid = Frame::new();
+    for (i, frame) in frames.iter().enumerate() {
+        let (r, c) = (i / columns, i % columns);
+        let bb = frame.get_bounding_box();
+        let offset_y = match valign {
+            VerticalAlign::Baseline => row_ascents[r],
+            _ => align_y(bb, row_heights[r], valign),
+        };
+        let offset = Vec2::new(col_xs[c] + align_x(bb, col_widths[c], halign), row_ys[r] + offset_y);
+        grid.place_frame(frame, offset);
+    }
+
+    // The grid occupies all of its cells, also when the frames are smaller.
+    grid.union_bounding_box(&BoundingBox::sized(x, y));
+
+    // Like for a vertical stack, the anchor is at the bottom left.
+    grid.set_anchor(Vec2::new(0.0, y));
+
+    Ok(Val::Frame(Rc::new(grid)))
+}
+
+pub fn pad<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    let mut args = bind_required("pad", &["frame", "padding"], args, named)?;
+    let frame = match args.remove(0) {
+        Val::Frame(f) => f,
+        other => return Err(Error::arg_type("pad", ValType::Frame, other.get_type(), 0)),
+    };
+    // The padding is either the same on all sides, or separate horizontal
+    // and vertical padding.
+    let (px, py) = match args.remove(0) {
+        Val::Num(p, 1) => (p, p),
+        Val::Coord(px, py, 1) => (px, py),
+        other => return Err(Error::arg_type("pad", ValType::Num(1), other.get_type(), 1)),
+    };
+    if px < 0.0 || py < 0.0 {
+        let msg = format!("Expected a padding of at least 0, but found ({}, {}).", px, py);
+        return Err(Error::value(msg))
+    }
+
+    // Only the bounding box changes, the elements stay where they are.
+    let mut padded = (*frame).clone();
+    let bb = frame.get_bounding_box();
+    let top_left = Vec2::new(bb.x - px, bb.y - py);
+    let size = Vec2::new(bb.width + 2.0 * px, bb.height + 2.0 * py);
+    padded.union_bounding_box(&BoundingBox::new(top_left, size));
+
+    Ok(Val::Frame(Rc::new(padded)))
+}
+
+/// Returns the path of a rectangle with rounded corners.
+///
+/// The corners are approximated with cubic Bézier curves.
+fn rounded_rectangle(bb: &BoundingBox, radius: f64) -> Vec<PathCommand> {
+    // The radius cannot exceed half of the shortest side.
+    let r = radius.min(bb.width * 0.5).min(bb.height * 0.5).max(0.0);
+    // Distance of the control points from the corner points, for a curve
+    // that is close to a quarter circle.
+    let k = r * (1.0 - 0.5523);
+    let (x0, y0, x1, y1) = (bb.x, bb.y, bb.x + bb.width, bb.y + bb.height);
+    vec![
+        PathCommand::MoveTo(Vec2::new(x0 + r, y0)),
+        PathCommand::LineTo(Vec2::new(x1 - r, y0)),
+        PathCommand::CurveTo(Vec2::new(x1 - k, y0), Vec2::new(x1, y0 + k), Vec2::new(x1, y0 + r)),
+        PathCommand::LineTo(Vec2::new(x1, y1 - r)),
+        PathCommand::CurveTo(Vec2::new(x1, y1 - k), Vec2::new(x1 - k, y1), Vec2::new(x1 - r, y1)),
+        PathCommand::LineTo(Vec2::new(x0 + r, y1)),
+        PathCommand::CurveTo(Vec2::new(x0 + k, y1), Vec2::new(x0, y1 - k), Vec2::new(x0, y1 - r)),
+        PathCommand::LineTo(Vec2::new(x0, y0 + r)),
+        PathCommand::CurveTo(Vec2::new(x0, y0 + k), Vec2::new(x0 + k, y0), Vec2::new(x0 + r, y0)),
+        PathCommand::Close,
+    ]
+}
+
+/// Reads an argument that is either a color, or "none".
+fn get_color_or_none<'a>(fn_name: &str, val: Val<'a>, arg_num: u32) -> Result<Option<Color>> {
+    match val {
+        Val::Col(col) => Ok(Some(col)),
+        Val::Str(ref s) if s == "none" => Ok(None),
+        other => Err(Error::arg_type(fn_name, ValType::Color, other.get_type(), arg_num)),
+    }
+}
+
+pub fn box_frame<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                         args: Vec<Val<'a>>,
+                         named: NamedArgs<'a>)
+                         -> Result<Val<'a>> {
+    // Only the frame is required. By default the box is invisible, so it
+    // only matters which of the fill and stroke are given.
+    let params = ["frame", "fill", "stroke", "radius"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("box", &params, args, named)?.into_iter();
+    let frame = match bound.next().unwrap() {
+        Some(Val::Frame(f)) => f,
+        Some(other) => return Err(Error::arg_type("box", ValType::Frame, other.get_type(), 0)),
+        None => return Err(Error::missing_arg("box", "frame", 4, num_given)),
+    };
+    let fill = match bound.next().unwrap() {
+        Some(val) => get_color_or_none("box", val, 1)?,
+        None => None,
+    };
+    let stroke = match bound.next().unwrap() {
+        Some(val) => get_color_or_none("box", val, 2)?,
+        None => None,
+    };
+    let radius = match bound.next().unwrap() {
+        Some(Val::Num(r, 1)) => r,
+        Some(other) => return Err(Error::arg_type("box", ValType::Num(1), other.get_type(), 3)),
+        None => 0.0,
+    };
+    let line_width = interpreter.env.lookup_len(&Idents(vec!["line_width"]))?;
+
+    // The box goes behind the contents of the frame, on every subframe, so it
+    // stays visible while the contents are revealed.
+    let bb = frame.get_bounding_box().clone();
+    let commands = rounded_rectangle(&bb, radius);
+    let mut background = Subframe::new();
+    if let Some(color) = fill {
+        let path = FillPath {
+            color: color,
+            commands: commands.clone(),
+        };
+        background.place_element(Vec2::zero(), Element::FillPath(path));
+    }
+    if let Some(color) = stroke {
+        let path = StrokePath {
+            color: color,
+            line_width: line_width,
+            commands: commands,
+        };
+        background.place_element(Vec2::zero(), Element::StrokePath(path));
+    }
+
+    let mut boxed = Frame::from_env(frame.get_env().clone());
+    let num_subframes = cmp::max(frame.get_subframes().len(), 1);
+    for _ in 0..num_subframes {
+        boxed.push_subframe(background.clone());
+    }
+    boxed.place_frame(&frame, Vec2::zero());
+    boxed.set_anchor(frame.get_anchor());
+
+    // Half of the stroke lies outside of the box.
+    if stroke.is_some() {
+        let half = line_width * 0.5;
+        let outer = BoundingBox::new(Vec2::new(bb.x - half, bb.y - half),
+                                     Vec2::new(bb.width + line_width, bb.height + line_width));
+        boxed.union_bounding_box(&outer);
+    }
+
+    Ok(Val::Frame(Rc::new(boxed)))
+}
+
+/// Typesets a string with `t`, and returns the resulting frame.
+fn typeset<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>, text: String) -> Result<Rc<Frame<'a>>> {
+    match t(interpreter, vec![Val::Str(text)], Vec::new())? {
+        Val::Frame(f) => Ok(f),
+        _ => unreachable!(),
+    }
+}
+
+/// Formats a number as a lowercase Roman numeral.
+fn to_roman(mut n: usize) -> String {
+    let numerals = [(1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+                    (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i")];
+    let mut result = String::new();
+    for &(value, numeral) in &numerals {
+        while n >= value {
+            result.push_str(numeral);
+            n -= value;
+        }
+    }
+    result
+}
+
+/// Formats a number as letters: a, b, ..., z, aa, ab, and so on.
+fn to_alpha(mut n: usize) -> String {
+    let mut result = Vec::new();
+    while n > 0 {
+        n -= 1;
+        result.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    result.reverse();
+    String::from_utf8(result).unwrap()
+}
+
+#[test]
+fn to_roman_and_to_alpha_format_numbers() {
+    assert_eq!(to_roman(4), "iv");
+    assert_eq!(to_roman(1994), "mcmxciv");
+    assert_eq!(to_alpha(1), "a");
+    assert_eq!(to_alpha(26), "z");
+    assert_eq!(to_alpha(28), "ab");
+}
+
+/// Settings for `bullets`, read once from the environment.
+struct BulletStyle {
+    /// The bullet and the numbering style per level. Levels beyond the end of
+    /// the list use the last one.
+    bullets: Vec<String>,
+    styles: Vec<String>,
+    indent: f64,
+    spacing: f64,
+}
+
+impl BulletStyle {
+    /// Returns the marker for the item with the given 1-based number.
+    fn marker(&self, level: usize, number: usize) -> String {
+        let style = &self.styles[cmp::min(level, self.styles.len() - 1)];
+        match style.as_ref() {
+            "decimal" => format!("{}.", number),
+            "lower-alpha" => format!("{}.", to_alpha(number)),
+            "upper-alpha" => format!("{}.", to_alpha(number).to_uppercase()),
+            "lower-roman" => format!("{}.", to_roman(number)),
+            "upper-roman" => format!("{}.", to_roman(number).to_uppercase()),
+            _ => self.bullets[cmp::min(level, self.bullets.len() - 1)].clone(),
+        }
+    }
+}
+
+/// Reads a variable that is either a string, or a non-empty list of strings
+/// with one entry per nesting level.
+fn lookup_per_level<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>, var_name: &'static str) -> Result<Vec<String>> {
+    let idents = Idents(vec![var_name]);
+    let values = match interpreter.env.lookup(&idents)? {
+        Val::Str(s) => vec![s],
+        Val::List(..) => interpreter.env.lookup_str_list(&idents)?,
+        other => return Err(Error::var_type(&idents, ValType::Str, other.get_type())),
+    };
+    if values.is_empty() {
+        let msg = format!("Expected '{}' to contain at least one value per level, but it is empty.", var_name);
+        return Err(Error::value(msg))
+    }
+    Ok(values)
+}
+
+/// Lays out the items of a (nested) bullet list, appending every item, with
+/// its marker, to `placed`, together with its position.
+///
+/// Returns the y-coordinate of the bottom of the last item.
+fn lay_out_bullets<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                           style: &BulletStyle,
+                           items: Vec<Val<'a>>,
+                           level: usize,
+                           mut bottom: f64,
+                           placed: &mut Vec<(Frame<'a>, Vec2)>)
+                           -> Result<f64> {
+    let mut number = 0;
+    for item in items {
+        let content = match item {
+            Val::Str(s) => typeset(interpreter, s)?,
+            Val::Frame(f) => f,
+            // A list inside the list contains the sub-items of the previous
+            // item, one level deeper.
+            Val::List(sub_items) => {
+                bottom = lay_out_bullets(interpreter, style, sub_items, level + 1, bottom, placed)?;
+                continue
+            }
+            other => {
+                let mut fmt = Formatter::new();
+                fmt.print("Expected the items to be of type 'str', 'frame' or 'list', but found '");
+                fmt.print(other.get_type());
+                fmt.print("'.");
+                return Err(Error::value(fmt.into_string()))
+            }
+        };
+        number += 1;
+        let marker = typeset(interpreter, style.marker(level, number))?;
+
+        // The marker and content share a baseline. The top of the item is
+        // below the bottom of the previous one.
+        let mut item = Frame::new();
+        item.place_frame(&marker, Vec2::zero());
+        item.place_frame(&content, Vec2::new(style.indent, 0.0));
+        item.set_anchor(Vec2::new(style.indent, 0.0) + content.get_anchor());
+
+        let is_first = placed.is_empty();
+        let top = if is_first { bottom } else { bottom + style.spacing };
+        let bb = item.get_bounding_box().clone();
+        let position = Vec2::new(level as f64 * style.indent, top - bb.y);
+        bottom = position.y + bb.y + bb.height;
+        placed.push((item, position));
+    }
+    Ok(bottom)
+}
+
+pub fn bullets<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                       mut args: Vec<Val<'a>>,
+                       named: NamedArgs<'a>)
+                       -> Result<Val<'a>> {
+    validate_args("bullets", &[("items", ValType::List)], &mut args, named)?;
+    let items = match args.remove(0) {
+        Val::List(items) => items,
+        _ => unreachable!(),
+    };
+
+    let style_choices = ["bullet", "decimal", "lower-alpha", "upper-alpha", "lower-roman", "upper-roman"];
+    let styles = lookup_per_level(interpreter, "bullet_style")?;
+    for style in &styles {
+        if !style_choices.contains(&style.as_ref()) {
+            return Err(invalid_choice("bullet_style", style, &style_choices))
+        }
+    }
+    let one_by_one = match interpreter.env.lookup_str(&Idents(vec!["bullet_reveal"]))?.as_ref() {
+        "all" => false,
+        "one_by_one" => true,
+        other => return Err(invalid_choice("bullet_reveal", other, &["all", "one_by_one"])),
+    };
+    let style = BulletStyle {
+        bullets: lookup_per_level(interpreter, "bullet")?,
+        styles: styles,
+        indent: interpreter.env.lookup_len(&Idents(vec!["bullet_indent"]))?,
+        spacing: interpreter.env.lookup_len(&Idents(vec!["bullet_spacing"]))?,
+    };
+
+    let mut placed = Vec::new();
+    lay_out_bullets(interpreter, &style, items, 0, 0.0, &mut placed)?;
+
+    // Like for text, the origin is on the baseline of the first line.
+    let baseline = match placed.first() {
+        Some(&(_, position)) => position.y,
+        None => 0.0,
+    };
+    for &mut (_, ref mut position) in &mut placed {
+        position.y -= baseline;
+    }
+
+    // To reveal the items one by one, subframe i shows the first i + 1
+    // items, the last subframe of every item.
+    let mut frame = Frame::new();
+    if one_by_one {
+        for _ in 0..placed.len() {
+            frame.push_subframe(Subframe::new());
+        }
+    }
+    for (i, &(ref item, position)) in placed.iter().enumerate() {
+        if one_by_one {
+            if let Some(last) = item.get_subframes().last() {
+                for k in i..placed.len() {
+                    let subframe = frame.get_subframe_mut(k);
+                    for pe in last.get_elements() {
+                        subframe.place_element(position + pe.position, pe.element.clone());
+                    }
+                }
+            }
+            frame.union_bounding_box(&item.get_bounding_box().offset(position));
+        } else {
+            frame.place_frame(item, position);
+        }
+    }
+
+    // Like for text, the anchor is at the end of the last line.
+    if let Some(&(ref item, position)) = placed.last() {
+        frame.set_anchor(position + item.get_anchor());
+    }
+
+    Ok(Val::Frame(Rc::new(frame)))
+}
+
+#[derive(Copy, Clone)]
+enum ColumnAlign {
+    Align(HorizontalAlign),
+    /// Aligns the first decimal point of the cells in the column.
+    Decimal,
+}
+
+/// Reads the alignment per column, a list that is empty by default.
+fn get_column_aligns<'a>(val: Option<Val<'a>>) -> Result<Vec<ColumnAlign>> {
+    let vals = match val {
+        Some(Val::List(vals)) => vals,
+        Some(other) => return Err(Error::arg_type("table", ValType::List, other.get_type(), 1)),
+        None => return Ok(Vec::new()),
+    };
+    let choices = ["left", "center", "right", "decimal"];
+    let mut aligns = Vec::with_capacity(vals.len());
+    for val in vals {
+        let align = match val {
+            Val::Str(align) => align,
+            other => return Err(list_element_error("the column alignments", ValType::Str, other.get_type())),
+        };
+        aligns.push(match align.as_ref() {
+            "left" => ColumnAlign::Align(HorizontalAlign::Left),
+            "center" => ColumnAlign::Align(HorizontalAlign::Center),
+            "right" => ColumnAlign::Align(HorizontalAlign::Right),
+            "decimal" => ColumnAlign::Decimal,
+            other => return Err(invalid_choice("align", other, &choices)),
+        });
+    }
+    Ok(aligns)
+}
+
+/// A typeset table cell.
+struct Cell<'a> {
+    frame: Rc<Frame<'a>>,
+    /// For decimal alignment, the x-coordinate of the decimal point in the
+    /// frame. Frames without one align as if it were at their right edge.
+    point_x: f64,
+}
+
+pub fn table<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                     args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Val<'a>> {
+    // Only the rows are required. By default the first row is a header in
+    // bold, with rules above and below the table and below the header.
+    let params = ["rows", "align", "header", "header_font_weight", "header_color",
+                  "padding", "rules", "rule_width"];
+    let num_given = (args.len() + named.len()) as u32;
+    let mut bound = runtime::bind_args("table", &params, args, named)?.into_iter();
+    let rows = match bound.next().unwrap() {
+        Some(Val::List(rows)) => rows,
+        Some(other) => return Err(Error::arg_type("table", ValType::List, other.get_type(), 0)),
+        None => return Err(Error::missing_arg("table", "rows", 8, num_given)),
+    };
+    let aligns = get_column_aligns(bound.next().unwrap())?;
+    let has_header = match bound.next().unwrap() {
+        Some(Val::Str(ref s)) if s == "on" => true,
+        Some(Val::Str(ref s)) if s == "off" => false,
+        Some(Val::Str(other)) => return Err(invalid_choice("header", &other, &["on", "off"])),
+        Some(other) => return Err(Error::arg_type("table", ValType::Str, other.get_type(), 2)),
+        None => true,
+    };
+    // The weight is checked like 'font_weight', when the header is typeset.
+    let header_font_weight = match bound.next().unwrap() {
+        Some(Val::Num(w, 0)) => Val::Num(w, 0),
+        Some(Val::Str(w)) => Val::Str(w),
+        Some(other) => return Err(Error::arg_type("table", ValType::Num(0), other.get_type(), 3)),
+        None => Val::Str("bold".to_string()),
+    };
+    let header_color = match bound.next().unwrap() {
+        Some(val) => get_color_or_none("table", val, 4)?,
+        None => None,
+    };
+    let (px, py) = match bound.next().unwrap() {
+        Some(Val::Coord(x, y, 1)) => (x, y),
+        Some(other) => return Err(Error::arg_type("table", ValType::Coord(1), other.get_type(), 5)),
+        // By default 0.04h by 0.02h.
+        None => (43.2, 21.6),
+    };
+    let rule_choices = ["none", "header", "all"];
+    let rules = match bound.next().unwrap() {
+        Some(Val::Str(rules)) => rules,
+        Some(other) => return Err(Error::arg_type("table", ValType::Str, other.get_type(), 6)),
+        None => "header".to_string(),
+    };
+    if !rule_choices.contains(&rules.as_ref()) {
+        return Err(invalid_choice("rules", &rules, &rule_choices))
+    }
+    let rule_width = match bound.next().unwrap() {
+        Some(Val::Num(w, 1)) => w,
+        Some(other) => return Err(Error::arg_type("table", ValType::Num(1), other.get_type(), 7)),
+        None => 2.0,
+    };
+    let color = interpreter.env.lookup_color(&Idents(vec!["color"]))?;
+
+    // The header row is typeset in an environment with the header style.
+    let mut header_env = interpreter.env.clone();
+    header_env.put("font_weight", header_font_weight);
+    if let Some(col) = header_color {
+        header_env.put("color", Val::Col(col));
+    }
+
+    // Typeset the cells.
+    let mut cells: Vec<Vec<Cell<'a>>> = Vec::with_capacity(rows.len());
+    for (r, row) in rows.into_iter().enumerate() {
+        let vals = match row {
+            Val::List(vals) => vals,
+            other => return Err(list_element_error("the rows", ValType::List, other.get_type())),
+        };
+        let mut row_cells = Vec::with_capacity(vals.len());
+        for (c, val) in vals.into_iter().enumerate() {
+            let is_decimal = match aligns.get(c) {
+                Some(&ColumnAlign::Decimal) => true,
+                _ => false,
+            };
+            let mut cell_interpreter = ExprInterpreter {
+                font_map: &mut *interpreter.font_map,
+                env: if has_header && r == 0 { &header_env } else { interpreter.env },
+                base_dir: interpreter.base_dir,
+            };
+            // Numbers are shown as 'str' would, so rows from 'read_csv' can
+            // be used directly.
+            let val = match val {
+                Val::Num(x, 0) => Val::Str(format!("{}", x)),
+                other => other,
+            };
+            let cell = match val {
+                Val::Str(s) => {
+                    // Measure the text before the decimal point by typesetting
+                    // it separately.
+                    let point_x = match s.find('.') {
+                        Some(i) if is_decimal => {
+                            let before = typeset(&mut cell_interpreter, s[..i].to_string())?;
+                            let bb = before.get_bounding_box();
+                            Some(bb.x + bb.width)
+                        }
+                        _ => None,
+                    };
+                    let frame = typeset(&mut cell_interpreter, s)?;
+                    let bb_right = frame.get_bounding_box().x + frame.get_bounding_box().width;
+                    Cell { frame: frame, point_x: point_x.unwrap_or(bb_right) }
+                }
+                Val::Frame(f) => {
+                    let bb_right = f.get_bounding_box().x + f.get_bounding_box().width;
+                    Cell { frame: f, point_x: bb_right }
+                }
+                other => {
+                    let mut fmt = Formatter::new();
+                    fmt.print("Expected the cells to be of type 'str', 'num' or 'frame', but found '");
+                    fmt.print(other.get_type());
+                    fmt.print("'.");
+                    return Err(Error::value(fmt.into_string()))
+                }
+            };
+            row_cells.push(cell);
+        }
+        cells.push(row_cells);
+    }
+
+    // Measure the columns. With decimal alignment, a column must fit the
+    // widest part before the decimal point and the widest part after it.
+    let columns = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut col_widths = vec![0.0_f64; columns];
+    let mut col_befores = vec![0.0_f64; columns];
+    let mut col_afters = vec![0.0_f64; columns];
+    for row in &cells {
+        for (c, cell) in row.iter().enumerate() {
+            let bb = cell.frame.get_bounding_box();
+            col_widths[c] = col_widths[c].max(bb.width);
+            col_befores[c] = col_befores[c].max(cell.point_x - bb.x);
+            col_afters[c] = col_afters[c].max(bb.x + bb.width - cell.point_x);
+        }
+    }
+    for c in 0..columns {
+        if let Some(&ColumnAlign::Decimal) = aligns.get(c) {
+            col_widths[c] = col_befores[c] + col_afters[c];
+        }
+    }
+    let mut col_xs = Vec::with_capacity(columns);
+    let mut width = 0.0;
+    for w in &col_widths {
+        col_xs.push(width + px);
+        width += w + 2.0 * px;
+    }
+
+    // Lay out the rows, aligning the cells in a row on their baselines, and
+    // record where the rules go.
+    let mut table = Frame::new();
+    let mut rule_ys = Vec::new();
+    if rules != "none" {
+        rule_ys.push(0.0);
+    }
+    let mut y = 0.0;
+    for (r, row) in cells.iter().enumerate() {
+        let ascent = row.iter().map(|cell| -cell.frame.get_bounding_box().y).fold(0.0, f64::max);
+        let descent = row.iter().map(|cell| {
+            let bb = cell.frame.get_bounding_box();
+            bb.y + bb.height
+        }).fold(0.0, f64::max);
+        let baseline = y + py + ascent;
+        for (c, cell) in row.iter().enumerate() {
+            let bb = cell.frame.get_bounding_box();
+            let offset_x = match aligns.get(c) {
+                Some(&ColumnAlign::Decimal) => col_befores[c] - cell.point_x,
+                Some(&ColumnAlign::Align(align)) => align_x(bb, col_widths[c], align),
+                None => align_x(bb, col_widths[c], HorizontalAlign::Left),
+            };
+            table.place_frame(&cell.frame, Vec2::new(col_xs[c] + offset_x, baseline));
+        }
+        y = baseline + descent + py;
+
+        let is_last = r + 1 == cells.len();
+        let below_header = has_header && r == 0;
+        let has_rule = match rules.as_ref() {
+            "all" => true,
+            "header" => is_last || below_header,
+            _ => false,
+        };
+        if has_rule {
+            rule_ys.push(y);
+        }
+    }
+
+    for rule_y in rule_ys {
+        let rule = StrokePolygon {
+            color: color,
+            line_width: rule_width,
+            close: false,
+            vertices: vec![Vec2::new(0.0, rule_y), Vec2::new(width, rule_y)],
+        };
+        table.place_element_on_last_subframe(Vec2::zero(), Element::StrokePolygon(rule));
+    }
+
+    // The table occupies its full area, including the padding and the rules.
+    let half_rule = if rules == "none" { 0.0 } else { rule_width * 0.5 };
+    let top_left = Vec2::new(0.0, -half_rule);
+    table.union_bounding_box(&BoundingBox::new(top_left, Vec2::new(width, y + 2.0 * half_rule)));
+
+    // The anchor is at the bottom left, so a frame adjoined with `~` goes
+    // below the table, as for a grid.
+    table.set_anchor(Vec2::new(0.0, y));
+
+    Ok(Val::Frame(Rc::new(table)))
+}
+
+/// Colors for syntax highlighting.
+struct CodeTheme {
+    plain: Color,
+    keyword: Color,
+    string: Color,
+    number: Color,
+    comment: Color,
+    highlight: Color,
+    background: Option<Color>,
+}
+
+impl CodeTheme {
+    fn color(&self, kind: SpanKind) -> Color {
+        match kind {
+            SpanKind::Plain => self.plain,
+            SpanKind::Keyword => self.keyword,
+            SpanKind::String => self.string,
+            SpanKind::Number => self.number,
+            SpanKind::Comment => self.comment,
+        }
+    }
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> Color {
+    Color::new(r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
+}
+
+fn lookup_code_theme<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>) -> Result<CodeTheme> {
+    match interpreter.env.lookup_str(&Idents(vec!["code_theme"]))?.as_ref() {
+        "light" => Ok(CodeTheme {
+            plain: rgb(0x38, 0x3a, 0x42),
+            keyword: rgb(0xa6, 0x26, 0xa4),
+            string: rgb(0x50, 0xa1, 0x4f),
+            number: rgb(0x98, 0x68, 0x01),
+            comment: rgb(0xa0, 0xa1, 0xa7),
+            highlight: rgb(0xff, 0xf3, 0xb0),
+            background: None,
+        }),
+        "dark" => Ok(CodeTheme {
+            plain: rgb(0xab, 0xb2, 0xbf),
+            keyword: rgb(0xc6, 0x78, 0xdd),
+            string: rgb(0x98, 0xc3, 0x79),
+            number: rgb(0xd1, 0x9a, 0x66),
+            comment: rgb(0x7f, 0x84, 0x8e),
+            highlight: rgb(0x3e, 0x44, 0x51),
+            background: Some(rgb(0x28, 0x2c, 0x34)),
+        }),
+        other => Err(invalid_choice("code_theme", other, &["light", "dark"])),
+    }
+}
+
+/// Returns the 1-based numbers of the lines to highlight.
+fn lookup_highlight_lines<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>) -> Result<Vec<usize>> {
+    let idents = Idents(vec!["code_highlight_lines"]);
+    let vals = match interpreter.env.lookup(&idents)? {
+        Val::List(vals) => vals,
+        other => return Err(Error::var_type(&idents, ValType::List, other.get_type())),
+    };
+    let mut lines = Vec::with_capacity(vals.len());
+    for val in vals {
+        match val {
+            Val::Num(x, 0) if x >= 1.0 && x.fract() == 0.0 => lines.push(x as usize),
+            Val::Num(x, 0) => {
+                let msg = format!("Expected line numbers to be positive whole numbers, but found {}.", x);
+                return Err(Error::value(msg))
+            }
+            other => return Err(list_element_error("'code_highlight_lines'", ValType::Num(0), other.get_type())),
+        }
+    }
+    Ok(lines)
+}
+
+/// Returns a rectangle from `(x0, y0)` to `(x1, y1)`.
+fn rectangle(color: Color, x0: f64, y0: f64, x1: f64, y1: f64) -> Element {
+    Element::FillPolygon(FillPolygon {
+        color: color,
+        vertices: vec![
+            Vec2::new(x0, y0),
+            Vec2::new(x1, y0),
+            Vec2::new(x1, y1),
+            Vec2::new(x0, y1),
+        ],
+    })
+}
+
+pub fn code<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                    mut args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    validate_args("code", &[("source", ValType::Str), ("language", ValType::Str)], &mut args, named)?;
+    let source = match args.remove(0) {
+        Val::Str(s) => s.replace('\t', "    "),
+        _ => unreachable!(),
+    };
+    let language = match args.remove(0) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+    let spans = match highlight::highlight(&source, &language) {
+        Some(spans) => spans,
+        None => return Err(invalid_choice("language", &language, &highlight::LANGUAGES)),
+    };
+
+    let theme = lookup_code_theme(interpreter)?;
+    let line_numbers = match interpreter.env.lookup_str(&Idents(vec!["code_line_numbers"]))?.as_ref() {
+        "on" => true,
+        "off" => false,
+        other => return Err(invalid_choice("code_line_numbers", other, &["on", "off"])),
+    };
+    let highlight_lines = lookup_highlight_lines(interpreter)?;
+    let font_size = interpreter.env.lookup_len(&Idents(vec!["font_size"]))?;
+    let line_height = interpreter.env.lookup_len(&Idents(vec!["line_height"]))?;
+
+    // Code is typeset in the code font, with a color per kind of span. Text
+    // layout settings that would break the columns of the code, or decorate
+    // it, are reset to their defaults.
+    let mut code_env = interpreter.env.clone();
+    code_env.put("font_family", interpreter.env.lookup(&Idents(vec!["code_font_family"]))?);
+    let defaults = runtime::Env::new();
+    for &name in &["text_align", "writing_mode", "letter_spacing", "word_spacing", "kerning",
+                   "text_decoration", "text_background"] {
+        code_env.put(name, defaults.lookup(&Idents(vec![name]))?);
+    }
+    let kinds = [SpanKind::Plain, SpanKind::Keyword, SpanKind::String, SpanKind::Number, SpanKind::Comment];
+    let envs: Vec<_> = kinds.iter().map(|&kind| {
+        let mut env = code_env.clone();
+        env.put("color", Val::Col(theme.color(kind)));
+        env
+    }).collect();
+
+    // Typeset every line as a row of spans, split at newlines.
+    let mut lines = vec![Frame::new()];
+    let mut x = 0.0;
+    for (kind, span) in spans {
+        let env = &envs[kinds.iter().position(|&k| k == kind).unwrap()];
+        for (i, part) in span.split('\n').enumerate() {
+            if i > 0 {
+                lines.push(Frame::new());
+                x = 0.0;
+            }
+            if part.len() == 0 {
+                continue
+            }
+            let mut span_interpreter = ExprInterpreter {
+                font_map: &mut *interpreter.font_map,
+                env: env,
+                base_dir: interpreter.base_dir,
+            };
+            let frame = typeset(&mut span_interpreter, part.to_string())?;
+            let line = lines.last_mut().unwrap();
+            line.place_frame(&frame, Vec2::new(x, 0.0));
+            x += frame.get_anchor().x;
+        }
+    }
+
+    // Line numbers are right-aligned in a gutter, one em away from the code.
+    let mut numbers = Vec::new();
+    let mut gutter = 0.0_f64;
+    if line_numbers {
+        for i in 0..lines.len() {
+            let mut number_interpreter = ExprInterpreter {
+                font_map: &mut *interpreter.font_map,
+                env: &envs[kinds.iter().position(|&k| k == SpanKind::Comment).unwrap()],
+                base_dir: interpreter.base_dir,
+            };
+            let number = typeset(&mut number_interpreter, format!("{}", i + 1))?;
+            gutter = gutter.max(number.get_anchor().x);
+            numbers.push(number);
+        }
+        gutter += font_size;
+    }
+
+    let width = lines.iter().fold(0.0_f64, |w, line| {
+        let bb = line.get_bounding_box();
+        w.max(gutter + bb.x + bb.width)
+    });
+
+    // Most of a line is above its baseline, but leave about a fifth of the
+    // line height for descenders.
+    let top = -0.8 * line_height;
+    let bottom = (lines.len() - 1) as f64 * line_height + 0.2 * line_height;
+
+    let mut frame = Frame::new();
+    if let Some(background) = theme.background {
+        let pad = 0.5 * font_size;
+        let bg = rectangle(background, -pad, top - pad, width + pad, bottom + pad);
+        frame.place_element_on_last_subframe(Vec2::zero(), bg);
+        let size = Vec2::new(width + 2.0 * pad, bottom - top + 2.0 * pad);
+        frame.union_bounding_box(&BoundingBox::new(Vec2::new(-pad, top - pad), size));
+    }
+    for &n in &highlight_lines {
+        if n <= lines.len() {
+            let y = (n - 1) as f64 * line_height;
+            let hl = rectangle(theme.highlight, 0.0, y + top, width, y + top + line_height);
+            frame.place_element_on_last_subframe(Vec2::zero(), hl);
+        }
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let y = i as f64 * line_height;
+        if let Some(number) = numbers.get(i) {
+            let x = gutter - font_size - number.get_anchor().x;
+            frame.place_frame(number, Vec2::new(x, y));
+        }
+        frame.place_frame(line, Vec2::new(gutter, y));
+    }
+    frame.union_bounding_box(&BoundingBox::new(Vec2::new(0.0, top), Vec2::new(width, bottom - top)));
+
+    // The anchor is below the last line, at the left of the line numbers.
+    frame.set_anchor(Vec2::new(0.0, bottom));
+
+    Ok(Val::Frame(Rc::new(frame)))
+}
+
+pub fn math<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                    mut args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    validate_args("math", &[("formula", ValType::Str)], &mut args, named)?;
+    let formula = match args.remove(0) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+
+    // Math fonts come in a single style, so only the family is configurable.
+    let font = FontSpec {
+        family: interpreter.env.lookup_str(&Idents(vec!["math_font_family"]))?,
+        weight: 400,
+        slant: Slant::Roman,
+        stretch: 100,
+        variations: Vec::new(),
+    };
+    let font_size = interpreter.env.lookup_len(&Idents(vec!["font_size"]))?;
+    let color = interpreter.env.lookup_color(&Idents(vec!["color"]))?;
+    let display = match interpreter.env.lookup_str(&Idents(vec!["math_style"]))?.as_ref() {
+        "inline" => false,
+        "display" => true,
+        other => return Err(invalid_choice("math_style", other, &["inline", "display"])),
+    };
+
+    let formula_box = math::typeset(interpreter.font_map, &font, font_size, color, display, &formula)?;
+
+    let mut frame = Frame::new();
+    for (position, element) in formula_box.elements {
+        frame.place_element_on_last_subframe(position, element);
+    }
+    let top_left = Vec2::new(0.0, -formula_box.ascent);
+    let size = Vec2::new(formula_box.width, formula_box.ascent + formula_box.descent);
+    frame.union_bounding_box(&BoundingBox::new(top_left, size));
+
+    // The origin is on the baseline, like for text, and the anchor is at the
+    // end of the baseline, so a formula can be adjoined to text with `~`.
+    frame.set_anchor(Vec2::new(formula_box.width, 0.0));
+
+    Ok(Val::Frame(Rc::new(frame)))
+}
+
+#[test]
+fn math_and_t_agree_on_width() {
+    use std::path::Path;
+    use runtime::Env;
+
+    // The same digits set with t() in the math font have the same advances,
+    // so both frames should be equally wide.
+    let mut font_map = FontMap::new();
+    let mut env = Env::new();
+    env.put("font_family", Val::Str("Latin Modern Math".to_string()));
+    let mut interpreter = ExprInterpreter {
+        font_map: &mut font_map,
+        env: &env,
+        base_dir: Path::new("."),
+    };
+
+    // Fontconfig substitutes a different font when Latin Modern Math is not
+    // installed, and that one has no MATH table; skip the test in that case.
+    let spec = lookup_font_spec(&mut interpreter).unwrap();
+    let has_math = match interpreter.font_map.get_hb_font(&spec) {
+        Ok(font) => font.has_math_data(),
+        Err(..) => false,
+    };
+    if !has_math {
+        return
+    }
+
+    fn width_of(val: Result<Val>) -> f64 {
+        match val.unwrap() {
+            Val::Frame(frame) => frame.get_bounding_box().width,
+            _ => panic!("Expected a frame."),
+        }
+    }
+    let text_width = width_of(t(&mut interpreter, vec![Val::Str("2017".to_string())], Vec::new()));
+    let math_width = width_of(math(&mut interpreter, vec![Val::Str("2017".to_string())], Vec::new()));
+    assert!(text_width > 100.0);
+    assert!((text_width - math_width).abs() < 0.01 * text_width);
+}
+
+/// Returns the range of an axis rounded outward to a multiple of a step of
+/// 1, 2 or 5 times a power of ten, such that there are at most `max_ticks`
+/// steps. Returns (low, high, step).
+fn nice_ticks(low: f64, high: f64, max_ticks: u32) -> (f64, f64, f64) {
+    // An empty range still gets an axis around the value.
+    let (low, high) = if high > low { (low, high) } else { (low - 1.0, high + 1.0) };
+    let rough_step = (high - low) / max_ticks as f64;
+    let magnitude = 10.0_f64.powf(rough_step.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0].iter()
+                                    .map(|m| m * magnitude)
+                                    .find(|&s| s >= rough_step)
+                                    .unwrap_or(10.0 * magnitude);
+    ((low / step).floor() * step, (high / step).ceil() * step, step)
+}
+
+#[test]
+fn nice_ticks_rounds_to_nice_steps() {
+    assert_eq!(nice_ticks(0.0, 9.0, 5), (0.0, 10.0, 2.0));
+    assert_eq!(nice_ticks(3.0, 97.0, 5), (0.0, 100.0, 20.0));
+    assert_eq!(nice_ticks(-0.3, 0.4, 5), (-0.4, 0.4, 0.2));
+    assert_eq!(nice_ticks(5.0, 5.0, 5), (4.0, 6.0, 0.5));
+}
+
+/// Formats a tick value with as many decimals as the step needs.
+fn format_tick(value: f64, step: f64) -> String {
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    // Avoid printing "-0" for values that are zero up to rounding.
+    let value = if value.abs() < step * 1e-6 { 0.0 } else { value };
+    format!("{:.*}", decimals, value)
+}
+
+#[test]
+fn format_tick_uses_decimals_of_step() {
+    assert_eq!(format_tick(20.0, 20.0), "20");
+    assert_eq!(format_tick(0.4, 0.2), "0.4");
+    assert_eq!(format_tick(-0.00000001, 0.5), "0.0");
+}
+
+/// Settings for charts, read once from the environment.
+struct ChartStyle {
+    width: f64,
+    height: f64,
+    color: Color,
+    series_colors: Vec<Color>,
+    grid_color: Option<Color>,
+    line_width: f64,
+    marker_size: f64,
+    font_size: f64,
+}
+
+impl ChartStyle {
+    fn series_color(&self, i: usize) -> Color {
+        if self.series_colors.is_empty() {
+            self.color
+        } else {
+            self.series_colors[i % self.series_colors.len()]
+        }
+    }
+}
+
+fn lookup_chart_style<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>) -> Result<ChartStyle> {
+    let size_idents = Idents(vec!["chart_size"]);
+    let (width, height) = match interpreter.env.lookup(&size_idents)? {
+        Val::Coord(w, h, 1) => (w, h),
+        other => return Err(Error::var_type(&size_idents, ValType::Coord(1), other.get_type())),
+    };
+    let colors_idents = Idents(vec!["chart_colors"]);
+    let mut series_colors = Vec::new();
+    match interpreter.env.lookup(&colors_idents)? {
+        Val::List(vals) => for val in vals {
+            match val {
+                Val::Col(col) => series_colors.push(col),
+                other => return Err(list_element_error("'chart_colors'", ValType::Color, other.get_type())),
+            }
+        },
+        other => return Err(Error::var_type(&colors_idents, ValType::List, other.get_type())),
+    }
+    let grid_idents = Idents(vec!["chart_grid_color"]);
+    let grid_color = match interpreter.env.lookup(&grid_idents)? {
+        Val::Col(col) => Some(col),
+        Val::Str(ref s) if s == "none" => None,
+        other => return Err(Error::var_type(&grid_idents, ValType::Color, other.get_type())),
+    };
+    Ok(ChartStyle {
+        width: width,
+        height: height,
+        color: interpreter.env.lookup_color(&Idents(vec!["color"]))?,
+        series_colors: series_colors,
+        grid_color: grid_color,
+        line_width: interpreter.env.lookup_len(&Idents(vec!["chart_line_width"]))?,
+        marker_size: interpreter.env.lookup_len(&Idents(vec!["chart_marker_size"]))?,
+        font_size: interpreter.env.lookup_len(&Idents(vec!["font_size"]))?,
+    })
+}
+
+/// Maps data coordinates to the plot area, which spans from (0, 0) to the
+/// chart size, with the y-axis pointing up.
+struct ChartAxes {
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+    width: f64,
+    height: f64,
+}
+
+impl ChartAxes {
+    fn map(&self, x: f64, y: f64) -> Vec2 {
+        let (x0, x1) = self.x_range;
+        let (y0, y1) = self.y_range;
+        Vec2::new((x - x0) / (x1 - x0) * self.width,
+                  self.height - (y - y0) / (y1 - y0) * self.height)
+    }
+}
+
+/// A tick on an axis: the position in data coordinates, and the label.
+type Tick = (f64, String);
+
+fn numeric_ticks(low: f64, high: f64, step: f64) -> Vec<Tick> {
+    let n = ((high - low) / step).round() as i64;
+    (0..n + 1).map(|i| {
+        let value = low + i as f64 * step;
+        (value, format_tick(value, step))
+    }).collect()
+}
+
+fn stroke(color: Color, line_width: f64, vertices: Vec<Vec2>) -> Element {
+    Element::StrokePolygon(StrokePolygon {
+        color: color,
+        line_width: line_width,
+        close: false,
+        vertices: vertices,
+    })
+}
+
+/// Builds the frame of a chart: gridlines, then the data elements, and then
+/// the axes with their ticks and labels on top.
+fn make_chart<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                      style: &ChartStyle,
+                      axes: &ChartAxes,
+                      x_ticks: &[Tick],
+                      y_ticks: &[Tick],
+                      x_grid: bool,
+                      data: Vec<Element>)
+                      -> Result<Frame<'a>> {
+    let (w, h) = (axes.width, axes.height);
+    let tick_length = 0.25 * style.font_size;
+    let axis_width = 0.5 * style.line_width;
+    let mut chart = Frame::new();
+
+    if let Some(grid_color) = style.grid_color {
+        for &(y, _) in y_ticks {
+            let p = axes.map(axes.x_range.0, y);
+            chart.place_element_on_last_subframe(Vec2::zero(), stroke(grid_color, axis_width, vec![p, Vec2::new(w, p.y)]));
+        }
+        if x_grid {
+            for &(x, _) in x_ticks {
+                let p = axes.map(x, axes.y_range.0);
+                chart.place_element_on_last_subframe(Vec2::zero(), stroke(grid_color, axis_width, vec![p, Vec2::new(p.x, 0.0)]));
+            }
+        }
+    }
+
+    for element in data {
+        chart.place_element_on_last_subframe(Vec2::zero(), element);
+    }
+
+    let axes_line = vec![Vec2::new(0.0, 0.0), Vec2::new(0.0, h), Vec2::new(w, h)];
+    chart.place_element_on_last_subframe(Vec2::zero(), stroke(style.color, axis_width, axes_line));
+
+    // Labels on the y-axis are right-aligned next to the ticks, and centered
+    // on them vertically, assuming digits are about 0.7em high.
+    for &(y, ref label) in y_ticks {
+        let p = axes.map(axes.x_range.0, y);
+        let tick = vec![p, Vec2::new(-tick_length, p.y)];
+        chart.place_element_on_last_subframe(Vec2::zero(), stroke(style.color, axis_width, tick));
+        let label = typeset(interpreter, label.clone())?;
+        let bb = label.get_bounding_box().clone();
+        let offset = Vec2::new(-2.0 * tick_length - bb.x - bb.width, p.y + 0.35 * style.font_size);
+        chart.place_frame(&label, offset);
+    }
+
+    // Labels on the x-axis are centered below the ticks.
+    for &(x, ref label) in x_ticks {
+        let p = axes.map(x, axes.y_range.0);
+        let tick = vec![Vec2::new(p.x, h), Vec2::new(p.x, h + tick_length)];
+        chart.place_element_on_last_subframe(Vec2::zero(), stroke(style.color, axis_width, tick));
+        let label = typeset(interpreter, label.clone())?;
+        let bb = label.get_bounding_box().clone();
+        let offset = Vec2::new(p.x - bb.x - bb.width * 0.5, h + tick_length + style.font_size);
+        chart.place_frame(&label, offset);
+    }
+
+    chart.union_bounding_box(&BoundingBox::sized(w, h));
+    chart.set_anchor(Vec2::new(w, h));
+    Ok(chart)
+}
+
+/// Reads a list of dimensionless numbers.
+fn get_numbers<'a>(list_name: &str, vals: Vec<Val<'a>>) -> Result<Vec<f64>> {
+    let mut numbers = Vec::with_capacity(vals.len());
+    for val in vals {
+        match val {
+            Val::Num(x, 0) => numbers.push(x),
+            other => return Err(list_element_error(list_name, ValType::Num(0), other.get_type())),
+        }
+    }
+    Ok(numbers)
+}
+
+/// Reads a [label, value] pair, where the value is of the given type.
+fn get_labeled<'a>(list_name: &str, val: Val<'a>) -> Result<(String, Val<'a>)> {
+    let msg = format!("Expected {} to contain [label, value] pairs, where the label is a 'str'.", list_name);
+    match val {
+        Val::List(mut pair) => {
+            if pair.len() != 2 {
+                return Err(Error::value(msg))
+            }
+            match pair.remove(0) {
+                Val::Str(label) => Ok((label, pair.remove(0))),
+                _ => Err(Error::value(msg)),
+            }
+        }
+        _ => Err(Error::value(msg)),
+    }
+}
+
+/// Converts a record into a list of [name, value] pairs, the shape that charts
+/// take for labeled data. Other values are returned unchanged.
+fn record_to_pairs<'a>(val: Val<'a>) -> Val<'a> {
+    match val {
+        Val::Record(fields) => {
+            Val::List(fields.into_iter().map(|(k, v)| Val::List(vec![Val::Str(k), v])).collect())
+        }
+        other => other,
+    }
+}
+
+/// Returns the smallest and largest value, or zeros if there are no values.
+fn min_max(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0)
+    }
+    values.iter().fold((::std::f64::INFINITY, ::std::f64::NEG_INFINITY), |(lo, hi), &v| (lo.min(v), hi.max(v)))
+}
+
+pub fn bar_chart<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                         args: Vec<Val<'a>>,
+                         named: NamedArgs<'a>)
+                         -> Result<Val<'a>> {
+    // A record is converted after binding, so it can be passed by name too.
+    let mut args = bind_required("bar_chart", &["bars"], args, named)?;
+    let vals = match record_to_pairs(args.remove(0)) {
+        Val::List(vals) => vals,
+        other => return Err(Error::arg_type("bar_chart", ValType::List, other.get_type(), 0)),
+    };
+
+    // The data is either a list of numbers, or of [label, number] pairs.
+    // Without labels, bars are numbered.
+    let mut labels = Vec::with_capacity(vals.len());
+    let mut values = Vec::with_capacity(vals.len());
+    for (i, val) in vals.into_iter().enumerate() {
+        match val {
+            Val::Num(x, 0) => {
+                labels.push(format!("{}", i + 1));
+                values.push(x);
+            }
+            other => {
+                let (label, value) = get_labeled("the bars", other)?;
+                labels.push(label);
+                values.extend(get_numbers("the bars", vec![value])?);
+            }
+        }
+    }
+
+    let style = lookup_chart_style(interpreter)?;
+    // Bars start at zero, so the axis includes zero.
+    let (lo, hi) = min_max(&values);
+    let (y0, y1, step) = nice_ticks(lo.min(0.0), hi.max(0.0), 5);
+    let axes = ChartAxes {
+        x_range: (0.0, cmp::max(values.len(), 1) as f64),
+        y_range: (y0, y1),
+        width: style.width,
+        height: style.height,
+    };
+
+    // Every bar is centered in a band, and fills most of it.
+    let mut bars = Vec::with_capacity(values.len());
+    for (i, &v) in values.iter().enumerate() {
+        let x = i as f64;
+        let p0 = axes.map(x + 0.15, 0.0);
+        let p1 = axes.map(x + 0.85, v);
+        bars.push(Element::FillPolygon(FillPolygon {
+            color: style.series_color(i),
+            vertices: vec![p0, Vec2::new(p1.x, p0.y), p1, Vec2::new(p0.x, p1.y)],
+        }));
+    }
+
+    let x_ticks: Vec<Tick> = labels.into_iter().enumerate().map(|(i, l)| (i as f64 + 0.5, l)).collect();
+    let y_ticks = numeric_ticks(y0, y1, step);
+    let chart = make_chart(interpreter, &style, &axes, &x_ticks, &y_ticks, false, bars)?;
+    Ok(Val::Frame(Rc::new(chart)))
+}
+
+pub fn line_chart<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                          args: Vec<Val<'a>>,
+                          named: NamedArgs<'a>)
+                          -> Result<Val<'a>> {
+    let mut args = bind_required("line_chart", &["series"], args, named)?;
+    let vals = match record_to_pairs(args.remove(0)) {
+        Val::List(vals) => vals,
+        other => return Err(Error::arg_type("line_chart", ValType::List, other.get_type(), 0)),
+    };
+
+    // The data is either a single series of numbers, or a list of
+    // [label, numbers] pairs, one per series.
+    let is_single = vals.iter().all(|v| match *v { Val::Num(_, 0) => true, _ => false });
+    let mut series = Vec::new();
+    if is_single {
+        series.push((None, get_numbers("the values", vals)?));
+    } else {
+        for val in vals {
+            let (label, values) = get_labeled("the series", val)?;
+            match values {
+                Val::List(values) => series.push((Some(label), get_numbers("the series", values)?)),
+                other => return Err(list_element_error("the series", ValType::List, other.get_type())),
+            }
+        }
+    }
+
+    let style = lookup_chart_style(interpreter)?;
+    let all_values: Vec<f64> = series.iter().flat_map(|&(_, ref vs)| vs.iter().cloned()).collect();
+    let (lo, hi) = min_max(&all_values);
+    let (y0, y1, y_step) = nice_ticks(lo, hi, 5);
+    // Points are numbered from 1 on the x-axis.
+    let n = series.iter().map(|&(_, ref vs)| vs.len()).max().unwrap_or(0);
+    let (x0, x1, x_step) = nice_ticks(1.0, cmp::max(n, 1) as f64, 8);
+    let x_step = x_step.max(1.0);
+    let axes = ChartAxes {
+        x_range: (x0, x1),
+        y_range: (y0, y1),
+        width: style.width,
+        height: style.height,
+    };
+
+    let mut lines = Vec::with_capacity(series.len());
+    for (i, &(_, ref values)) in series.iter().enumerate() {
+        let points = values.iter().enumerate().map(|(j, &v)| axes.map(j as f64 + 1.0, v)).collect();
+        lines.push(stroke(style.series_color(i), style.line_width, points));
+    }
+
+    let x_ticks = numeric_ticks(x0, x1, x_step);
+    let y_ticks = numeric_ticks(y0, y1, y_step);
+    let mut chart = make_chart(interpreter, &style, &axes, &x_ticks, &y_ticks, true, lines)?;
+
+    // A legend to the right of the plot lists the labeled series.
+    let legend_x = style.width + style.font_size;
+    let line_height = interpreter.env.lookup_len(&Idents(vec!["line_height"]))?;
+    for (i, &(ref label, _)) in series.iter().enumerate() {
+        if let Some(ref label) = *label {
+            let y = (i as f64 + 1.0) * line_height;
+            let sample = vec![Vec2::new(legend_x, y - 0.35 * style.font_size),
+                              Vec2::new(legend_x + style.font_size, y - 0.35 * style.font_size)];
+            chart.place_element_on_last_subframe(Vec2::zero(), stroke(style.series_color(i), style.line_width, sample));
+            let text = typeset(interpreter, label.clone())?;
+            chart.place_frame(&text, Vec2::new(legend_x + 1.5 * style.font_size, y));
+        }
+    }
+
+    Ok(Val::Frame(Rc::new(chart)))
+}
+
+pub fn scatter<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                       mut args: Vec<Val<'a>>,
+                       named: NamedArgs<'a>)
+                       -> Result<Val<'a>> {
+    validate_args("scatter", &[("points", ValType::List)], &mut args, named)?;
+    let mut points = Vec::new();
+    match args.remove(0) {
+        Val::List(vals) => for val in vals {
+            match val {
+                Val::Coord(x, y, 0) => points.push((x, y)),
+                other => return Err(list_element_error("the points", ValType::Coord(0), other.get_type())),
+            }
+        },
+        _ => unreachable!(),
+    }
+
+    let style = lookup_chart_style(interpreter)?;
+    let xs: Vec<f64> = points.iter().map(|&(x, _)| x).collect();
+    let ys: Vec<f64> = points.iter().map(|&(_, y)| y).collect();
+    let (x_lo, x_hi) = min_max(&xs);
+    let (y_lo, y_hi) = min_max(&ys);
+    let (x0, x1, x_step) = nice_ticks(x_lo, x_hi, 8);
+    let (y0, y1, y_step) = nice_ticks(y_lo, y_hi, 5);
+    let axes = ChartAxes {
+        x_range: (x0, x1),
+        y_range: (y0, y1),
+        width: style.width,
+        height: style.height,
+    };
+
+    // Markers are circles, which are squares with fully rounded corners.
+    let r = style.marker_size * 0.5;
+    let markers = points.iter().map(|&(x, y)| {
+        let p = axes.map(x, y);
+        let bb = BoundingBox::new(Vec2::new(p.x - r, p.y - r), Vec2::new(2.0 * r, 2.0 * r));
+        Element::FillPath(FillPath {
+            color: style.series_color(0),
+            commands: rounded_rectangle(&bb, r),
+        })
+    }).collect();
+
+    let x_ticks = numeric_ticks(x0, x1, x_step);
+    let y_ticks = numeric_ticks(y0, y1, y_step);
+    let chart = make_chart(interpreter, &style, &axes, &x_ticks, &y_ticks, true, markers)?;
+    Ok(Val::Frame(Rc::new(chart)))
+}
+
+/// Reads a data file, relative to the source file.
+fn read_data_file<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>, path: &str) -> Result<String> {
+    let mut contents = String::new();
+    let full_path = interpreter.base_dir.join(path);
+    match File::open(&full_path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(..) => Ok(contents),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(Error::missing_file(path.into())),
+        Err(err) => Err(Error::Other(format!("Cannot read '{}': {}.", path, err))),
+    }
+}
+
+pub fn read_csv<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                        mut args: Vec<Val<'a>>,
+                        named: NamedArgs<'a>)
+                        -> Result<Val<'a>> {
+    validate_args("read_csv", &[("path", ValType::Str)], &mut args, named)?;
+    let path = match args.remove(0) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+    let contents = read_data_file(interpreter, &path)?;
+    data::parse_csv(&path, &contents)
+}
+
+pub fn read_json<'i, 'a>(interpreter: &mut ExprInterpreter<'i, 'a>,
+                         mut args: Vec<Val<'a>>,
+                         named: NamedArgs<'a>)
+                         -> Result<Val<'a>> {
+    validate_args("read_json", &[("path", ValType::Str)], &mut args, named)?;
+    let path = match args.remove(0) {
+        Val::Str(s) => s,
+        _ => unreachable!(),
+    };
+    let contents = read_data_file(interpreter, &path)?;
+    data::parse_json(&path, &contents)
+}
+
+/// Reads a dimensionless number, such as an angle in radians.
+fn get_number<'a>(fn_name: &str, val: Val<'a>, arg_num: u32) -> Result<f64> {
+    match val {
+        Val::Num(x, 0) => Ok(x),
+        other => Err(Error::arg_type(fn_name, ValType::Num(0), other.get_type(), arg_num)),
+    }
+}
+
+/// Reads a number of any dimension.
+fn get_num_any<'a>(fn_name: &str, val: Val<'a>, arg_num: u32) -> Result<(f64, LenDim)> {
+    match val {
+        Val::Num(x, d) => Ok((x, d)),
+        other => Err(Error::arg_type(fn_name, ValType::Num(0), other.get_type(), arg_num)),
+    }
+}
+
+pub fn sin<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    let mut args = bind_required("sin", &["angle"], args, named)?;
+    let angle = get_number("sin", args.remove(0), 0)?;
+    Ok(Val::Num(angle.sin(), 0))
+}
+
+pub fn cos<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    let mut args = bind_required("cos", &["angle"], args, named)?;
+    let angle = get_number("cos", args.remove(0), 0)?;
+    Ok(Val::Num(angle.cos(), 0))
+}
+
+/// Returns the square root, which halves the dimension, so the root of an
+/// area is a length.
+pub fn sqrt<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                    args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    let mut args = bind_required("sqrt", &["x"], args, named)?;
+    let (x, d) = get_num_any("sqrt", args.remove(0), 0)?;
+    if d % 2 != 0 {
+        let mut fmt = Formatter::new();
+        fmt.print("Cannot take the square root of a '");
+        fmt.print(ValType::Num(d));
+        fmt.print("', because the result would not have a whole dimension.");
+        return Err(Error::value(fmt.into_string()))
+    }
+    if x < 0.0 {
+        let msg = format!("Cannot take the square root of the negative number {}.", x);
+        return Err(Error::value(msg))
+    }
+    Ok(Val::Num(x.sqrt(), d / 2))
+}
+
+pub fn abs<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    let mut args = bind_required("abs", &["x"], args, named)?;
+    let (x, d) = get_num_any("abs", args.remove(0), 0)?;
+    Ok(Val::Num(x.abs(), d))
+}
+
+/// Rounds to the nearest whole number. Lengths are rounded to whole points.
+pub fn round<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                     args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Val<'a>> {
+    let mut args = bind_required("round", &["x"], args, named)?;
+    let (x, d) = get_num_any("round", args.remove(0), 0)?;
+    Ok(Val::Num(x.round(), d))
+}
+
+/// Returns the smaller or larger of two numbers of the same dimension.
+fn min_or_max<'a>(fn_name: &str,
+                  args: Vec<Val<'a>>,
+                  named: NamedArgs<'a>,
+                  pick: fn(f64, f64) -> f64)
+                  -> Result<Val<'a>> {
+    let mut args = bind_required(fn_name, &["a", "b"], args, named)?;
+    let (a, d) = get_num_any(fn_name, args.remove(0), 0)?;
+    match args.remove(0) {
+        Val::Num(b, e) if e == d => Ok(Val::Num(pick(a, b), d)),
+        other => Err(Error::arg_type(fn_name, ValType::Num(d), other.get_type(), 1)),
+    }
+}
+
+pub fn min<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    min_or_max("min", args, named, f64::min)
+}
+
+pub fn max<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                   args: Vec<Val<'a>>,
+                   named: NamedArgs<'a>)
+                   -> Result<Val<'a>> {
+    min_or_max("max", args, named, f64::max)
+}
+
+/// Interpolates linearly between two numbers, coordinates, or colors, where
+/// `t = 0` gives the first value, and `t = 1` the second one.
+pub fn lerp<'i, 'a>(_interpreter: &mut ExprInterpreter<'i, 'a>,
+                    args: Vec<Val<'a>>,
+                    named: NamedArgs<'a>)
+                    -> Result<Val<'a>> {
+    let mut args = bind_required("lerp", &["a", "b", "t"], args, named)?;
+    let t = get_number("lerp", args.remove(2), 2)?;
+    let mix = |a: f64, b: f64| a + (b - a) * t;
+    match (args.remove(0), args.remove(0)) {
+        (Val::Num(a, d), Val::Num(b, e)) if d == e => Ok(Val::Num(mix(a, b), d)),
+        (Val::Coord(ax, ay, d), Val::Coord(bx, by, e)) if d == e => {
+            Ok(Val::Coord(mix(ax, bx), mix(ay, by), d))
+        }
+        (Val::Col(a), Val::Col(b)) => Ok(Val::Col(Color::new(mix(a.r, b.r), mix(a.g, b.g), mix(a.b, b.b)))),
+        (a, b) => {
+            let mut fmt = Formatter::new();
+            fmt.print("'lerp' needs two numbers, coords or colors of the same type \
+                       and dimension, but found '");
+            fmt.print(a.get_type());
+            fmt.print("' and '");
+            fmt.print(b.get_type());
+            fmt.print("'.");
+            Err(Error::value(fmt.into_string()))
+        }
+    }
+}
+
+/// Calls a builtin that does not depend on the environment.
+#[cfg(test)]
+fn call_builtin(builtin: for<'i, 'a> fn(&mut ExprInterpreter<'i, 'a>, Vec<Val<'a>>, NamedArgs<'a>) -> Result<Val<'a>>,
+                args: Vec<Val<'static>>)
+                -> Result<Val<'static>> {
+    let mut font_map = FontMap::new();
+    let env = runtime::Env::new();
+    let mut interpreter = ExprInterpreter {
+        font_map: &mut font_map,
+        env: &env,
+        base_dir: ::std::path::Path::new("."),
+    };
+    builtin(&mut interpreter, args, Vec::new())
+}
+
+#[test]
+fn sqrt_halves_the_dimension() {
+    match call_builtin(sqrt, vec![Val::Num(16.0, 2)]) {
+        Ok(Val::Num(x, 1)) => assert_eq!(x, 4.0),
+        _ => panic!("Expected a length."),
+    }
+    match call_builtin(sqrt, vec![Val::Num(9.0, 0)]) {
+        Ok(Val::Num(x, 0)) => assert_eq!(x, 3.0),
+        _ => panic!("Expected a number."),
+    }
+    assert!(call_builtin(sqrt, vec![Val::Num(4.0, 1)]).is_err());
+    assert!(call_builtin(sqrt, vec![Val::Num(-4.0, 0)]).is_err());
+}
+
+#[test]
+fn min_and_max_require_the_same_dimension() {
+    match call_builtin(min, vec![Val::Num(3.0, 1), Val::Num(2.0, 1)]) {
+        Ok(Val::Num(x, 1)) => assert_eq!(x, 2.0),
+        _ => panic!("Expected a length."),
+    }
+    match call_builtin(max, vec![Val::Num(3.0, 1), Val::Num(2.0, 1)]) {
+        Ok(Val::Num(x, 1)) => assert_eq!(x, 3.0),
+        _ => panic!("Expected a length."),
+    }
+    assert!(call_builtin(min, vec![Val::Num(3.0, 1), Val::Num(2.0, 0)]).is_err());
+    assert!(call_builtin(max, vec![Val::Num(3.0, 0), Val::Num(2.0, 2)]).is_err());
+}
+
+#[test]
+fn lerp_interpolates_values_of_the_same_type() {
+    match call_builtin(lerp, vec![Val::Num(2.0, 1), Val::Num(6.0, 1), Val::Num(0.25, 0)]) {
+        Ok(Val::Num(x, 1)) => assert_eq!(x, 3.0),
+        _ => panic!("Expected a length."),
+    }
+    match call_builtin(lerp, vec![Val::Coord(0.0, 4.0, 1), Val::Coord(8.0, 0.0, 1), Val::Num(0.5, 0)]) {
+        Ok(Val::Coord(x, y, 1)) => assert_eq!((x, y), (4.0, 2.0)),
+        _ => panic!("Expected a coord."),
+    }
+    let (black, white) = (Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0));
+    match call_builtin(lerp, vec![Val::Col(black), Val::Col(white), Val::Num(0.5, 0)]) {
+        Ok(Val::Col(c)) => assert_eq!((c.r, c.g, c.b), (0.5, 0.5, 0.5)),
+        _ => panic!("Expected a color."),
+    }
+    let strings = vec![Val::Str("x".to_string()), Val::Str("y".to_string()), Val::Num(0.5, 0)];
+    match call_builtin(lerp, strings) {
+        Err(Error::Value(..)) => {}
+        _ => panic!("Expected a value error."),
+    }
+    match call_builtin(lerp, vec![Val::Num(1.0, 1), Val::Num(2.0, 0), Val::Num(0.5, 0)]) {
+        Err(Error::Value(..)) => {}
+        _ => panic!("Expected a value error."),
+    }
+}
@@ -7,6 +7,7 @@
 
 use freetype;
 use freetype::freetype_sys::FT_Face;
+use std::ffi::CString;
 use std::mem;
 use std::os::raw::{c_char, c_int, c_ulong};
 use std::path::Path;
@@ -20,6 +21,9 @@ pub enum cairo_t {}
 #[allow(non_camel_case_types)]
 enum cairo_font_face_t {}
 
+#[allow(non_camel_case_types)]
+enum cairo_font_options_t {}
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone)]
@@ -29,6 +33,17 @@ pub struct cairo_glyph_t {
     y: f64,
 }
 
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone)]
+pub struct cairo_text_cluster_t {
+    num_bytes: c_int,
+    num_glyphs: c_int,
+}
+
+#[allow(non_camel_case_types)]
+type cairo_text_cluster_flags_t = c_int;
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone)]
@@ -49,6 +64,7 @@ extern {
     fn cairo_set_line_width(cr: *mut cairo_t, width: f64);
     fn cairo_move_to(cr: *mut cairo_t, x: f64, y: f64);
     fn cairo_line_to(cr: *mut cairo_t, x: f64, y: f64);
+    fn cairo_curve_to(cr: *mut cairo_t, x1: f64, y1: f64, x2: f64, y2: f64, x3: f64, y3: f64);
     fn cairo_close_path(cr: *mut cairo_t);
     fn cairo_rectangle(cr: *mut cairo_t, x: f64, y: f64, w: f64, h: f64);
     fn cairo_stroke(cr: *mut cairo_t);
@@ -60,11 +76,21 @@ extern {
     fn cairo_font_face_destroy(face: *mut cairo_font_face_t);
     fn cairo_set_font_face(cr: *mut cairo_t, font: *mut cairo_font_face_t);
     fn cairo_set_font_size(cr: *mut cairo_t, size: f64);
+    fn cairo_font_options_create() -> *mut cairo_font_options_t;
+    fn cairo_font_options_destroy(options: *mut cairo_font_options_t);
+    fn cairo_font_options_set_variations(options: *mut cairo_font_options_t, variations: *const c_char);
+    fn cairo_set_font_options(cr: *mut cairo_t, options: *const cairo_font_options_t);
     fn cairo_show_glyphs(cr: *mut cairo_t, glyphs: *const cairo_glyph_t, num_glyphs: c_int);
+    fn cairo_show_text_glyphs(cr: *mut cairo_t,
+                              utf8: *const c_char, utf8_len: c_int,
+                              glyphs: *const cairo_glyph_t, num_glyphs: c_int,
+                              clusters: *const cairo_text_cluster_t, num_clusters: c_int,
+                              cluster_flags: cairo_text_cluster_flags_t);
     fn cairo_get_matrix(cr: *mut cairo_t, matrix: *mut cairo_matrix_t);
     fn cairo_set_matrix(cr: *mut cairo_t, matrix: *const cairo_matrix_t);
     fn cairo_translate(cr: *mut cairo_t, tx: f64, ty: f64);
     fn cairo_scale(cr: *mut cairo_t, sx: f64, sy: f64);
+    fn cairo_rotate(cr: *mut cairo_t, angle: f64);
 }
 
 pub struct Surface {
@@ -85,12 +111,15 @@ pub struct FontFace {
 #[derive(Copy, Clone)]
 pub struct Glyph(cairo_glyph_t);
 
+/// Maps a number of bytes of text to a number of glyphs.
+#[derive(Copy, Clone)]
+pub struct TextCluster(cairo_text_cluster_t);
+
 #[derive(Copy, Clone)]
 pub struct Matrix(cairo_matrix_t);
 
 impl Surface {
     pub fn new(fname: &Path, width: f64, height: f64) -> Surface {
-        use std::ffi::CString;
         let fname_cstr = CString::new(fname.to_str().unwrap()).unwrap();
         Surface {
             ptr: unsafe { cairo_pdf_surface_create(fname_cstr.as_ptr(), width, height) }
@@ -133,6 +162,10 @@ impl Cairo {
         unsafe { cairo_line_to(self.ptr, x, y) }
     }
 
+    pub fn curve_to(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, x3: f64, y3: f64) {
+        unsafe { cairo_curve_to(self.ptr, x1, y1, x2, y2, x3, y3) }
+    }
+
     pub fn close_path(&mut self) {
         unsafe { cairo_close_path(self.ptr) }
     }
@@ -161,6 +194,19 @@ impl Cairo {
         unsafe { cairo_set_font_size(self.ptr, size) }
     }
 
+    /// Sets the instance of a variable font to render, in the format
+    /// "wght=650,opsz=24".
+    pub fn set_font_variations(&mut self, variations: &str) {
+        let variations_cstr = CString::new(variations).unwrap();
+        unsafe {
+            // Cairo copies the options, so we can destroy them right away.
+            let options = cairo_font_options_create();
+            cairo_font_options_set_variations(options, variations_cstr.as_ptr());
+            cairo_set_font_options(self.ptr, options);
+            cairo_font_options_destroy(options);
+        }
+    }
+
     pub fn show_glyphs(&mut self, glyphs: &[Glyph]) {
         unsafe {
             let cgs: *const cairo_glyph_t = mem::transmute(glyphs.as_ptr());
@@ -168,6 +214,26 @@ impl Cairo {
         }
     }
 
+    /// Shows glyphs together with the text they represent, so the text can be
+    /// selected and copied in the output.
+    ///
+    /// The clusters map the text to the glyphs, in order. Together they must
+    /// cover all of the text and all of the glyphs.
+    pub fn show_text_glyphs(&mut self, text: &str, glyphs: &[Glyph], clusters: &[TextCluster]) {
+        unsafe {
+            let chars: *const c_char = mem::transmute(text.as_bytes().as_ptr());
+            let cgs: *const cairo_glyph_t = mem::transmute(glyphs.as_ptr());
+            let ccs: *const cairo_text_cluster_t = mem::transmute(clusters.as_ptr());
+            // The flags indicate whether clusters are in backward order; ours
+            // are always forward.
+            cairo_show_text_glyphs(self.ptr,
+                                   chars, text.len() as c_int,
+                                   cgs, glyphs.len() as c_int,
+                                   ccs, clusters.len() as c_int,
+                                   0);
+        }
+    }
+
     pub fn get_matrix(&self) -> Matrix {
         unsafe {
             let mut mtx: cairo_matrix_t = mem::uninitialized();
@@ -185,6 +251,12 @@ impl Cairo {
         unsafe { cairo_translate(self.ptr, tx, ty) }
     }
 
+    /// Rotates the user-space axes by `angle` radians, clockwise as the
+    /// y-axis points down.
+    pub fn rotate(&mut self, angle: f64) {
+        unsafe { cairo_rotate(self.ptr, angle) }
+    }
+
     pub fn scale(&mut self, sx: f64, sy: f64) {
         unsafe { cairo_scale(self.ptr, sx, sy) }
     }
@@ -226,8 +298,43 @@ impl Glyph {
         Glyph(cg)
     }
 
+    pub fn index(&self) -> u64 {
+        self.0.index as u64
+    }
+
+    pub fn x(&self) -> f64 {
+        self.0.x
+    }
+
+    pub fn y(&self) -> f64 {
+        self.0.y
+    }
+
     /// Make a copy of the glyph, offset by the specified amount.
     pub fn offset(&self, dx: f64, dy: f64) -> Glyph {
         Glyph::new(self.0.index as u64, self.0.x + dx, self.0.y + dy)
     }
 }
+
+impl TextCluster {
+    pub fn new(num_bytes: usize, num_glyphs: usize) -> TextCluster {
+        let cc = cairo_text_cluster_t {
+            num_bytes: num_bytes as c_int,
+            num_glyphs: num_glyphs as c_int,
+        };
+        TextCluster(cc)
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.0.num_bytes as usize
+    }
+
+    pub fn num_glyphs(&self) -> usize {
+        self.0.num_glyphs as usize
+    }
+
+    /// Make a copy of the cluster that covers more bytes of text.
+    pub fn extend_bytes(&self, extra_bytes: usize) -> TextCluster {
+        TextCluster::new(self.num_bytes() + extra_bytes, self.num_glyphs())
+    }
+}
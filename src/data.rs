@@ -0,0 +1,337 @@
+// Pris -- A language for designing slides
+// Copyright 2017 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module parses CSV and JSON data files into Pris values.
+//!
+//! Numbers become dimensionless numbers, text becomes strings, arrays and rows
+//! become lists, and JSON objects become records.
+
+use error::{Error, Result};
+use runtime;
+use runtime::Val;
+
+/// Returns the line number (starting at 1) of the byte at the given index.
+fn line_at(source: &str, index: usize) -> usize {
+    1 + source.as_bytes()[..index].iter().filter(|&&b| b == b'\n').count()
+}
+
+fn is_digit(byte: u8) -> bool {
+    b'0' <= byte && byte <= b'9'
+}
+
+/// Returns a number if the text is one, or a string otherwise.
+fn parse_field<'a>(field: String) -> Val<'a> {
+    match field.trim().parse::<f64>() {
+        Ok(x) if x.is_finite() => Val::Num(x, 0),
+        _ => Val::Str(field),
+    }
+}
+
+/// Parses CSV as defined in RFC 4180 into a list of rows. Every row is a list
+/// of fields, where fields that contain a number become numbers.
+pub fn parse_csv<'a>(path: &str, source: &str) -> Result<Val<'a>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut chars = source.char_indices().peekable();
+
+    loop {
+        match chars.next() {
+            Some((start, '"')) if field.is_empty() => {
+                // A quoted field extends up to the next lone quote, and it can
+                // contain separators, newlines, and doubled quotes.
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => match chars.peek() {
+                            Some(&(_, '"')) => { chars.next(); field.push('"'); }
+                            Some(&(_, ',')) | Some(&(_, '\r')) | Some(&(_, '\n')) | None => break,
+                            Some(&(i, _)) => {
+                                let msg = "Expected a separator after the closing quote.".into();
+                                return Err(Error::data(path, line_at(source, i), msg))
+                            }
+                        },
+                        Some((_, c)) => field.push(c),
+                        None => {
+                            let msg = "Unterminated quoted field.".into();
+                            return Err(Error::data(path, line_at(source, start), msg))
+                        }
+                    }
+                }
+                // Keep quoted fields as strings, even if they look like numbers.
+                row.push(Val::Str(field));
+                field = String::new();
+                match chars.next() {
+                    Some((_, ',')) => continue,
+                    Some((_, '\r')) => { chars.next(); }
+                    _ => {}
+                }
+                rows.push(Val::List(row));
+                row = Vec::new();
+                if chars.peek().is_none() {
+                    break
+                }
+            }
+            Some((_, ',')) => {
+                row.push(parse_field(field));
+                field = String::new();
+            }
+            Some((_, '\r')) | Some((_, '\n')) | None => {
+                // Skip the line feed of a CRLF line ending.
+                if let Some(&(_, '\n')) = chars.peek() {
+                    chars.next();
+                }
+                // An empty line is not a row with one empty field.
+                if !(row.is_empty() && field.is_empty()) {
+                    row.push(parse_field(field));
+                    rows.push(Val::List(row));
+                }
+                field = String::new();
+                row = Vec::new();
+                if chars.peek().is_none() {
+                    break
+                }
+            }
+            Some((_, c)) => field.push(c),
+        }
+    }
+
+    Ok(Val::List(rows))
+}
+
+/// Parses a JSON document into a Pris value.
+pub fn parse_json<'a>(path: &str, source: &str) -> Result<Val<'a>> {
+    let mut parser = JsonParser {
+        path: path,
+        source: source,
+        pos: 0,
+    };
+    let val = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos < source.len() {
+        return Err(parser.error("Expected the end of the file after the value."))
+    }
+    Ok(val)
+}
+
+struct JsonParser<'s> {
+    path: &'s str,
+    source: &'s str,
+    pos: usize,
+}
+
+impl<'s> JsonParser<'s> {
+    fn error(&self, message: &str) -> Error {
+        Error::data(self.path, line_at(self.source, self.pos), message.into())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    /// Consumes the given byte after optional whitespace, if it is there.
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8, message: &str) -> Result<()> {
+        if self.eat(byte) { Ok(()) } else { Err(self.error(message)) }
+    }
+
+    fn parse_value<'a>(&mut self) -> Result<Val<'a>> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'[') => self.parse_array(),
+            Some(b'{') => self.parse_object(),
+            Some(b'"') => Ok(Val::Str(self.parse_string()?)),
+            Some(b) if b == b'-' || is_digit(b) => self.parse_number(),
+            // Pris has no booleans; settings like 'kerning' take strings, so
+            // booleans become strings too.
+            Some(b't') if self.source[self.pos..].starts_with("true") => {
+                self.pos += 4;
+                Ok(Val::Str("true".into()))
+            }
+            Some(b'f') if self.source[self.pos..].starts_with("false") => {
+                self.pos += 5;
+                Ok(Val::Str("false".into()))
+            }
+            Some(b'n') if self.source[self.pos..].starts_with("null") => {
+                Err(self.error("'null' is not supported, because Pris has no value for it."))
+            }
+            None => Err(self.error("Expected a value, but found the end of the file.")),
+            Some(..) => Err(self.error("Expected a value.")),
+        }
+    }
+
+    fn parse_array<'a>(&mut self) -> Result<Val<'a>> {
+        self.pos += 1;
+        let mut elements = Vec::new();
+        if self.eat(b']') {
+            return Ok(Val::List(elements))
+        }
+        loop {
+            elements.push(self.parse_value()?);
+            if self.eat(b']') {
+                return Ok(Val::List(elements))
+            }
+            self.expect(b',', "Expected ',' or ']' after an array element.")?;
+        }
+    }
+
+    fn parse_object<'a>(&mut self) -> Result<Val<'a>> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        if self.eat(b'}') {
+            return Ok(Val::Record(fields))
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("Expected a string as object key."))
+            }
+            let key = self.parse_string()?;
+            self.expect(b':', "Expected ':' after an object key.")?;
+            let value = self.parse_value()?;
+            runtime::set_field(&mut fields, key, value);
+            if self.eat(b'}') {
+                return Ok(Val::Record(fields))
+            }
+            self.expect(b',', "Expected ',' or '}' after an object value.")?;
+        }
+    }
+
+    fn parse_number<'a>(&mut self) -> Result<Val<'a>> {
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            match b {
+                b'-' | b'+' | b'.' | b'e' | b'E' => self.pos += 1,
+                _ if is_digit(b) => self.pos += 1,
+                _ => break,
+            }
+        }
+        match self.source[start..self.pos].parse::<f64>() {
+            Ok(x) => Ok(Val::Num(x, 0)),
+            Err(..) => {
+                self.pos = start;
+                Err(self.error("Invalid number."))
+            }
+        }
+    }
+
+    /// Parses four hexadecimal digits of a '\u' escape.
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let digit = match self.peek().and_then(|b| (b as char).to_digit(16)) {
+                Some(d) => d,
+                None => return Err(self.error("Expected four hexadecimal digits after '\\u'.")),
+            };
+            n = n * 16 + digit;
+            self.pos += 1;
+        }
+        Ok(n)
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.pos += 1;
+        let mut result = String::new();
+        loop {
+            let c = match self.source[self.pos..].chars().next() {
+                Some(c) => c,
+                None => return Err(self.error("Unterminated string.")),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(result),
+                '\n' => return Err(self.error("Unterminated string.")),
+                '\\' => {
+                    let escape = self.peek();
+                    self.pos += 1;
+                    match escape {
+                        Some(b'"') => result.push('"'),
+                        Some(b'\\') => result.push('\\'),
+                        Some(b'/') => result.push('/'),
+                        Some(b'b') => result.push('\u{8}'),
+                        Some(b'f') => result.push('\u{c}'),
+                        Some(b'n') => result.push('\n'),
+                        Some(b'r') => result.push('\r'),
+                        Some(b't') => result.push('\t'),
+                        Some(b'u') => {
+                            let mut code = self.parse_hex4()?;
+                            // Characters outside of the basic multilingual
+                            // plane are escaped as a surrogate pair.
+                            if code >= 0xd800 && code < 0xdc00 && self.source[self.pos..].starts_with("\\u") {
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            match ::std::char::from_u32(code) {
+                                Some(ch) => result.push(ch),
+                                None => return Err(self.error("Invalid '\\u' escape.")),
+                            }
+                        }
+                        _ => return Err(self.error("Invalid escape sequence.")),
+                    }
+                }
+                _ => result.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn show(val: &Val) -> String {
+    match *val {
+        Val::Num(x, 0) => format!("{}", x),
+        Val::Str(ref s) => format!("{:?}", s),
+        Val::List(ref vs) => {
+            let inner: Vec<String> = vs.iter().map(show).collect();
+            format!("[{}]", inner.join(", "))
+        }
+        Val::Record(ref fields) => {
+            let inner: Vec<String> = fields.iter().map(|&(ref k, ref v)| format!("{} = {}", k, show(v))).collect();
+            format!("{{{}}}", inner.join(", "))
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_csv_handles_quotes_and_numbers() {
+    let source = "name,time\r\n\"Parse, lex\",1.25\n\"say \"\"hi\"\"\", 3\n\n";
+    let val = parse_csv("a.csv", source).unwrap();
+    assert_eq!(show(&val), r#"[["name", "time"], ["Parse, lex", 1.25], ["say \"hi\"", 3]]"#);
+}
+
+#[test]
+fn parse_json_converts_values() {
+    let source = "{\"a\": [1, -2.5e1, \"x\\u00e9\"], \"b\": true}";
+    let val = parse_json("a.json", source).unwrap();
+    assert_eq!(show(&val), r#"{a = [1, -25, "xé"], b = "true"}"#);
+}
+
+#[test]
+fn parse_json_reports_line_of_error() {
+    match parse_json("a.json", "[1,\n 2\n 3]") {
+        Err(Error::Data(ref e)) => assert_eq!(e.line, 3),
+        _ => panic!("Expected a data error."),
+    }
+}
@@ -6,9 +6,10 @@
 // of the License is available in the root of the repository.
 
 use ast::Idents;
-use cairo::{Cairo, FontFace};
-use elements::{Color, Element, PlacedElement, Vec2};
+use cairo::Cairo;
+use elements::{Color, Element, PathCommand, PlacedElement, Vec2};
 use runtime::{FontMap, Frame};
+use variations;
 
 fn draw_background(cr: &mut Cairo, color: Color) {
     // TODO: Do not hard-code the canvas dimensions.
@@ -33,6 +34,18 @@ fn draw_polygon(cr: &mut Cairo, vertices: &[Vec2], close: bool) {
     }
 }
 
+/// Draw the segments of a path, but don't stroke or fill it yet.
+fn draw_path(cr: &mut Cairo, commands: &[PathCommand]) {
+    for command in commands {
+        match *command {
+            PathCommand::MoveTo(v) => cr.move_to(v.x, v.y),
+            PathCommand::LineTo(v) => cr.line_to(v.x, v.y),
+            PathCommand::CurveTo(c1, c2, v) => cr.curve_to(c1.x, c1.y, c2.x, c2.y, v.x, v.y),
+            PathCommand::Close => cr.close_path(),
+        }
+    }
+}
+
 fn draw_element(fm: &mut FontMap, cr: &mut Cairo, pe: &PlacedElement) {
     match pe.element {
         Element::StrokePolygon(ref polygon) => {
@@ -61,6 +74,31 @@ fn draw_element(fm: &mut FontMap, cr: &mut Cairo, pe: &PlacedElement) {
             cr.set_matrix(&matrix);
         }
 
+        Element::FillPath(ref path) => {
+            let matrix = cr.get_matrix();
+            cr.translate(pe.position.x, pe.position.y);
+
+            draw_path(cr, &path.commands);
+
+            cr.set_source_rgb(path.color.r, path.color.g, path.color.b);
+            cr.fill();
+
+            cr.set_matrix(&matrix);
+        }
+
+        Element::StrokePath(ref path) => {
+            let matrix = cr.get_matrix();
+            cr.translate(pe.position.x, pe.position.y);
+
+            draw_path(cr, &path.commands);
+
+            cr.set_source_rgb(path.color.r, path.color.g, path.color.b);
+            cr.set_line_width(path.line_width);
+            cr.stroke();
+
+            cr.set_matrix(&matrix);
+        }
+
         Element::Text(ref text) => {
             // Cairo uses absolute positions for glyphs, so we need to add
             // the final positions to the glyph locations.
@@ -70,17 +108,18 @@ fn draw_element(fm: &mut FontMap, cr: &mut Cairo, pe: &PlacedElement) {
                                             .map(|g| g.offset(pe.position.x, pe.position.y))
                                             .collect();
             // If we were able to shape the text, then the FT font must
-            // exist still. TODO: Would it be better to just embed a
-            // reference in the Text element instead of doing the lookup
-            // twice?
-            let ft_face = fm.get(&text.font_family, &text.font_style).unwrap();
-            let cr_face = FontFace::from_ft_face(ft_face.clone());
-            cr.set_font_face(&cr_face);
+            // exist still. The font map owns the Cairo font face, so it
+            // outlives the Cairo context that references it.
+            let cr_face = fm.get_cairo_face(&text.font).unwrap();
+            cr.set_font_face(cr_face);
             cr.set_font_size(text.font_size);
+            cr.set_font_variations(&variations::to_settings_string(&text.font.variations));
             cr.set_source_rgb(text.color.r, text.color.g, text.color.b);
-            cr.show_glyphs(&glyphs_offset);
-            // TODO: The cr_font should outlive the Cairo, because Cairo
-            // might internally reference the font still. How to model this?
+            if text.text.is_empty() {
+                cr.show_glyphs(&glyphs_offset);
+            } else {
+                cr.show_text_glyphs(&text.text, &glyphs_offset, &text.clusters);
+            }
         }
 
         Element::Scaled(ref elements, scale) => {
@@ -94,6 +133,17 @@ fn draw_element(fm: &mut FontMap, cr: &mut Cairo, pe: &PlacedElement) {
             cr.set_matrix(&matrix);
         }
 
+        Element::Rotated(ref elements, angle) => {
+            // Store the current transform so we can restore it later.
+            let matrix = cr.get_matrix();
+            cr.translate(pe.position.x, pe.position.y);
+            cr.rotate(angle);
+            for inner_pe in elements {
+                draw_element(fm, cr, inner_pe);
+            }
+            cr.set_matrix(&matrix);
+        }
+
         Element::Svg(ref svg) => {
             // Store the current transform so we can restore it later.
             let matrix = cr.get_matrix();
@@ -25,10 +25,14 @@ pub struct Vec2 {
 #[derive(Clone)]
 pub enum Element {
     FillPolygon(FillPolygon),
+    FillPath(FillPath),
     StrokePolygon(StrokePolygon),
+    StrokePath(StrokePath),
     Text(Text),
     Svg(Svg),
     Scaled(Vec<PlacedElement>, f64),
+    /// Elements rotated clockwise by an angle in radians around the position.
+    Rotated(Vec<PlacedElement>, f64),
 }
 
 #[derive(Clone)]
@@ -37,6 +41,22 @@ pub struct FillPolygon {
     pub vertices: Vec<Vec2>,
 }
 
+/// A filled shape that can have curved edges, such as a glyph outline.
+#[derive(Clone)]
+pub struct FillPath {
+    pub color: Color,
+    pub commands: Vec<PathCommand>,
+}
+
+#[derive(Copy, Clone)]
+pub enum PathCommand {
+    MoveTo(Vec2),
+    LineTo(Vec2),
+    /// A cubic Bézier curve with two control points and an end point.
+    CurveTo(Vec2, Vec2, Vec2),
+    Close,
+}
+
 #[derive(Clone)]
 pub struct StrokePolygon {
     pub color: Color,
@@ -45,6 +65,14 @@ pub struct StrokePolygon {
     pub vertices: Vec<Vec2>,
 }
 
+/// The outline of a shape that can have curved edges.
+#[derive(Clone)]
+pub struct StrokePath {
+    pub color: Color,
+    pub line_width: f64,
+    pub commands: Vec<PathCommand>,
+}
+
 // TODO: What color space is this? A linear RGB space would be nice.
 #[derive(Copy, Clone)]
 pub struct Color {
@@ -56,10 +84,45 @@ pub struct Color {
 #[derive(Clone)]
 pub struct Text {
     pub color: Color,
-    pub font_family: String,
-    pub font_style: String,
+    pub font: FontSpec,
     pub font_size: f64,
     pub glyphs: Vec<cairo::Glyph>,
+    /// The text that the glyphs represent, so it can be selected in the output.
+    pub text: String,
+    /// Mapping from the text to the glyphs.
+    pub clusters: Vec<cairo::TextCluster>,
+}
+
+/// Identifies a font face by the properties that Fontconfig selects on.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FontSpec {
+    pub family: String,
+    /// The weight on the OpenType scale, from 100 (thin) to 900 (black).
+    pub weight: u32,
+    pub slant: Slant,
+    /// The width as a percentage of the normal width, from 50
+    /// (ultra-condensed) to 200 (ultra-expanded).
+    pub stretch: u32,
+    /// Coordinates on the axes of a variable font. Axes that are not listed
+    /// keep their default value.
+    pub variations: Vec<Variation>,
+}
+
+/// A coordinate on a variation axis of a variable font.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Variation {
+    /// The OpenType axis tag, such as 'wght', the first character in the
+    /// most significant byte.
+    pub tag: u32,
+    /// The value in 16.16 fixed point, so the spec can be hashed.
+    pub value: i32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Slant {
+    Roman,
+    Italic,
+    Oblique,
 }
 
 impl Vec2 {
@@ -89,6 +152,17 @@ impl ops::Add<Vec2> for Vec2 {
     }
 }
 
+impl ops::Sub<Vec2> for Vec2 {
+    type Output = Vec2;
+
+    fn sub(self, rhs: Vec2) -> Vec2 {
+        Vec2 {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+        }
+    }
+}
+
 impl ops::Mul<f64> for Vec2 {
     type Output = Vec2;
 
@@ -100,6 +174,19 @@ impl ops::Mul<f64> for Vec2 {
     }
 }
 
+impl FontSpec {
+    /// Returns a copy of the spec with a different family.
+    pub fn with_family(&self, family: &str) -> FontSpec {
+        FontSpec {
+            family: family.to_string(),
+            weight: self.weight,
+            slant: self.slant,
+            stretch: self.stretch,
+            variations: self.variations.clone(),
+        }
+    }
+}
+
 impl Color {
     pub fn new(r: f64, g: f64, b: f64) -> Color {
         Color { r: r, g: g, b: b }
@@ -8,6 +8,7 @@
 use std::result;
 
 use ast::Idents;
+use elements::FontSpec;
 use pretty::Formatter;
 use types::ValType;
 
@@ -22,14 +23,34 @@ pub type Result<T> = result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     Arity(ArityError),
+    Data(DataError),
     MissingFile(MissingFileError),
     MissingFont(MissingFontError),
     Parse(ParseError),
     Type(TypeError),
     Value(ValueError),
+    /// A warning that is treated as an error, because of `--strict`.
+    Warning(Warning),
     Other(String),
 }
 
+/// A problem that does not prevent evaluation, but which is likely a mistake.
+#[derive(Debug)]
+pub enum Warning {
+    FontFallback(FontFallbackWarning),
+    FontMismatch(FontMismatchWarning),
+    Deprecated(DeprecatedWarning),
+}
+
+/// An error in a data file loaded by 'read_csv' or 'read_json'.
+#[derive(Debug)]
+pub struct DataError {
+    path: String,
+    /// The line that contains the error, starting at 1.
+    pub line: usize,
+    message: String,
+}
+
 #[derive(Debug)]
 pub struct MissingFileError {
     path: String,
@@ -37,8 +58,7 @@ pub struct MissingFileError {
 
 #[derive(Debug)]
 pub struct MissingFontError {
-    family: String,
-    style: String,
+    font: FontSpec,
 }
 
 #[derive(Debug)]
@@ -73,6 +93,27 @@ pub struct ValueError {
     message: String,
 }
 
+#[derive(Debug)]
+pub struct FontFallbackWarning {
+    family: String,
+    /// The characters that the font lacks, and the fallback font used for
+    /// each, if one could be found.
+    fallbacks: Vec<(char, Option<String>)>,
+}
+
+#[derive(Debug)]
+pub struct FontMismatchWarning {
+    requested: FontSpec,
+    loaded: FontSpec,
+}
+
+#[derive(Debug)]
+pub struct DeprecatedWarning {
+    var: String,
+    /// The assignments that have the same effect.
+    replacement: String,
+}
+
 impl Error {
     pub fn arity(fn_name: &str, expected: u32, actual: u32) -> Error {
         let mut f = Formatter::new();
@@ -93,6 +134,22 @@ impl Error {
         Error::Arity(arity_error)
     }
 
+    /// An arity error for a call that lacks the argument for `param`.
+    pub fn missing_arg(fn_name: &str, param: &str, expected: u32, actual: u32) -> Error {
+        let mut f = Formatter::new();
+        f.print("'");
+        f.print(fn_name);
+        f.print("' requires the argument '");
+        f.print(param);
+        f.print("', but it was not given.");
+        let arity_error = ArityError {
+            expected: expected,
+            actual: actual,
+            message: f.into_string(),
+        };
+        Error::Arity(arity_error)
+    }
+
     pub fn binop_type(op_name: &str,
                       expected: ValType,
                       actual_lhs: ValType,
@@ -179,10 +236,18 @@ impl Error {
         Error::Value(err)
     }
 
-    pub fn missing_font(family: String, style: String) -> Error {
+    pub fn data(path: &str, line: usize, message: String) -> Error {
+        let err = DataError {
+            path: path.into(),
+            line: line,
+            message: message,
+        };
+        Error::Data(err)
+    }
+
+    pub fn missing_font(font: &FontSpec) -> Error {
         let err = MissingFontError {
-            family: family,
-            style: style,
+            font: font.clone(),
         };
         Error::MissingFont(err)
     }
@@ -208,12 +273,90 @@ impl Error {
         print!("\x1b[31;1mError: \x1b[0m");
         match *self {
             Error::Arity(ref ae) => println!("{}\n", ae.message),
+            Error::Data(ref de) => println!("In '{}' at line {}: {}\n", de.path, de.line, de.message),
             Error::MissingFile(ref mf) => println!("The file '{}' does not exist.\n", mf.path),
-            Error::MissingFont(ref mf) => println!("The font '{} {}' cannot be found.\n", mf.family, mf.style),
+            Error::MissingFont(ref mf) => {
+                let mut f = Formatter::new();
+                f.print("The font ");
+                f.print(&mf.font);
+                f.print(" cannot be found.");
+                println!("{}\n", f.into_string());
+            }
             Error::Other(ref ot) => println!("{}\n", ot),
             Error::Parse(ref pe) => println!("{}\n", pe.message),
             Error::Type(ref tye) => println!("{}\n", tye.message),
             Error::Value(ref ve) => println!("{}\n", ve.message),
+            Error::Warning(ref w) => println!("{}\n", w.message()),
         }
     }
 }
+
+impl Warning {
+    pub fn font_fallback(family: String, fallbacks: Vec<(char, Option<String>)>) -> Warning {
+        let warning = FontFallbackWarning {
+            family: family,
+            fallbacks: fallbacks,
+        };
+        Warning::FontFallback(warning)
+    }
+
+    pub fn font_mismatch(requested: FontSpec, loaded: FontSpec) -> Warning {
+        let warning = FontMismatchWarning {
+            requested: requested,
+            loaded: loaded,
+        };
+        Warning::FontMismatch(warning)
+    }
+
+    pub fn deprecated(var: &str, replacement: String) -> Warning {
+        let warning = DeprecatedWarning {
+            var: var.to_string(),
+            replacement: replacement,
+        };
+        Warning::Deprecated(warning)
+    }
+
+    pub fn message(&self) -> String {
+        let mut f = Formatter::new();
+        match *self {
+            Warning::FontFallback(ref ff) => {
+                f.print("The font '");
+                f.print(&ff.family[..]);
+                f.print("' lacks glyphs for some characters.");
+                for &(ch, ref fallback) in &ff.fallbacks {
+                    f.print("\n  '");
+                    f.print(&format!("{}' (U+{:04X}) ", ch, ch as u32)[..]);
+                    match *fallback {
+                        Some(ref family) => {
+                            f.print("is taken from '");
+                            f.print(&family[..]);
+                            f.print("'.");
+                        }
+                        None => f.print("is not in any installed font."),
+                    }
+                }
+            }
+            Warning::FontMismatch(ref fm) => {
+                f.print("Requested the font ");
+                f.print(&fm.requested);
+                f.print(", but the closest match is ");
+                f.print(&fm.loaded);
+                f.print(".");
+            }
+            Warning::Deprecated(ref dp) => {
+                f.print("'");
+                f.print(&dp.var[..]);
+                f.print("' is deprecated, use ");
+                f.print(&dp.replacement[..]);
+                f.print(" instead.");
+            }
+        }
+        f.into_string()
+    }
+
+    pub fn print(&self) {
+        // Print in yellow using ANSI escape codes.
+        print!("\x1b[33;1mWarning: \x1b[0m");
+        println!("{}\n", self.message());
+    }
+}
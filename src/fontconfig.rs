@@ -5,18 +5,23 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
+use std::cmp;
 use std::ffi::{CStr, CString, OsStr};
 use std::mem;
 use std::os::raw::{c_int, c_char, c_uchar};
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ptr;
 
+use elements::{FontSpec, Slant};
+
+enum FcCharSet {}
 enum FcConfig {}
 enum FcPattern {}
 
 type FcBool = c_int;
 type FcChar8 = c_uchar;
+type FcChar32 = u32;
 type FcMatchKind = c_int;
 type FcResult = c_int;
 
@@ -43,66 +48,365 @@ mod fc {
 
 #[link(name = "fontconfig")]
 extern {
-    fn FcNameParse(fname: *const FcChar8) -> *mut FcPattern;
+    fn FcPatternCreate() -> *mut FcPattern;
+    fn FcConfigAppFontAddDir(config: *mut FcConfig, dir: *const FcChar8) -> FcBool;
     fn FcConfigSubstitute(config: *mut FcConfig, pattern: *mut FcPattern, kind: FcMatchKind) -> FcBool;
     fn FcDefaultSubstitute(pattern: *mut FcPattern);
     fn FcFontMatch(config: *mut FcConfig, pattern: *mut FcPattern, result: *mut FcResult) -> *mut FcPattern;
+    fn FcPatternAddString(pattern: *mut FcPattern, object: *const c_char, s: *const FcChar8) -> FcBool;
+    fn FcPatternAddInteger(pattern: *mut FcPattern, object: *const c_char, i: c_int) -> FcBool;
     fn FcPatternGetString(pattern: *mut FcPattern, object: *const c_char, n: c_int, result: *mut *mut FcChar8) -> FcResult;
+    fn FcPatternGetInteger(pattern: *mut FcPattern, object: *const c_char, n: c_int, result: *mut c_int) -> FcResult;
+    fn FcPatternGetCharSet(pattern: *mut FcPattern, object: *const c_char, n: c_int, result: *mut *mut FcCharSet) -> FcResult;
+    fn FcPatternAddCharSet(pattern: *mut FcPattern, object: *const c_char, charset: *const FcCharSet) -> FcBool;
     fn FcPatternDestroy(pattern: *mut FcPattern);
+    fn FcCharSetCreate() -> *mut FcCharSet;
+    fn FcCharSetAddChar(charset: *mut FcCharSet, ucs4: FcChar32) -> FcBool;
+    fn FcCharSetHasChar(charset: *const FcCharSet, ucs4: FcChar32) -> FcBool;
+    fn FcCharSetDestroy(charset: *mut FcCharSet);
 }
 
-/// Given a Fontconfig query such as "Cantarell" or "Cantarell:bold", returns
-/// the absolute path to the corresponding font file, if it could be found.
-pub fn get_font_location(font_query: &str) -> Option<PathBuf> {
-    let mut result = None;
+// These are the FC_FAMILY, FC_WEIGHT, etc. constants in the C API.
+const FC_FAMILY: &'static [u8] = b"family\0";
+const FC_WEIGHT: &'static [u8] = b"weight\0";
+const FC_SLANT: &'static [u8] = b"slant\0";
+const FC_WIDTH: &'static [u8] = b"width\0";
+const FC_FILE: &'static [u8] = b"file\0";
+const FC_CHARSET: &'static [u8] = b"charset\0";
 
-    unsafe {
-        // This is the FC_FILE constant in the C API.
-        let fc_file = CStr::from_bytes_with_nul_unchecked(b"file\0");
+/// The font that Fontconfig selected for a query.
+pub struct FontMatch {
+    /// Absolute path to the font file.
+    pub path: PathBuf,
+    /// The properties of the matched font, which may differ from the query.
+    pub spec: FontSpec,
+    /// All family names of the font. There can be more than one, for instance
+    /// when the font has localized names.
+    pub families: Vec<String>,
+}
 
-        // Fontconfig insists on using a non-standard character type, but it
-        // only differs in signedness, which is arbitrary for characters anyway.
-        let query_cstr = CString::new(font_query).unwrap();
-        let query_char8: *const FcChar8 = mem::transmute(query_cstr.as_ptr());
-        let pattern = FcNameParse(query_char8);
+/// Correspondence between OpenType weights and Fontconfig weights. This is the
+/// same table that `FcWeightFromOpenType` in Fontconfig uses, but that function
+/// is not available in older versions.
+const WEIGHT_MAP: [(u32, c_int); 12] = [
+    (0, 0),
+    (100, 0),    // Thin
+    (200, 40),   // Extra light
+    (300, 50),   // Light
+    (350, 55),   // Demi light
+    (380, 75),   // Book
+    (400, 80),   // Regular
+    (500, 100),  // Medium
+    (600, 180),  // Semi bold
+    (700, 200),  // Bold
+    (800, 205),  // Extra bold
+    (900, 210),  // Black
+];
 
-        // The docs say that FcConfigSubstitute must be called, although it
-        // is unclear what its purpose is.
-        let config = ptr::null_mut();
-        assert!(0 != FcConfigSubstitute(config, pattern, fc::FcMatchPattern));
-
-        // The parsed pattern might not have some properties set, such as the
-        // weight or slant. FcDefaultSubstitute fills these in.
-        FcDefaultSubstitute(pattern);
-
-        // Note: it is important that the result is initialized to "match",
-        // because FcFontMatch does not overwrite it on success for older
-        // versions of Fontconfig (2.8 at least), apparently.
-        let mut match_result = fc::FcResultMatch;
-
-        let font_match = FcFontMatch(config, pattern, &mut match_result);
-
-        if match_result == fc::FcResultMatch {
-            // Retrieve the filename from the "match", if there was one. Doing
-            // this should always succeed, otherwise there is a programming
-            // error, or allocation failure.
-            let mut fname_ptr: *mut FcChar8 = ptr::null_mut();
-            let get_result = FcPatternGetString(font_match, fc_file.as_ptr(), 0, &mut fname_ptr);
-            assert_eq!(get_result, fc::FcResultMatch);
-
-            // Do the conversion dance: from *mut c_str to PathBuf. PathBuf owns
-            // its contents: we make a copy of Fontconfig's string, so we can
-            // free it afterwards. It is an extra copy, but it is far more
-            // convenient than the alternative. Also transmute Fontconfig's
-            // signed character strings once more.
-            let fname_cstr = CStr::from_ptr(mem::transmute(fname_ptr));
-            let fname_osstr = OsStr::from_bytes(fname_cstr.to_bytes());
-            result = Some(PathBuf::from(fname_osstr));
+/// Converts an OpenType weight (100-900) into a Fontconfig weight.
+fn weight_from_opentype(weight: u32) -> c_int {
+    for w in WEIGHT_MAP.windows(2) {
+        let (ot0, fc0) = w[0];
+        let (ot1, fc1) = w[1];
+        if weight <= ot1 {
+            let t = (weight - ot0) as f64 / (ot1 - ot0) as f64;
+            return (fc0 as f64 + t * (fc1 - fc0) as f64).round() as c_int
         }
+    }
+    WEIGHT_MAP[WEIGHT_MAP.len() - 1].1
+}
+
+/// Converts a Fontconfig weight into an OpenType weight (100-900).
+fn weight_to_opentype(weight: c_int) -> u32 {
+    // Skip the first entry, Fontconfig maps weights 0 through 100 to 0.
+    for w in WEIGHT_MAP[1..].windows(2) {
+        let (ot0, fc0) = w[0];
+        let (ot1, fc1) = w[1];
+        if weight <= fc1 {
+            let t = cmp::max(weight - fc0, 0) as f64 / (fc1 - fc0) as f64;
+            return (ot0 as f64 + t * (ot1 - ot0) as f64).round() as u32
+        }
+    }
+    WEIGHT_MAP[WEIGHT_MAP.len() - 1].0
+}
+
+#[test]
+fn weight_from_opentype_maps_named_weights() {
+    assert_eq!(weight_from_opentype(100), 0);
+    assert_eq!(weight_from_opentype(400), 80);
+    assert_eq!(weight_from_opentype(450), 90);
+    assert_eq!(weight_from_opentype(700), 200);
+    assert_eq!(weight_from_opentype(900), 210);
+}
+
+#[test]
+fn weight_to_opentype_inverts_weight_from_opentype() {
+    for &w in &[100, 200, 300, 400, 500, 600, 700, 800, 900] {
+        assert_eq!(weight_to_opentype(weight_from_opentype(w)), w);
+    }
+}
+
+/// Parses a style name such as "Bold Italic" into a weight and a slant.
+///
+/// This is how fonts used to be selected, through the now deprecated
+/// 'font_style' variable. Returns `None` for names that are not a weight,
+/// a slant, or a weight followed by a slant.
+pub fn parse_style_name(style: &str) -> Option<(u32, Slant)> {
+    // Ignore case, spaces and dashes, so "Semi-Bold" and "SemiBold" match.
+    let name: String = style.to_lowercase().chars().filter(|&c| c != ' ' && c != '-').collect();
+
+    let (weight_name, slant) = if name.ends_with("italic") {
+        (&name[..name.len() - 6], Slant::Italic)
+    } else if name.ends_with("oblique") {
+        (&name[..name.len() - 7], Slant::Oblique)
+    } else {
+        (&name[..], Slant::Roman)
+    };
+
+    let weight = match weight_name {
+        "thin" | "hairline" => 100,
+        "extralight" | "ultralight" => 200,
+        "light" => 300,
+        "" | "regular" | "normal" | "book" | "roman" => 400,
+        "medium" => 500,
+        "semibold" | "demibold" => 600,
+        "bold" => 700,
+        "extrabold" | "ultrabold" => 800,
+        "black" | "heavy" => 900,
+        _ => return None,
+    };
+
+    Some((weight, slant))
+}
+
+#[test]
+fn parse_style_name_handles_weight_and_slant() {
+    assert_eq!(parse_style_name("Regular"), Some((400, Slant::Roman)));
+    assert_eq!(parse_style_name("roman"), Some((400, Slant::Roman)));
+    assert_eq!(parse_style_name("Italic"), Some((400, Slant::Italic)));
+    assert_eq!(parse_style_name("Bold Italic"), Some((700, Slant::Italic)));
+    assert_eq!(parse_style_name("Semi-Bold"), Some((600, Slant::Roman)));
+    assert_eq!(parse_style_name("ExtraLight Oblique"), Some((200, Slant::Oblique)));
+    assert_eq!(parse_style_name("Condensed"), None);
+}
+
+fn slant_to_fc(slant: Slant) -> c_int {
+    match slant {
+        Slant::Roman => 0,
+        Slant::Italic => 100,
+        Slant::Oblique => 110,
+    }
+}
+
+fn slant_from_fc(slant: c_int) -> Slant {
+    match slant {
+        s if s < 50 => Slant::Roman,
+        s if s < 105 => Slant::Italic,
+        _ => Slant::Oblique,
+    }
+}
+
+/// Adds the fonts in a directory to the fonts that Fontconfig knows about.
+///
+/// Returns false if the directory could not be read.
+pub fn add_font_dir(dir: &Path) -> bool {
+    unsafe {
+        let dir_cstr = CString::new(dir.as_os_str().as_bytes()).unwrap();
+        let dir_char8: *const FcChar8 = mem::transmute(dir_cstr.as_ptr());
+
+        // A null config refers to the current configuration, which is the one
+        // that is used for matching as well.
+        let config = ptr::null_mut();
+        0 != FcConfigAppFontAddDir(config, dir_char8)
+    }
+}
 
-        FcPatternDestroy(font_match);
+/// Returns the font file that best matches the font spec, if there is any.
+pub fn get_font_location(spec: &FontSpec) -> Option<FontMatch> {
+    unsafe {
+        let pattern = make_pattern(spec);
+        let result = match_pattern(pattern, None);
         FcPatternDestroy(pattern);
+        result
     }
+}
+
+/// Returns a font that has a glyph for the given character.
+///
+/// The spec is used to prefer fonts that are similar to the font that lacks
+/// the glyph. Returns `None` if no installed font covers the character at all.
+pub fn get_font_location_for_char(spec: &FontSpec, ch: char) -> Option<FontMatch> {
+    unsafe {
+        // Add a character set with only the required character to the pattern.
+        // The character set ranks above the family when Fontconfig sorts
+        // fonts, so the best match will be a font that covers the character,
+        // if there is one.
+        let pattern = make_pattern(spec);
+        let charset = FcCharSetCreate();
+        assert!(0 != FcCharSetAddChar(charset, ch as FcChar32));
+        assert!(0 != FcPatternAddCharSet(pattern, fc_object(FC_CHARSET), charset));
+        FcCharSetDestroy(charset);
+
+        let result = match_pattern(pattern, Some(ch));
+        FcPatternDestroy(pattern);
+        result
+    }
+}
+
+unsafe fn fc_object(name: &'static [u8]) -> *const c_char {
+    CStr::from_bytes_with_nul_unchecked(name).as_ptr()
+}
+
+/// Builds a pattern for the font spec that must be freed by the caller.
+unsafe fn make_pattern(spec: &FontSpec) -> *mut FcPattern {
+    let pattern = FcPatternCreate();
+
+    // Fontconfig insists on using a non-standard character type, but it
+    // only differs in signedness, which is arbitrary for characters anyway.
+    let family_cstr = CString::new(&spec.family[..]).unwrap();
+    let family_char8: *const FcChar8 = mem::transmute(family_cstr.as_ptr());
+
+    // Adding values copies them into the pattern; these calls only fail on
+    // allocation failure.
+    assert!(0 != FcPatternAddString(pattern, fc_object(FC_FAMILY), family_char8));
+    assert!(0 != FcPatternAddInteger(pattern, fc_object(FC_WEIGHT), weight_from_opentype(spec.weight)));
+    assert!(0 != FcPatternAddInteger(pattern, fc_object(FC_SLANT), slant_to_fc(spec.slant)));
+    assert!(0 != FcPatternAddInteger(pattern, fc_object(FC_WIDTH), spec.stretch as c_int));
+
+    pattern
+}
+
+/// Reads a string property of a pattern, if it is present.
+unsafe fn get_string(pattern: *mut FcPattern, object: &'static [u8], n: c_int) -> Option<String> {
+    let mut str_ptr: *mut FcChar8 = ptr::null_mut();
+    if FcPatternGetString(pattern, fc_object(object), n, &mut str_ptr) != fc::FcResultMatch {
+        return None
+    }
+    let cstr = CStr::from_ptr(mem::transmute(str_ptr));
+    Some(cstr.to_string_lossy().into_owned())
+}
+
+/// Reads an integer property of a pattern, or returns the default if absent.
+unsafe fn get_integer(pattern: *mut FcPattern, object: &'static [u8], default: c_int) -> c_int {
+    let mut value = default;
+    FcPatternGetInteger(pattern, fc_object(object), 0, &mut value);
+    value
+}
+
+/// Finds the best font for a pattern.
+///
+/// If a character is specified, the match is only returned if the font
+/// contains a glyph for that character.
+unsafe fn match_pattern(pattern: *mut FcPattern, required_char: Option<char>) -> Option<FontMatch> {
+    let mut result = None;
+
+    // The docs say that FcConfigSubstitute must be called, although it
+    // is unclear what its purpose is.
+    let config = ptr::null_mut();
+    assert!(0 != FcConfigSubstitute(config, pattern, fc::FcMatchPattern));
+
+    // The pattern might not have some properties set, such as the pixel size.
+    // FcDefaultSubstitute fills these in.
+    FcDefaultSubstitute(pattern);
+
+    // Note: it is important that the result is initialized to "match",
+    // because FcFontMatch does not overwrite it on success for older
+    // versions of Fontconfig (2.8 at least), apparently.
+    let mut match_result = fc::FcResultMatch;
+
+    let font_match = FcFontMatch(config, pattern, &mut match_result);
+
+    // Fontconfig always returns its best match, even if that font does not
+    // cover the character that we asked for. So check the character set of
+    // the match too.
+    let covers_char = match required_char {
+        None => true,
+        Some(ch) if match_result == fc::FcResultMatch => {
+            let mut charset: *mut FcCharSet = ptr::null_mut();
+            let get_result = FcPatternGetCharSet(font_match, fc_object(FC_CHARSET), 0, &mut charset);
+            get_result == fc::FcResultMatch && 0 != FcCharSetHasChar(charset, ch as FcChar32)
+        }
+        Some(..) => false,
+    };
+
+    if match_result == fc::FcResultMatch && covers_char {
+        // Retrieve the filename from the "match", if there was one. Doing
+        // this should always succeed, otherwise there is a programming
+        // error, or allocation failure.
+        let mut fname_ptr: *mut FcChar8 = ptr::null_mut();
+        let get_result = FcPatternGetString(font_match, fc_object(FC_FILE), 0, &mut fname_ptr);
+        assert_eq!(get_result, fc::FcResultMatch);
+
+        // Do the conversion dance: from *mut c_str to PathBuf. PathBuf owns
+        // its contents: we make a copy of Fontconfig's string, so we can
+        // free it afterwards. It is an extra copy, but it is far more
+        // convenient than the alternative. Also transmute Fontconfig's
+        // signed character strings once more.
+        let fname_cstr = CStr::from_ptr(mem::transmute(fname_ptr));
+        let fname_osstr = OsStr::from_bytes(fname_cstr.to_bytes());
+        let path = PathBuf::from(fname_osstr);
+
+        let mut families = Vec::new();
+        while let Some(family) = get_string(font_match, FC_FAMILY, families.len() as c_int) {
+            families.push(family);
+        }
+
+        let spec = FontSpec {
+            family: families.first().cloned().unwrap_or(String::new()),
+            weight: weight_to_opentype(get_integer(font_match, FC_WEIGHT, 80)),
+            slant: slant_from_fc(get_integer(font_match, FC_SLANT, 0)),
+            stretch: get_integer(font_match, FC_WIDTH, 100) as u32,
+            variations: Vec::new(),
+        };
+
+        result = Some(FontMatch {
+            path: path,
+            spec: spec,
+            families: families,
+        });
+    }
+
+    FcPatternDestroy(font_match);
 
     result
 }
+
+impl FontMatch {
+    /// Returns whether one of the family names of the font is `family`.
+    ///
+    /// Generic families such as "sans" are aliases that Fontconfig resolves
+    /// to an installed font, so every font is a member of them.
+    pub fn has_family(&self, family: &str) -> bool {
+        is_generic_family(family) ||
+        self.families.iter().any(|f| f.to_lowercase() == family.to_lowercase())
+    }
+}
+
+/// Returns whether the family is one of the generic aliases that the default
+/// Fontconfig configuration defines, rather than the name of a font.
+fn is_generic_family(family: &str) -> bool {
+    match family.to_lowercase().as_ref() {
+        "sans" | "sans-serif" | "serif" | "monospace" | "mono" |
+        "cursive" | "fantasy" | "system-ui" | "emoji" | "math" => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn has_family_accepts_generic_families() {
+    let font_match = FontMatch {
+        path: PathBuf::from("/usr/share/fonts/DejaVuSans.ttf"),
+        spec: FontSpec {
+            family: "DejaVu Sans".to_string(),
+            weight: 400,
+            slant: Slant::Roman,
+            stretch: 100,
+            variations: Vec::new(),
+        },
+        families: vec!["DejaVu Sans".to_string()],
+    };
+    assert!(font_match.has_family("dejavu sans"));
+    assert!(font_match.has_family("sans"));
+    assert!(font_match.has_family("Monospace"));
+    assert!(!font_match.has_family("Cantarell"));
+}
@@ -12,12 +12,17 @@ use std::os::raw::{c_char, c_int, c_uint, c_void};
 use std::ptr;
 use std::slice;
 
+use elements::Variation;
+
 #[allow(non_camel_case_types)]
 enum hb_font_t {}
 
 #[allow(non_camel_case_types)]
 enum hb_buffer_t {}
 
+#[allow(non_camel_case_types)]
+enum hb_face_t {}
+
 #[allow(non_camel_case_types)]
 type hb_destroy_func_t = *mut extern fn(*mut c_void);
 
@@ -68,6 +73,32 @@ struct hb_feature_t {
     end: c_uint,
 }
 
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct hb_glyph_extents_t {
+    // The Harfbuzz types are hb_position_t. The y-axis points up, so the
+    // height is negative for glyphs that extend down from the bearing.
+    x_bearing: i32,
+    y_bearing: i32,
+    width: i32,
+    height: i32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct hb_ot_math_glyph_variant_t {
+    glyph: u32,
+    advance: i32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct hb_variation_t {
+    // The axis tag, in the same representation as the feature tag.
+    tag: u32,
+    value: f32,
+}
+
 // Note: this is an enum in C. We can define one in Rust, but the underlying
 // type of an enum in C is 'int', and although in Rust we can opt for u32 or u64
 // directly, we cannot pick the platform-dependent 'int' type. So define a bunch
@@ -89,6 +120,7 @@ mod hb {
 extern {
     fn hb_ft_font_create(ft_face: FT_Face, destroy: hb_destroy_func_t) -> *mut hb_font_t;
     fn hb_font_destroy(font: *mut hb_font_t);
+    fn hb_font_set_variations(font: *mut hb_font_t, variations: *const hb_variation_t, variations_length: c_uint);
     fn hb_buffer_create() -> *mut hb_buffer_t;
     fn hb_buffer_destroy(buffer: *mut hb_buffer_t);
     fn hb_buffer_set_direction(buffer: *mut hb_buffer_t, direction: hb_direction_t);
@@ -97,6 +129,18 @@ extern {
     fn hb_buffer_get_glyph_infos(buffer: *mut hb_buffer_t, length: *mut c_uint) -> *mut hb_glyph_info_t;
     fn hb_buffer_get_glyph_positions(buffer: *mut hb_buffer_t, length: *mut c_uint) -> *mut hb_glyph_position_t;
     fn hb_feature_from_string(string: *const c_char, length: c_int, feature: *mut hb_feature_t) -> hb_bool_t;
+    fn hb_font_get_face(font: *mut hb_font_t) -> *mut hb_face_t;
+    fn hb_font_get_glyph_extents(font: *mut hb_font_t, glyph: u32, extents: *mut hb_glyph_extents_t) -> hb_bool_t;
+    fn hb_font_get_glyph_h_advance(font: *mut hb_font_t, glyph: u32) -> i32;
+    fn hb_ot_math_has_data(face: *mut hb_face_t) -> hb_bool_t;
+    fn hb_ot_math_get_constant(font: *mut hb_font_t, constant: c_int) -> i32;
+    fn hb_ot_math_get_glyph_variants(font: *mut hb_font_t,
+                                     glyph: u32,
+                                     direction: hb_direction_t,
+                                     start_offset: c_uint,
+                                     variants_count: *mut c_uint,
+                                     variants: *mut hb_ot_math_glyph_variant_t)
+                                     -> c_uint;
 }
 
 pub struct Font {
@@ -109,6 +153,7 @@ pub struct Buffer {
 
 /// Text direction (Rust version of `hb_direction_t`).
 #[allow(dead_code)] // Not all variants are used, but they're there anyway.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Direction {
     LeftToRight,
     RightToLeft,
@@ -116,9 +161,83 @@ pub enum Direction {
     BottomToTop,
 }
 
+/// Constants from the OpenType MATH table (Rust version of
+/// `hb_ot_math_constant_t`), in the order that Harfbuzz numbers them.
+#[allow(dead_code)] // Not all variants are used, but they're there anyway.
+#[derive(Copy, Clone, Debug)]
+pub enum MathConstant {
+    ScriptPercentScaleDown,
+    ScriptScriptPercentScaleDown,
+    DelimitedSubFormulaMinHeight,
+    DisplayOperatorMinHeight,
+    MathLeading,
+    AxisHeight,
+    AccentBaseHeight,
+    FlattenedAccentBaseHeight,
+    SubscriptShiftDown,
+    SubscriptTopMax,
+    SubscriptBaselineDropMin,
+    SuperscriptShiftUp,
+    SuperscriptShiftUpCramped,
+    SuperscriptBottomMin,
+    SuperscriptBaselineDropMax,
+    SubSuperscriptGapMin,
+    SuperscriptBottomMaxWithSubscript,
+    SpaceAfterScript,
+    UpperLimitGapMin,
+    UpperLimitBaselineRiseMin,
+    LowerLimitGapMin,
+    LowerLimitBaselineDropMin,
+    StackTopShiftUp,
+    StackTopDisplayStyleShiftUp,
+    StackBottomShiftDown,
+    StackBottomDisplayStyleShiftDown,
+    StackGapMin,
+    StackDisplayStyleGapMin,
+    StretchStackTopShiftUp,
+    StretchStackBottomShiftDown,
+    StretchStackGapAboveMin,
+    StretchStackGapBelowMin,
+    FractionNumeratorShiftUp,
+    FractionNumeratorDisplayStyleShiftUp,
+    FractionDenominatorShiftDown,
+    FractionDenominatorDisplayStyleShiftDown,
+    FractionNumeratorGapMin,
+    FractionNumDisplayStyleGapMin,
+    FractionRuleThickness,
+    FractionDenominatorGapMin,
+    FractionDenomDisplayStyleGapMin,
+    SkewedFractionHorizontalGap,
+    SkewedFractionVerticalGap,
+    OverbarVerticalGap,
+    OverbarRuleThickness,
+    OverbarExtraAscender,
+    UnderbarVerticalGap,
+    UnderbarRuleThickness,
+    UnderbarExtraDescender,
+    RadicalVerticalGap,
+    RadicalDisplayStyleVerticalGap,
+    RadicalRuleThickness,
+    RadicalExtraAscender,
+    RadicalKernBeforeDegree,
+    RadicalKernAfterDegree,
+    RadicalDegreeBottomRaisePercent,
+}
+
+/// The ink extents of a glyph, relative to its origin. The y-axis points up.
+#[derive(Copy, Clone, Debug)]
+pub struct GlyphExtents {
+    pub x_bearing: i32,
+    pub y_bearing: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Glyph {
     pub codepoint: u32,
+    /// Byte offset of the first character in the input that maps to this glyph.
+    pub cluster: u32,
     pub x_advance: i32,
     pub y_advance: i32,
     pub x_offset: i32,
@@ -132,6 +251,66 @@ impl Font {
             ptr: unsafe { hb_ft_font_create(ft_face.raw_mut(), ptr::null_mut()) },
         }
     }
+
+    /// Sets the coordinates on the axes of a variable font. Harfbuzz ignores
+    /// axes that the font does not have.
+    pub fn set_variations(&mut self, variations: &[Variation]) {
+        let hb_variations: Vec<hb_variation_t> = variations.iter().map(|v| {
+            hb_variation_t {
+                tag: v.tag,
+                value: (v.value as f64 / 65536.0) as f32,
+            }
+        }).collect();
+        unsafe {
+            hb_font_set_variations(self.ptr, hb_variations.as_ptr(), hb_variations.len() as c_uint)
+        }
+    }
+
+    /// Returns whether the font has an OpenType MATH table.
+    pub fn has_math_data(&mut self) -> bool {
+        unsafe { hb_ot_math_has_data(hb_font_get_face(self.ptr)) != 0 }
+    }
+
+    /// Returns a constant from the MATH table. Percentages are returned as
+    /// is, other constants in the same units as glyph positions.
+    pub fn math_constant(&mut self, constant: MathConstant) -> i32 {
+        unsafe { hb_ot_math_get_constant(self.ptr, constant as c_int) }
+    }
+
+    /// Returns the extents of a glyph, or all zeros if the font has none.
+    pub fn glyph_extents(&mut self, glyph: u32) -> GlyphExtents {
+        let mut extents = hb_glyph_extents_t { x_bearing: 0, y_bearing: 0, width: 0, height: 0 };
+        unsafe { hb_font_get_glyph_extents(self.ptr, glyph, &mut extents) };
+        GlyphExtents {
+            x_bearing: extents.x_bearing,
+            y_bearing: extents.y_bearing,
+            width: extents.width,
+            height: extents.height,
+        }
+    }
+
+    pub fn glyph_h_advance(&mut self, glyph: u32) -> i32 {
+        unsafe { hb_font_get_glyph_h_advance(self.ptr, glyph) }
+    }
+
+    /// Returns the larger versions of a glyph that the MATH table lists for
+    /// stretching it in the given direction, as (glyph, advance) pairs from
+    /// small to large. The glyph itself is usually the first variant.
+    pub fn math_glyph_variants(&mut self, glyph: u32, direction: Direction) -> Vec<(u32, i32)> {
+        unsafe {
+            // The first call only counts the variants.
+            let mut count = 0;
+            let total = hb_ot_math_get_glyph_variants(self.ptr, glyph, direction.to_hb(),
+                                                      0, &mut count, ptr::null_mut());
+            let mut variants: Vec<hb_ot_math_glyph_variant_t> = (0..total).map(|_| {
+                hb_ot_math_glyph_variant_t { glyph: 0, advance: 0 }
+            }).collect();
+            count = total;
+            hb_ot_math_get_glyph_variants(self.ptr, glyph, direction.to_hb(),
+                                          0, &mut count, variants.as_mut_ptr());
+            variants.iter().take(count as usize).map(|v| (v.glyph, v.advance)).collect()
+        }
+    }
 }
 
 impl Drop for Font {
@@ -140,14 +319,20 @@ impl Drop for Font {
     }
 }
 
-impl Buffer {
-    pub fn new(direction: Direction) -> Buffer {
-        let hb_direction = match direction {
+impl Direction {
+    fn to_hb(self) -> hb_direction_t {
+        match self {
             Direction::RightToLeft => hb::HB_DIRECTION_RTL,
             Direction::LeftToRight => hb::HB_DIRECTION_LTR,
             Direction::TopToBottom => hb::HB_DIRECTION_TTB,
             Direction::BottomToTop => hb::HB_DIRECTION_BTT,
-        };
+        }
+    }
+}
+
+impl Buffer {
+    pub fn new(direction: Direction) -> Buffer {
+        let hb_direction = direction.to_hb();
 
         let ptr = unsafe {
             // Note: Harfbuzz buffers are refcounted, and creating one will set its
@@ -180,24 +365,22 @@ impl Buffer {
         unsafe { hb_buffer_add_utf8(self.ptr, chars, count, 0, count) }
     }
 
-    pub fn shape(&mut self, font: &mut Font) {
-        // Enable default features (proper kerning).
-        // TODO: This does not appear to do anything; "Yellow" still looks awful
-        // in Cantarell (see background_color example). How do I do kerning with
-        // Harfbuzz?
-        // For now, enable old-style numerals by default instead.
-        let features = unsafe {
-            let mut fs = [mem::uninitialized()];
-            let feature = b"onum";
+    /// Shapes the buffer with the given OpenType features enabled or disabled.
+    ///
+    /// Features use the Harfbuzz syntax, for instance "onum" enables
+    /// old-style numerals, and "-kern" disables kerning.
+    pub fn shape(&mut self, font: &mut Font, features: &[&str]) {
+        let hb_features: Vec<hb_feature_t> = features.iter().map(|feature| unsafe {
+            let mut hb_feature = mem::uninitialized();
             let parsed_ok = hb_feature_from_string(
                 mem::transmute(feature.as_ptr()),
                 feature.len() as c_int,
-                &mut fs[0]);
-            assert!(parsed_ok == 1);
-            fs
-        };
+                &mut hb_feature);
+            assert!(parsed_ok == 1, "Invalid OpenType feature '{}'.", feature);
+            hb_feature
+        }).collect();
 
-        unsafe { hb_shape(font.ptr, self.ptr, features.as_ptr(), features.len() as c_uint) }
+        unsafe { hb_shape(font.ptr, self.ptr, hb_features.as_ptr(), hb_features.len() as c_uint) }
     }
 
     pub fn glyphs(&mut self) -> Vec<Glyph> {
@@ -218,6 +401,7 @@ impl Buffer {
         infos.iter().zip(poss.iter()).map(|(info, pos)| {
             Glyph {
                 codepoint: info.codepoint,
+                cluster: info.cluster,
                 x_offset: pos.x_offset,
                 y_offset: pos.y_offset,
                 x_advance: pos.x_advance,
@@ -0,0 +1,261 @@
+// Pris -- A language for designing slides
+// Copyright 2017 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module splits source code into spans for syntax highlighting.
+//!
+//! Pris source is tokenized by the Pris lexer. For other languages there is
+//! a small grammar that lists keywords, comment markers and string quotes,
+//! which is enough to highlight code samples on slides. The spans cover all
+//! of the source, so concatenating them yields the source again.
+
+use lexer;
+use lexer::Token;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SpanKind {
+    Plain,
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+/// The languages that can be highlighted.
+pub const LANGUAGES: [&'static str; 6] = ["json", "pris", "python", "rust", "shell", "text"];
+
+struct Grammar {
+    keywords: &'static [&'static str],
+    line_comment: Option<&'static str>,
+    /// Whether a line comment must follow whitespace, as in shell, where
+    /// e.g. "$#" is not a comment.
+    comment_after_space: bool,
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+static RUST: Grammar = Grammar {
+    keywords: &["as", "break", "const", "continue", "crate", "else", "enum", "extern", "false",
+                "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+                "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+                "true", "type", "unsafe", "use", "where", "while"],
+    line_comment: Some("//"),
+    comment_after_space: false,
+    block_comment: Some(("/*", "*/")),
+    // Single quotes are not included, because they also start lifetimes.
+    quotes: &['"'],
+};
+
+static PYTHON: Grammar = Grammar {
+    keywords: &["False", "None", "True", "and", "as", "assert", "break", "class", "continue",
+                "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+                "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise",
+                "return", "try", "while", "with", "yield"],
+    line_comment: Some("#"),
+    comment_after_space: false,
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+static SHELL: Grammar = Grammar {
+    keywords: &["case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function",
+                "if", "in", "local", "return", "then", "until", "while"],
+    line_comment: Some("#"),
+    comment_after_space: true,
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+static JSON: Grammar = Grammar {
+    keywords: &["false", "null", "true"],
+    line_comment: None,
+    comment_after_space: false,
+    block_comment: None,
+    quotes: &['"'],
+};
+
+static TEXT: Grammar = Grammar {
+    keywords: &[],
+    line_comment: None,
+    comment_after_space: false,
+    block_comment: None,
+    quotes: &[],
+};
+
+/// Splits the source into highlighted spans. Returns `None` if the language
+/// is not one of `LANGUAGES`.
+pub fn highlight<'a>(source: &'a str, language: &str) -> Option<Vec<(SpanKind, &'a str)>> {
+    let grammar = match language {
+        "json" => &JSON,
+        "pris" => return Some(highlight_pris(source)),
+        "python" => &PYTHON,
+        "rust" => &RUST,
+        "shell" => &SHELL,
+        "text" => &TEXT,
+        _ => return None,
+    };
+    Some(highlight_grammar(source, grammar))
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the byte index of the first char at or after `start` for which
+/// the predicate does not hold.
+fn skip_while<F: Fn(char) -> bool>(source: &str, start: usize, pred: F) -> usize {
+    match source[start..].char_indices().find(|&(_, c)| !pred(c)) {
+        Some((i, _)) => start + i,
+        None => source.len(),
+    }
+}
+
+/// Returns the index past the closing quote of the string that starts at
+/// `start`, or the end of the source for an unterminated string.
+fn skip_string(source: &str, start: usize, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in source[start + quote.len_utf8()..].char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return start + quote.len_utf8() + i + c.len_utf8()
+        }
+    }
+    source.len()
+}
+
+fn highlight_grammar<'a>(source: &'a str, grammar: &Grammar) -> Vec<(SpanKind, &'a str)> {
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut i = 0;
+
+    while i < source.len() {
+        let rest = &source[i..];
+        let c = rest.chars().next().unwrap();
+        let prev = source[..i].chars().next_back();
+        let comment_allowed = !grammar.comment_after_space || prev.map_or(true, |p| p.is_whitespace());
+
+        let (kind, end) = match (grammar.line_comment, grammar.block_comment) {
+            (Some(marker), _) if comment_allowed && rest.starts_with(marker) => {
+                (SpanKind::Comment, rest.find('\n').map_or(source.len(), |n| i + n))
+            }
+            (_, Some((open, close))) if rest.starts_with(open) => {
+                let end = rest[open.len()..].find(close)
+                                            .map_or(source.len(), |n| i + open.len() + n + close.len());
+                (SpanKind::Comment, end)
+            }
+            _ if grammar.quotes.contains(&c) => (SpanKind::String, skip_string(source, i, c)),
+            _ if c.is_digit(10) && prev.map_or(true, |p| !is_ident_continue(p)) => {
+                (SpanKind::Number, skip_while(source, i, |d| is_ident_continue(d) || d == '.'))
+            }
+            _ if is_ident_start(c) && prev.map_or(true, |p| !is_ident_continue(p)) => {
+                let end = skip_while(source, i, is_ident_continue);
+                let kind = if grammar.keywords.contains(&&source[i..end]) {
+                    SpanKind::Keyword
+                } else {
+                    SpanKind::Plain
+                };
+                (kind, end)
+            }
+            _ => (SpanKind::Plain, i + c.len_utf8()),
+        };
+
+        if kind != SpanKind::Plain {
+            if plain_start < i {
+                spans.push((SpanKind::Plain, &source[plain_start..i]));
+            }
+            spans.push((kind, &source[i..end]));
+            plain_start = end;
+        }
+        i = end;
+    }
+
+    if plain_start < source.len() {
+        spans.push((SpanKind::Plain, &source[plain_start..]));
+    }
+    spans
+}
+
+/// Highlights Pris source with the Pris lexer. The lexer drops whitespace
+/// and comments, so the gaps between tokens are either. If the source does
+/// not lex, it is not highlighted at all.
+fn highlight_pris<'a>(source: &'a str) -> Vec<(SpanKind, &'a str)> {
+    let tokens = match lexer::lex(source.as_bytes()) {
+        Ok(tokens) => tokens,
+        Err(..) => return vec![(SpanKind::Plain, source)],
+    };
+
+    let mut spans = Vec::new();
+    let mut at = 0;
+    for (start, token, end) in tokens {
+        push_pris_gap(&source[at..start], &mut spans);
+        let kind = match token {
+            Token::KwAt | Token::KwFunction | Token::KwImport |
+            Token::KwPut | Token::KwReturn | Token::KwWith => SpanKind::Keyword,
+            Token::String(..) | Token::RawString(..) => SpanKind::String,
+            Token::Number(..) | Token::Color(..) |
+            Token::UnitEm | Token::UnitH | Token::UnitW | Token::UnitPt => SpanKind::Number,
+            _ => SpanKind::Plain,
+        };
+        spans.push((kind, &source[start..end]));
+        at = end;
+    }
+    push_pris_gap(&source[at..], &mut spans);
+    spans
+}
+
+/// Splits the whitespace and comments between two Pris tokens into spans.
+fn push_pris_gap<'a>(gap: &'a str, spans: &mut Vec<(SpanKind, &'a str)>) {
+    let mut rest = gap;
+    while let Some(start) = rest.find("//") {
+        let end = rest[start..].find('\n').map_or(rest.len(), |n| start + n);
+        if start > 0 {
+            spans.push((SpanKind::Plain, &rest[..start]));
+        }
+        spans.push((SpanKind::Comment, &rest[start..end]));
+        rest = &rest[end..];
+    }
+    if rest.len() > 0 {
+        spans.push((SpanKind::Plain, rest));
+    }
+}
+
+#[test]
+fn highlight_rust_finds_keywords_strings_and_comments() {
+    let spans = highlight("let x = \"a\\\"b\"; // x", "rust").unwrap();
+    assert_eq!(spans, vec![
+        (SpanKind::Keyword, "let"),
+        (SpanKind::Plain, " x = "),
+        (SpanKind::String, "\"a\\\"b\""),
+        (SpanKind::Plain, "; "),
+        (SpanKind::Comment, "// x"),
+    ]);
+}
+
+#[test]
+fn highlight_shell_ignores_hash_inside_words() {
+    let spans = highlight("echo $# # n", "shell").unwrap();
+    assert_eq!(spans, vec![
+        (SpanKind::Plain, "echo $# "),
+        (SpanKind::Comment, "# n"),
+    ]);
+}
+
+#[test]
+fn highlight_pris_covers_source() {
+    let source = "at (1w, 0h) put t(\"x\") // y\n";
+    let spans = highlight(source, "pris").unwrap();
+    let joined: String = spans.iter().map(|&(_, s)| s).collect();
+    assert_eq!(joined, source);
+    assert_eq!(spans[0], (SpanKind::Keyword, "at"));
+    assert!(spans.contains(&(SpanKind::Comment, "// y")));
+}
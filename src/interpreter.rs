@@ -5,15 +5,19 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
+use std::path::Path;
 use std::rc::Rc;
 
 use ast;
-use ast::{Assign, BinOp, BinTerm, Block, Coord, FnCall, FnDef, Idents};
-use ast::{Num, PutAt, Return, Stmt, Term, UnOp, UnTerm, Unit};
-use error::{Error, Result};
-use elements::{Color, Vec2};
+use ast::{Assign, BinOp, BinTerm, Block, Coord, FnCall, Idents};
+use ast::{Num, PutAt, Return, Stmt, Term, UnOp, UnTerm, Unit, With};
+use error::{Error, Result, Warning};
+use elements::{Color, Slant, Vec2};
+use fontconfig;
+use pretty;
 use pretty::Formatter;
-use runtime::{Builtin, FontMap, Frame, Env, Subframe, Val};
+use runtime;
+use runtime::{Builtin, Closure, FontMap, Frame, Env, NamedArgs, Subframe, Val};
 use types::ValType;
 
 // Expression interpreter.
@@ -22,6 +26,9 @@ use types::ValType;
 pub struct ExprInterpreter<'i, 'a: 'i> {
     pub font_map: &'i mut FontMap,
     pub env: &'i Env<'a>,
+
+    /// The directory that relative paths in the source are relative to.
+    pub base_dir: &'i Path,
 }
 
 impl<'i, 'a> ExprInterpreter<'i, 'a> {
@@ -33,11 +40,13 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
             Term::Color(ref co) => Ok(ExprInterpreter::eval_color(co)),
             Term::Idents(ref i) => self.env.lookup(i),
             Term::Coord(ref co) => self.eval_coord(co),
+            Term::List(ref elems) => self.eval_list(elems),
             Term::BinOp(ref bo) => self.eval_binop(bo),
             Term::UnOp(ref uop) => self.eval_unop(uop),
             Term::FnCall(ref f) => self.eval_call(f),
-            Term::FnDef(ref fd) => Ok(Val::FnExtrin(fd)),
+            Term::FnDef(ref fd) => Ok(Val::FnExtrin(Rc::new(Closure::new(fd, self.env)))),
             Term::Block(ref bk) => self.eval_block(bk),
+            Term::Record(ref fields) => self.eval_record(fields),
         }
     }
 
@@ -80,6 +89,25 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
         }
     }
 
+    fn eval_list(&mut self, elems: &'a [Term<'a>]) -> Result<Val<'a>> {
+        let mut vals = Vec::with_capacity(elems.len());
+        for elem in elems {
+            vals.push(self.eval_expr(elem)?);
+        }
+        Ok(Val::List(vals))
+    }
+
+    fn eval_record(&mut self, fields: &'a [Assign<'a>]) -> Result<Val<'a>> {
+        // Fields are evaluated in the enclosing environment, so a field cannot
+        // refer to another field of the same record.
+        let mut vals = Vec::with_capacity(fields.len());
+        for &Assign(name, ref term) in fields {
+            let val = self.eval_expr(term)?;
+            runtime::set_field(&mut vals, name.to_string(), val);
+        }
+        Ok(Val::Record(vals))
+    }
+
     fn eval_binop(&mut self, binop: &'a BinTerm<'a>) -> Result<Val<'a>> {
         let lhs = self.eval_expr(&binop.0)?;
         let rhs = self.eval_expr(&binop.2)?;
@@ -100,20 +128,10 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
                 let mut frame = (*f0).clone();
                 let anchor = f0.get_anchor();
                 // Copy the elements of f1 onto the new frame (cloned from f0),
-                // subframe by subframe.
-                for (i, sf1) in f1.get_subframes().iter().enumerate() {
-                    // If f1 had more subframes than f0, the result will have as
-                    // many subframes as f1.
-                    if frame.get_subframes().len() <= i {
-                        frame.push_subframe(Subframe::new());
-                    }
-                    let mut subframe = frame.get_subframe_mut(i);
-                    for pe in sf1.get_elements() {
-                        subframe.place_element(anchor + pe.position, pe.element.clone());
-                    }
-                }
+                // subframe by subframe. If f1 had more subframes than f0, the
+                // result will have as many subframes as f1.
+                frame.place_frame(&f1, anchor);
                 frame.set_anchor(anchor + f1.get_anchor());
-                frame.union_bounding_box(&f1.get_bounding_box().offset(anchor));
                 Ok(Val::Frame(Rc::new(frame)))
             }
             (lhs, rhs) => {
@@ -217,13 +235,20 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
         for arg in &call.1 {
             args.push(self.eval_expr(arg)?);
         }
+        let mut named = Vec::with_capacity(call.2.len());
+        for &Assign(name, ref arg) in &call.2 {
+            named.push((name, self.eval_expr(arg)?));
+        }
         let func = self.eval_expr(&call.0)?;
         match func {
             // For a user-defined function, we evaluate the function body.
-            Val::FnExtrin(fn_def) => self.eval_call_extrin(fn_def, args),
+            Val::FnExtrin(closure) => {
+                let fn_name = pretty::print(&call.0);
+                self.eval_call_extrin(&fn_name, &closure, args, named)
+            }
             // For a builtin function, the value carries a function pointer,
             // so we can just call that.
-            Val::FnIntrin(Builtin(intrin)) => intrin(self, args),
+            Val::FnIntrin(Builtin(intrin)) => intrin(self, args, named),
             // Other things are not callable.
             _ => {
                 let msg = "Type error: attempting to call value of type <TODO>. \
@@ -234,27 +259,45 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
     }
 
     fn eval_call_extrin(&mut self,
-                        fn_def: &'a FnDef<'a>,
-                        args: Vec<Val<'a>>)
+                        fn_name: &str,
+                        closure: &Closure<'a>,
+                        args: Vec<Val<'a>>,
+                        named: NamedArgs<'a>)
                         -> Result<Val<'a>> {
-        // Ensure that a value is provided for every argument, and no more.
-        if fn_def.0.len() != args.len() {
-            let msg = format!("Arity error: function takes {} arguments, \
-                               but {} were provided.",
-                              fn_def.0.len(), args.len());
-            return Err(Error::Other(msg))
-        }
+        let fn_def = closure.def;
+        let params: Vec<&str> = fn_def.0.iter().map(|p| p.0).collect();
+        let num_given = (args.len() + named.len()) as u32;
+        let bound = runtime::bind_args(fn_name, &params, args, named)?;
 
         // For a function call, bring the argument in scope as variables, and
-        // then evaluate the body block in the modified environment.
-        let mut inner_env = self.env.clone();
-        for (arg_name, val) in fn_def.0.iter().zip(args) {
-            inner_env.put(arg_name, val);
+        // then evaluate the body block in the modified environment. The body
+        // sees the variables where the function was defined, except for style
+        // variables, which come from the call site.
+        let mut inner_env = closure.env_for_call(self.env);
+        for (param, val) in fn_def.0.iter().zip(bound) {
+            let val = match (val, &param.1) {
+                (Some(val), _) => val,
+                // Default values are evaluated at the call, in the environment
+                // of the body, so they can refer to the parameters before them.
+                (None, &Some(ref default)) => {
+                    let mut default_interpreter = ExprInterpreter {
+                        font_map: &mut *self.font_map,
+                        env: &inner_env,
+                        base_dir: self.base_dir,
+                    };
+                    default_interpreter.eval_expr(default)?
+                }
+                (None, &None) => {
+                    return Err(Error::missing_arg(fn_name, param.0, params.len() as u32, num_given))
+                }
+            };
+            inner_env.put(param.0, val);
         }
 
         let mut inner_interpreter = ExprInterpreter {
             font_map: &mut *self.font_map,
             env: &inner_env,
+            base_dir: self.base_dir,
         };
 
         inner_interpreter.eval_block(&fn_def.1)
@@ -263,7 +306,7 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
     fn eval_block(&mut self, block: &'a Block<'a>) -> Result<Val<'a>> {
         // A block is evaluated in its enclosing environment, but it does not
         // modify the environment, it gets a copy.
-        let inner_env = (*self.env).clone();
+        let inner_env = self.env.for_block();
 
         // A block can consist of multiple statements, which mutate the frame,
         // until the block ends. The statement interpreter keeps track of this
@@ -271,6 +314,7 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
         // block (if there was no return).
         let mut stmt_interpreter = StmtInterpreter {
             font_map: self.font_map,
+            base_dir: self.base_dir,
             frame: Frame::from_env(inner_env),
             current_subframe: 0,
         };
@@ -303,20 +347,51 @@ impl<'i, 'a> ExprInterpreter<'i, 'a> {
     }
 }
 
+/// Converts a value for the deprecated 'font_style' variable into the
+/// 'font_weight' and 'font_slant' variables that replace it.
+fn expand_font_style<'a>(font_map: &FontMap, value: Val<'a>) -> Result<Vec<(&'static str, Val<'a>)>> {
+    let var_style = Idents(vec!["font_style"]);
+    let style = match value {
+        Val::Str(s) => s,
+        other => return Err(Error::var_type(&var_style, ValType::Str, other.get_type())),
+    };
+    let (weight, slant) = match fontconfig::parse_style_name(&style) {
+        Some(ws) => ws,
+        None => {
+            let msg = format!("'{}' is not a valid value for 'font_style'. \
+                               Set 'font_weight' and 'font_slant' instead.", style);
+            return Err(Error::value(msg))
+        }
+    };
+    let slant_name = match slant {
+        Slant::Roman => "roman",
+        Slant::Italic => "italic",
+        Slant::Oblique => "oblique",
+    };
+    let replacement = format!("font_weight = {} and font_slant = \"{}\"", weight, slant_name);
+    font_map.warn(Warning::deprecated("font_style", replacement))?;
+    Ok(vec![
+        ("font_weight", Val::Num(weight as f64, 0)),
+        ("font_slant", Val::Str(slant_name.to_string())),
+    ])
+}
+
 // Statement interpreter.
 
 // TODO: This should not be public, or at least, not in this form.
 pub struct StmtInterpreter<'i, 'a: 'i> {
     font_map: &'i mut FontMap,
+    base_dir: &'i Path,
     frame: Frame<'a>,
     current_subframe: usize,
 }
 
 impl<'i, 'a> StmtInterpreter<'i, 'a> {
 
-    pub fn new(font_map: &'i mut FontMap) -> StmtInterpreter<'i, 'a> {
+    pub fn new(font_map: &'i mut FontMap, base_dir: &'i Path) -> StmtInterpreter<'i, 'a> {
         StmtInterpreter {
             font_map: font_map,
+            base_dir: base_dir,
             frame: Frame::new(),
             current_subframe: 0,
         }
@@ -327,6 +402,7 @@ impl<'i, 'a> StmtInterpreter<'i, 'a> {
         ExprInterpreter {
             font_map: self.font_map,
             env: env,
+            base_dir: self.base_dir,
         }
     }
 
@@ -362,13 +438,23 @@ impl<'i, 'a> StmtInterpreter<'i, 'a> {
                 self.eval_put_at(pa)?;
                 Ok(None)
             }
+            Stmt::With(ref w) => {
+                self.eval_with(w)?;
+                Ok(None)
+            }
         }
     }
 
     fn eval_assign(&mut self, stmt: &'a Assign<'a>) -> Result<()> {
         let Assign(target, ref expression) = *stmt;
         let value = self.get_expr_interpreter().eval_expr(expression)?;
-        self.frame.put_in_env(target, value);
+        if target == "font_style" {
+            for (name, val) in expand_font_style(self.font_map, value)? {
+                self.frame.assign_in_env(name, val);
+            }
+            return Ok(())
+        }
+        self.frame.assign_in_env(target, value);
         Ok(())
     }
 
@@ -391,6 +477,52 @@ impl<'i, 'a> StmtInterpreter<'i, 'a> {
             }
         };
 
+        self.place_frame(&content, pos);
+        Ok(())
+    }
+
+    fn eval_with(&mut self, with: &'a With<'a>) -> Result<()> {
+        // The values are evaluated in the current environment, but the body
+        // sees them all at once, so one cannot refer to another.
+        let mut env = self.frame.get_env().clone();
+        for &Assign(name, ref expression) in &with.0 {
+            let value = self.get_expr_interpreter().eval_expr(expression)?;
+            if name == "font_style" {
+                for (name, val) in expand_font_style(self.font_map, value)? {
+                    env.put(name, val);
+                }
+                continue
+            }
+            env.put(name, value);
+        }
+
+        let content = {
+            let mut expr_interpreter = ExprInterpreter {
+                font_map: &mut *self.font_map,
+                env: &env,
+                base_dir: self.base_dir,
+            };
+            expr_interpreter.eval_block(&with.1)?
+        };
+
+        match content {
+            Val::Frame(frame) => {
+                self.place_frame(&frame, Vec2::zero());
+                Ok(())
+            }
+            other => {
+                let mut f = Formatter::new();
+                f.print("The body of 'with' must evaluate to a frame, but found a '");
+                f.print(other.get_type());
+                f.print("' instead.");
+                Err(Error::Other(f.into_string()))
+            }
+        }
+    }
+
+    /// Places the elements of a frame at the given position in the current
+    /// frame, starting at the current subframe.
+    fn place_frame(&mut self, content: &Frame<'a>, pos: Vec2) {
         // Ensure that the current frame has enough subframes to place the
         // elements in content subframes. If the content has more subframes than
         // the current frame, more must be added.
@@ -414,7 +546,5 @@ impl<'i, 'a> StmtInterpreter<'i, 'a> {
         // Update the anchor of the frame: the anchor of a block is the anchor
         // of the element that was placed last.
         self.frame.set_anchor(pos + content.get_anchor());
-
-        Ok(())
     }
 }
@@ -40,6 +40,7 @@ pub enum Token<'a> {
     KwImport,
     KwPut,
     KwReturn,
+    KwWith,
 
     UnitEm,
     UnitH,
@@ -60,6 +61,8 @@ pub enum Token<'a> {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
 }
 
 /// Lexes a UTF-8 input file into (start_index, token, past_end_index) tokens.
@@ -203,6 +206,8 @@ impl<'a> Lexer<'a> {
                 b')' => self.push_single(i, Token::RParen),
                 b'{' => self.push_single(i, Token::LBrace),
                 b'}' => self.push_single(i, Token::RBrace),
+                b'[' => self.push_single(i, Token::LBracket),
+                b']' => self.push_single(i, Token::RBracket),
 
                 // If we detect the start of a byte order mark, complain about a
                 // wrong encoding. (No BOMs for UTF-8 either, please.)
@@ -525,6 +530,7 @@ fn make_keyword_or_ident(ident: &str) -> Token {
         "import" => Token::KwImport,
         "put" => Token::KwPut,
         "return" => Token::KwReturn,
+        "with" => Token::KwWith,
         _ => Token::Ident(ident),
     }
 }
@@ -676,11 +682,23 @@ fn lex_handles_braces() {
     assert_eq!(tokens[1], (2, Token::RBrace, 3));
 }
 
+#[test]
+fn lex_handles_brackets() {
+    let input = b"[a, b]";
+    let tokens = lex(input).unwrap();
+    assert_eq!(tokens.len(), 5);
+    assert_eq!(tokens[0], (0, Token::LBracket, 1));
+    assert_eq!(tokens[1], (1, Token::Ident("a"), 2));
+    assert_eq!(tokens[2], (2, Token::Comma, 3));
+    assert_eq!(tokens[3], (4, Token::Ident("b"), 5));
+    assert_eq!(tokens[4], (5, Token::RBracket, 6));
+}
+
 #[test]
 fn lex_handles_keywords() {
-    let input = b"return the function put at the import";
+    let input = b"return the function put at the import with";
     let tokens = lex(input).unwrap();
-    assert_eq!(tokens.len(), 7);
+    assert_eq!(tokens.len(), 8);
     assert_eq!(tokens[0], (0, Token::KwReturn, 6));
     assert_eq!(tokens[1], (7, Token::Ident("the"), 10));
     assert_eq!(tokens[2], (11, Token::KwFunction, 19));
@@ -688,6 +706,7 @@ fn lex_handles_keywords() {
     assert_eq!(tokens[4], (24, Token::KwAt, 26));
     assert_eq!(tokens[5], (27, Token::Ident("the"), 30));
     assert_eq!(tokens[6], (31, Token::KwImport, 37));
+    assert_eq!(tokens[7], (38, Token::KwWith, 42));
 }
 
 #[test]
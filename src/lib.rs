@@ -9,13 +9,18 @@ extern crate freetype;
 extern crate lalrpop_util;
 
 mod builtins;
+mod data;
 mod elements;
 mod fontconfig;
 mod harfbuzz;
+mod highlight;
+mod math;
+mod outline;
 mod parser;
 mod pretty;
 mod rsvg;
 mod types;
+mod variations;
 
 pub mod ast;
 pub mod cairo;
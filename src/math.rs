@@ -0,0 +1,1153 @@
+// Pris -- A language for designing slides
+// Copyright 2017 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module typesets formulas written in a subset of TeX.
+//!
+//! Formulas are parsed into a tree of nodes, which is laid out following the
+//! rules of TeX, with the parameters taken from the OpenType MATH table of
+//! the font, as MathML does. Stretchy delimiters and radicals use the larger
+//! glyph variants that the MATH table lists; glyph assembly from parts is not
+//! supported, so very tall delimiters use the largest variant.
+
+use std::cmp;
+use std::mem;
+use std::result;
+
+use cairo;
+use elements::{Color, Element, FillPolygon, FontSpec, Text, Vec2};
+use error::{Error, Result};
+use harfbuzz::{Direction, MathConstant};
+use pretty::Formatter;
+use runtime::FontMap;
+
+/// The class of an atom, which determines the space around it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Class {
+    Ord,
+    Op,
+    Bin,
+    Rel,
+    Open,
+    Close,
+    Punct,
+    Inner,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    /// Text in a single class, with letters already in the math alphabets.
+    Symbol(String, Class),
+    /// A large operator such as a sum, or a function name such as "lim".
+    Operator { text: String, large: bool, limits: bool },
+    Group(Vec<Node>),
+    Scripts { base: Box<Node>, sub: Option<Box<Node>>, sup: Option<Box<Node>> },
+    Fraction(Box<Node>, Box<Node>),
+    Radical { body: Box<Node>, degree: Option<Box<Node>> },
+    /// Content between delimiters that stretch to fit it.
+    Delimited { open: Option<char>, body: Vec<Node>, close: Option<char> },
+    Matrix(Vec<Vec<Node>>),
+    /// Horizontal space in ems.
+    Space(f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Char(char),
+    Command(String),
+    Space,
+    LBrace,
+    RBrace,
+    Hat,
+    Underscore,
+    Ampersand,
+    NewRow,
+}
+
+fn tokenize(formula: &str) -> result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '^' => Token::Hat,
+            '_' => Token::Underscore,
+            '&' => Token::Ampersand,
+            '\\' => {
+                let mut name = String::new();
+                while let Some(&n) = chars.peek() {
+                    if !n.is_alphabetic() { break }
+                    name.push(n);
+                    chars.next();
+                }
+                if name.len() > 0 {
+                    Token::Command(name)
+                } else {
+                    match chars.next() {
+                        Some('\\') => Token::NewRow,
+                        Some(n) => Token::Command(n.to_string()),
+                        None => return Err("The formula ends in a backslash.".to_string()),
+                    }
+                }
+            }
+            _ if c.is_whitespace() => Token::Space,
+            _ => Token::Char(c),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Returns the class of a symbol when it is not an ordinary symbol.
+fn class_of_char(c: char) -> Class {
+    match c {
+        '+' | '−' | '∗' | '±' | '∓' | '×' | '÷' | '⋅' | '∘' | '∪' | '∩' |
+        '∧' | '∨' | '⊕' | '⊗' | '∖' => Class::Bin,
+        '=' | '<' | '>' | ':' | '≤' | '≥' | '≠' | '≈' | '≡' | '∼' | '≃' | '∝' |
+        '→' | '←' | '⇒' | '⇐' | '⇔' | '⟺' | '↦' | '∈' | '∉' | '∋' | '⊂' | '⊃' |
+        '⊆' | '⊇' | '≪' | '≫' | '∣' => Class::Rel,
+        '(' | '[' | '{' | '⟨' | '⌈' | '⌊' => Class::Open,
+        ')' | ']' | '}' | '⟩' | '⌉' | '⌋' | '!' | '?' => Class::Close,
+        ',' | ';' => Class::Punct,
+        _ => Class::Ord,
+    }
+}
+
+/// Maps Latin and lowercase Greek letters to the math italic alphabet.
+fn math_italic(c: char) -> char {
+    let offset = match c {
+        // The italic small h predates the math alphabets, so it is elsewhere.
+        'h' => return '\u{210e}',
+        'a'...'z' => 0x1d44e + (c as u32 - 'a' as u32),
+        'A'...'Z' => 0x1d434 + (c as u32 - 'A' as u32),
+        'α'...'ω' => 0x1d6fc + (c as u32 - 'α' as u32),
+        'ϵ' => 0x1d716,
+        'ϑ' => 0x1d717,
+        'ϕ' => 0x1d719,
+        'ϱ' => 0x1d71a,
+        'ϖ' => 0x1d71b,
+        _ => return c,
+    };
+    ::std::char::from_u32(offset).unwrap_or(c)
+}
+
+fn symbol_for_char(c: char) -> Node {
+    match c {
+        '-' => Node::Symbol("−".to_string(), Class::Bin),
+        '*' => Node::Symbol("∗".to_string(), Class::Bin),
+        '\'' => Node::Symbol("′".to_string(), Class::Ord),
+        _ => Node::Symbol(math_italic(c).to_string(), class_of_char(c)),
+    }
+}
+
+fn symbol_for_command(name: &str) -> Option<char> {
+    let c = match name {
+        "alpha" => 'α', "beta" => 'β', "gamma" => 'γ', "delta" => 'δ',
+        "epsilon" => 'ϵ', "varepsilon" => 'ε', "zeta" => 'ζ', "eta" => 'η',
+        "theta" => 'θ', "vartheta" => 'ϑ', "iota" => 'ι', "kappa" => 'κ',
+        "lambda" => 'λ', "mu" => 'μ', "nu" => 'ν', "xi" => 'ξ', "pi" => 'π',
+        "varpi" => 'ϖ', "rho" => 'ρ', "varrho" => 'ϱ', "sigma" => 'σ',
+        "varsigma" => 'ς', "tau" => 'τ', "upsilon" => 'υ', "phi" => 'ϕ',
+        "varphi" => 'φ', "chi" => 'χ', "psi" => 'ψ', "omega" => 'ω',
+        "Gamma" => 'Γ', "Delta" => 'Δ', "Theta" => 'Θ', "Lambda" => 'Λ',
+        "Xi" => 'Ξ', "Pi" => 'Π', "Sigma" => 'Σ', "Upsilon" => 'Υ',
+        "Phi" => 'Φ', "Psi" => 'Ψ', "Omega" => 'Ω',
+        "pm" => '±', "mp" => '∓', "times" => '×', "div" => '÷', "cdot" => '⋅',
+        "ast" => '∗', "circ" => '∘', "cup" => '∪', "cap" => '∩', "wedge" => '∧',
+        "vee" => '∨', "oplus" => '⊕', "otimes" => '⊗', "setminus" => '∖',
+        "leq" | "le" => '≤', "geq" | "ge" => '≥', "neq" | "ne" => '≠',
+        "approx" => '≈', "equiv" => '≡', "sim" => '∼', "simeq" => '≃',
+        "propto" => '∝', "to" | "rightarrow" => '→', "leftarrow" | "gets" => '←',
+        "Rightarrow" => '⇒', "Leftarrow" => '⇐', "Leftrightarrow" => '⇔',
+        "iff" => '⟺', "mapsto" => '↦', "in" => '∈', "notin" => '∉', "ni" => '∋',
+        "subset" => '⊂', "supset" => '⊃', "subseteq" => '⊆', "supseteq" => '⊇',
+        "ll" => '≪', "gg" => '≫', "mid" => '∣',
+        "infty" => '∞', "partial" => '∂', "nabla" => '∇', "forall" => '∀',
+        "exists" => '∃', "emptyset" => '∅', "neg" => '¬', "ldots" => '…',
+        "cdots" => '⋯', "vdots" => '⋮', "ddots" => '⋱', "prime" => '′',
+        "hbar" => 'ℏ', "ell" => 'ℓ', "Re" => 'ℜ', "Im" => 'ℑ', "aleph" => 'ℵ',
+        "angle" => '∠', "triangle" => '△',
+        "langle" => '⟨', "rangle" => '⟩', "lceil" => '⌈', "rceil" => '⌉',
+        "lfloor" => '⌊', "rfloor" => '⌋',
+        "{" => '{', "}" => '}', "|" => '‖', "%" => '%', "#" => '#', "$" => '$',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Returns the symbol and whether limits go above and below it in display
+/// style, for large operators.
+fn large_operator(name: &str) -> Option<(char, bool)> {
+    match name {
+        "sum" => Some(('∑', true)),
+        "prod" => Some(('∏', true)),
+        "coprod" => Some(('∐', true)),
+        "bigcup" => Some(('⋃', true)),
+        "bigcap" => Some(('⋂', true)),
+        "bigoplus" => Some(('⨁', true)),
+        "bigotimes" => Some(('⨂', true)),
+        "int" => Some(('∫', false)),
+        "iint" => Some(('∬', false)),
+        "oint" => Some(('∮', false)),
+        _ => None,
+    }
+}
+
+/// Returns whether a function name takes limits above and below it in
+/// display style, for the function names that are known.
+fn function_name(name: &str) -> Option<bool> {
+    match name {
+        "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "arcsin" | "arccos" |
+        "arctan" | "sinh" | "cosh" | "tanh" | "log" | "ln" | "lg" | "exp" |
+        "deg" | "dim" | "ker" | "arg" | "gcd" => Some(false),
+        "lim" | "max" | "min" | "sup" | "inf" | "det" | "Pr" => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns the space for a command such as `\,`, in ems.
+fn space_for_command(name: &str) -> Option<f64> {
+    match name {
+        "," => Some(3.0 / 18.0),
+        ":" | ">" => Some(4.0 / 18.0),
+        ";" => Some(5.0 / 18.0),
+        "!" => Some(-3.0 / 18.0),
+        " " => Some(1.0 / 3.0),
+        "quad" => Some(1.0),
+        "qquad" => Some(2.0),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+/// Parses a formula into a list of nodes.
+pub fn parse(formula: &str) -> result::Result<Vec<Node>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(formula)?,
+        pos: 0,
+    };
+    let nodes = parser.parse_list(None)?;
+    parser.skip_spaces();
+    match parser.next() {
+        None => Ok(nodes),
+        Some(Token::RBrace) => Err("Found '}' without matching '{'.".to_string()),
+        Some(Token::Ampersand) | Some(Token::NewRow) => {
+            Err("Found '&' or '\\\\' outside of a matrix.".to_string())
+        }
+        Some(Token::Command(name)) => Err(format!("Found '\\{}' without matching '\\begin' or '\\left'.", name)),
+        Some(..) => unreachable!(),
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek() == Some(&Token::Space) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> result::Result<(), String> {
+        self.skip_spaces();
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err(format!("Expected {}.", description))
+        }
+    }
+
+    /// Parses atoms until the end of the group, a cell, or the `stop` char.
+    fn parse_list(&mut self, stop: Option<char>) -> result::Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_spaces();
+            let at_end = match self.peek() {
+                None | Some(&Token::RBrace) | Some(&Token::Ampersand) | Some(&Token::NewRow) => true,
+                Some(&Token::Command(ref name)) => name == "end" || name == "right",
+                Some(&Token::Char(c)) => Some(c) == stop,
+                _ => false,
+            };
+            if at_end {
+                return Ok(nodes)
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_scripts(atom)?);
+        }
+    }
+
+    fn parse_scripts(&mut self, base: Node) -> result::Result<Node, String> {
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(&Token::Hat) if sup.is_some() => return Err("Double superscript.".to_string()),
+                Some(&Token::Underscore) if sub.is_some() => return Err("Double subscript.".to_string()),
+                Some(&Token::Hat) => {}
+                Some(&Token::Underscore) => {}
+                // A prime is a superscript of its own.
+                Some(&Token::Char('\'')) if sup.is_none() => {}
+                _ => break,
+            }
+            match self.next() {
+                Some(Token::Hat) => sup = Some(Box::new(self.parse_argument()?)),
+                Some(Token::Underscore) => sub = Some(Box::new(self.parse_argument()?)),
+                _ => sup = Some(Box::new(symbol_for_char('\''))),
+            }
+        }
+        if sub.is_none() && sup.is_none() {
+            Ok(base)
+        } else {
+            Ok(Node::Scripts { base: Box::new(base), sub: sub, sup: sup })
+        }
+    }
+
+    /// Parses the argument of a command or script: a group or a single atom.
+    fn parse_argument(&mut self) -> result::Result<Node, String> {
+        self.skip_spaces();
+        match self.peek() {
+            None => Err("Expected an argument, but the formula ended.".to_string()),
+            Some(&Token::Hat) | Some(&Token::Underscore) => {
+                Err("Expected an argument, but found a script.".to_string())
+            }
+            _ => self.parse_atom(),
+        }
+    }
+
+    fn parse_atom(&mut self) -> result::Result<Node, String> {
+        self.skip_spaces();
+        match self.peek() {
+            // A script without base, as in "^2", has an empty base.
+            Some(&Token::Hat) | Some(&Token::Underscore) => return Ok(Node::Group(Vec::new())),
+            _ => {}
+        }
+        match self.next() {
+            Some(Token::LBrace) => {
+                let nodes = self.parse_list(None)?;
+                self.expect(Token::RBrace, "'}'")?;
+                Ok(Node::Group(nodes))
+            }
+            Some(Token::Char(c)) => Ok(symbol_for_char(c)),
+            Some(Token::Command(name)) => self.parse_command(&name),
+            Some(Token::RBrace) => Err("Found '}' without matching '{'.".to_string()),
+            _ => Err("Expected an atom.".to_string()),
+        }
+    }
+
+    fn parse_command(&mut self, name: &str) -> result::Result<Node, String> {
+        if let Some(c) = symbol_for_command(name) {
+            return Ok(Node::Symbol(math_italic(c).to_string(), class_of_char(c)))
+        }
+        if let Some((c, limits)) = large_operator(name) {
+            return Ok(Node::Operator { text: c.to_string(), large: true, limits: limits })
+        }
+        if let Some(limits) = function_name(name) {
+            return Ok(Node::Operator { text: name.to_string(), large: false, limits: limits })
+        }
+        if let Some(em) = space_for_command(name) {
+            return Ok(Node::Space(em))
+        }
+        match name {
+            "frac" => {
+                let num = self.parse_argument()?;
+                let den = self.parse_argument()?;
+                Ok(Node::Fraction(Box::new(num), Box::new(den)))
+            }
+            "sqrt" => {
+                self.skip_spaces();
+                let degree = if self.peek() == Some(&Token::Char('[')) {
+                    self.pos += 1;
+                    let nodes = self.parse_list(Some(']'))?;
+                    self.expect(Token::Char(']'), "']' after the degree of the root")?;
+                    Some(Box::new(Node::Group(nodes)))
+                } else {
+                    None
+                };
+                let body = self.parse_argument()?;
+                Ok(Node::Radical { body: Box::new(body), degree: degree })
+            }
+            "left" => {
+                let open = self.parse_delimiter("left")?;
+                let body = self.parse_list(None)?;
+                self.expect(Token::Command("right".to_string()), "'\\right'")?;
+                let close = self.parse_delimiter("right")?;
+                Ok(Node::Delimited { open: open, body: body, close: close })
+            }
+            "begin" => self.parse_environment(),
+            "text" | "mathrm" => {
+                self.expect(Token::LBrace, "'{' after '\\text'")?;
+                let mut text = String::new();
+                loop {
+                    match self.next() {
+                        Some(Token::RBrace) => break,
+                        Some(Token::Char(c)) => text.push(c),
+                        Some(Token::Space) => text.push(' '),
+                        _ => return Err("Expected only text in '\\text'.".to_string()),
+                    }
+                }
+                Ok(Node::Symbol(text, Class::Ord))
+            }
+            _ => Err(format!("Unknown command '\\{}'.", name)),
+        }
+    }
+
+    /// Parses the delimiter after `\left` or `\right`, where "." means none.
+    fn parse_delimiter(&mut self, command: &str) -> result::Result<Option<char>, String> {
+        self.skip_spaces();
+        match self.next() {
+            Some(Token::Char('.')) => Ok(None),
+            Some(Token::Char(c)) if class_of_char(c) == Class::Open ||
+                                    class_of_char(c) == Class::Close ||
+                                    c == '|' || c == '/' => Ok(Some(c)),
+            Some(Token::Command(ref name)) if symbol_for_command(name).map_or(false, |c| {
+                class_of_char(c) == Class::Open || class_of_char(c) == Class::Close || c == '‖'
+            }) => Ok(symbol_for_command(name)),
+            _ => Err(format!("Expected a delimiter after '\\{}'.", command)),
+        }
+    }
+
+    fn parse_environment(&mut self) -> result::Result<Node, String> {
+        let name = self.parse_environment_name()?;
+        let delimiters = match name.as_ref() {
+            "matrix" => None,
+            "pmatrix" => Some(('(', ')')),
+            "bmatrix" => Some(('[', ']')),
+            "Bmatrix" => Some(('{', '}')),
+            "vmatrix" => Some(('|', '|')),
+            "Vmatrix" => Some(('‖', '‖')),
+            _ => return Err(format!("Unknown environment '{}'.", name)),
+        };
+
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        loop {
+            row.push(Node::Group(self.parse_list(None)?));
+            self.skip_spaces();
+            match self.next() {
+                Some(Token::Ampersand) => {}
+                Some(Token::NewRow) => rows.push(mem::replace(&mut row, Vec::new())),
+                Some(Token::Command(ref end)) if end == "end" => break,
+                _ => return Err(format!("Expected '\\end{{{}}}'.", name)),
+            }
+        }
+        // A trailing "\\" does not start another row.
+        if row != vec![Node::Group(Vec::new())] || rows.is_empty() {
+            rows.push(row);
+        }
+
+        if self.parse_environment_name()? != name {
+            return Err(format!("Expected '\\end{{{}}}'.", name))
+        }
+
+        let matrix = Node::Matrix(rows);
+        match delimiters {
+            None => Ok(matrix),
+            Some((open, close)) => Ok(Node::Delimited {
+                open: Some(open),
+                body: vec![matrix],
+                close: Some(close),
+            }),
+        }
+    }
+
+    /// Parses the "{name}" after `\begin` or `\end`.
+    fn parse_environment_name(&mut self) -> result::Result<String, String> {
+        self.expect(Token::LBrace, "'{' after '\\begin' or '\\end'")?;
+        let mut name = String::new();
+        loop {
+            match self.next() {
+                Some(Token::RBrace) => return Ok(name),
+                Some(Token::Char(c)) => name.push(c),
+                _ => return Err("Expected an environment name.".to_string()),
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_handles_scripts_and_fractions() {
+    let nodes = parse(r"x^2 + \frac{a}{b}").unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(nodes[0], Node::Scripts {
+        base: Box::new(Node::Symbol("𝑥".to_string(), Class::Ord)),
+        sub: None,
+        sup: Some(Box::new(Node::Symbol("2".to_string(), Class::Ord))),
+    });
+    assert_eq!(nodes[1], Node::Symbol("+".to_string(), Class::Bin));
+    match nodes[2] {
+        Node::Fraction(..) => {}
+        ref other => panic!("Expected a fraction, found {:?}.", other),
+    }
+}
+
+#[test]
+fn parse_handles_matrices() {
+    let nodes = parse(r"\begin{pmatrix} 1 & 0 \\ 0 & 1 \\ \end{pmatrix}").unwrap();
+    match nodes[0] {
+        Node::Delimited { open: Some('('), ref body, close: Some(')') } => match body[0] {
+            Node::Matrix(ref rows) => {
+                assert_eq!(rows.len(), 2);
+                assert_eq!(rows[0].len(), 2);
+            }
+            ref other => panic!("Expected a matrix, found {:?}.", other),
+        },
+        ref other => panic!("Expected delimiters, found {:?}.", other),
+    }
+}
+
+#[test]
+fn parse_reports_errors() {
+    assert!(parse(r"x^2^3").is_err());
+    assert!(parse(r"\frac{a}").is_err());
+    assert!(parse(r"\foo").is_err());
+    assert!(parse(r"{x").is_err());
+    assert!(parse(r"x}").is_err());
+    assert!(parse(r"a & b").is_err());
+}
+
+/// A laid out part of a formula. The origin is on the baseline at the left.
+pub struct MathBox {
+    pub width: f64,
+    /// The extent above the baseline.
+    pub ascent: f64,
+    /// The extent below the baseline.
+    pub descent: f64,
+    pub elements: Vec<(Vec2, Element)>,
+}
+
+impl MathBox {
+    fn empty() -> MathBox {
+        MathBox {
+            width: 0.0,
+            ascent: 0.0,
+            descent: 0.0,
+            elements: Vec::new(),
+        }
+    }
+
+    /// Places another box with its origin at `x`, raised by `shift_up`.
+    fn place(&mut self, other: MathBox, x: f64, shift_up: f64) {
+        self.width = self.width.max(x + other.width);
+        self.ascent = self.ascent.max(other.ascent + shift_up);
+        self.descent = self.descent.max(other.descent - shift_up);
+        let offset = Vec2::new(x, -shift_up);
+        for (position, element) in other.elements {
+            self.elements.push((position + offset, element));
+        }
+    }
+
+    /// Adds a rectangle between heights `bottom` and `top` above the baseline.
+    fn rule(&mut self, color: Color, x0: f64, x1: f64, bottom: f64, top: f64) {
+        self.width = self.width.max(x1);
+        self.ascent = self.ascent.max(top);
+        self.descent = self.descent.max(-bottom);
+        let rect = FillPolygon {
+            color: color,
+            vertices: vec![
+                Vec2::new(x0, -top),
+                Vec2::new(x1, -top),
+                Vec2::new(x1, -bottom),
+                Vec2::new(x0, -bottom),
+            ],
+        };
+        self.elements.push((Vec2::zero(), Element::FillPolygon(rect)));
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Style {
+    display: bool,
+    /// 0 for the formula itself, 1 for scripts, 2 for scripts of scripts.
+    level: u32,
+    /// Whether superscripts are lowered, as under a fraction bar or root.
+    cramped: bool,
+}
+
+impl Style {
+    fn superscript(self) -> Style {
+        Style { display: false, level: cmp::min(self.level + 1, 2), cramped: self.cramped }
+    }
+
+    fn subscript(self) -> Style {
+        Style { cramped: true, ..self.superscript() }
+    }
+
+    fn numerator(self) -> Style {
+        if self.display {
+            Style { display: false, ..self }
+        } else {
+            self.superscript()
+        }
+    }
+
+    fn denominator(self) -> Style {
+        Style { cramped: true, ..self.numerator() }
+    }
+
+    fn cramped(self) -> Style {
+        Style { cramped: true, ..self }
+    }
+}
+
+/// Returns the class of a node for spacing, or None for explicit space.
+fn class_of(node: &Node) -> Option<Class> {
+    match *node {
+        Node::Symbol(_, class) => Some(class),
+        Node::Operator { .. } => Some(Class::Op),
+        Node::Scripts { ref base, .. } => class_of(base),
+        Node::Fraction(..) | Node::Delimited { .. } | Node::Matrix(..) => Some(Class::Inner),
+        Node::Group(..) | Node::Radical { .. } => Some(Class::Ord),
+        Node::Space(..) => None,
+    }
+}
+
+/// Returns the space between atoms of two classes in 18ths of an em, and
+/// whether the space is also used in scripts.
+fn spacing(left: Class, right: Class) -> (u32, bool) {
+    match (left, right) {
+        (Class::Open, _) => (0, true),
+        (Class::Ord, Class::Op) | (Class::Op, Class::Ord) | (Class::Op, Class::Op) |
+        (Class::Close, Class::Op) | (Class::Inner, Class::Op) => (3, true),
+        (Class::Punct, _) | (Class::Ord, Class::Inner) | (Class::Op, Class::Inner) |
+        (Class::Close, Class::Inner) | (Class::Inner, Class::Ord) | (Class::Inner, Class::Open) |
+        (Class::Inner, Class::Punct) | (Class::Inner, Class::Inner) => (3, false),
+        (Class::Bin, _) | (_, Class::Bin) => (4, false),
+        (Class::Rel, Class::Rel) | (Class::Rel, Class::Close) | (Class::Rel, Class::Punct) => (0, true),
+        (Class::Rel, _) | (_, Class::Rel) => (5, false),
+        _ => (0, true),
+    }
+}
+
+/// Returns the classes of the nodes in a list. A binary operator that does
+/// not stand between two operands, like the minus in "-x", is ordinary.
+fn resolve_classes(nodes: &[Node]) -> Vec<Option<Class>> {
+    let mut classes: Vec<Option<Class>> = nodes.iter().map(class_of).collect();
+    let mut prev = None;
+    for i in 0..classes.len() {
+        if classes[i] == Some(Class::Bin) {
+            let next = classes[i + 1..].iter().cloned().find(|c| c.is_some()).and_then(|c| c);
+            let after_operand = match prev {
+                None | Some(Class::Bin) | Some(Class::Op) | Some(Class::Rel) |
+                Some(Class::Open) | Some(Class::Punct) => false,
+                _ => true,
+            };
+            let before_operand = match next {
+                None | Some(Class::Rel) | Some(Class::Close) | Some(Class::Punct) => false,
+                _ => true,
+            };
+            if !after_operand || !before_operand {
+                classes[i] = Some(Class::Ord);
+            }
+        }
+        if classes[i].is_some() {
+            prev = classes[i];
+        }
+    }
+    classes
+}
+
+#[test]
+fn resolve_classes_makes_unary_minus_ordinary() {
+    let nodes = parse("-x - y").unwrap();
+    let classes = resolve_classes(&nodes);
+    assert_eq!(classes, vec![Some(Class::Ord), Some(Class::Ord), Some(Class::Bin), Some(Class::Ord)]);
+}
+
+/// A glyph as (glyph index, x-advance, x-offset, y-offset) in font units.
+type ShapedGlyph = (u32, i32, i32, i32);
+
+struct Typesetter<'f> {
+    font_map: &'f mut FontMap,
+    font: FontSpec,
+    font_size: f64,
+    color: Color,
+}
+
+/// Typesets a formula in the given font, which must have a MATH table.
+pub fn typeset(font_map: &mut FontMap,
+               font: &FontSpec,
+               font_size: f64,
+               color: Color,
+               display: bool,
+               formula: &str)
+               -> Result<MathBox> {
+    let nodes = match parse(formula) {
+        Ok(nodes) => nodes,
+        Err(msg) => return Err(Error::value(format!("Invalid formula '{}': {}", formula, msg))),
+    };
+
+    if !font_map.get_hb_font(font)?.has_math_data() {
+        let mut fmt = Formatter::new();
+        fmt.print("The font ");
+        fmt.print(font);
+        fmt.print(" has no OpenType MATH table, which is required to typeset math. \
+                   Set 'math_font_family' to a math font, such as \"Latin Modern Math\".");
+        return Err(Error::value(fmt.into_string()))
+    }
+
+    let mut typesetter = Typesetter {
+        font_map: font_map,
+        font: font.clone(),
+        font_size: font_size,
+        color: color,
+    };
+    let style = Style { display: display, level: 0, cramped: false };
+    typesetter.list(&nodes, style)
+}
+
+impl<'f> Typesetter<'f> {
+    /// Returns the font size for a style.
+    fn size(&mut self, style: Style) -> Result<f64> {
+        let percent = match style.level {
+            0 => return Ok(self.font_size),
+            1 => self.raw_constant(MathConstant::ScriptPercentScaleDown)?,
+            _ => self.raw_constant(MathConstant::ScriptScriptPercentScaleDown)?,
+        };
+        // Fonts that do not specify the scale get TeX's default.
+        let percent = match percent {
+            0 if style.level == 1 => 70,
+            0 => 50,
+            p => p,
+        };
+        Ok(self.font_size * percent as f64 / 100.0)
+    }
+
+    fn raw_constant(&mut self, constant: MathConstant) -> Result<i32> {
+        Ok(self.font_map.get_hb_font(&self.font)?.math_constant(constant))
+    }
+
+    /// Returns a length from the MATH table, at the size of the style.
+    fn constant(&mut self, constant: MathConstant, style: Style) -> Result<f64> {
+        let size = self.size(style)?;
+        let raw = self.raw_constant(constant)?;
+        Ok(to_points(raw, size))
+    }
+
+    fn shape(&mut self, text: &str, style: Style) -> Result<Vec<ShapedGlyph>> {
+        // Smaller sizes use the script variants of glyphs, if the font has them.
+        let features: &[&str] = match style.level {
+            0 => &[],
+            1 => &["ssty=1"],
+            _ => &["ssty=2"],
+        };
+        let hb_glyphs = self.font_map.shape(&self.font, Direction::LeftToRight, features, text)?;
+        Ok(hb_glyphs.iter().map(|g| (g.codepoint, g.x_advance, g.x_offset, g.y_offset)).collect())
+    }
+
+    fn glyph_box(&mut self, text: &str, glyphs: &[ShapedGlyph], size: f64) -> Result<MathBox> {
+        let mut result = MathBox::empty();
+        let mut cairo_glyphs = Vec::with_capacity(glyphs.len());
+        let mut x = 0.0;
+        {
+            let hb_font = self.font_map.get_hb_font(&self.font)?;
+            for &(index, x_advance, x_offset, y_offset) in glyphs {
+                let extents = hb_font.glyph_extents(index);
+                let y = to_points(y_offset, size);
+                cairo_glyphs.push(cairo::Glyph::new(index as u64, x + to_points(x_offset, size), -y));
+                result.ascent = result.ascent.max(to_points(extents.y_bearing, size) + y);
+                result.descent = result.descent.max(-to_points(extents.y_bearing + extents.height, size) - y);
+                x += to_points(x_advance, size);
+            }
+        }
+        result.width = x;
+
+        // The text is a single cluster, because stretched glyphs no longer
+        // correspond to individual characters.
+        let num_glyphs = cairo_glyphs.len();
+        let has_text = num_glyphs > 0 && text.len() > 0;
+        let text_elem = Text {
+            color: self.color,
+            font: self.font.clone(),
+            font_size: size,
+            glyphs: cairo_glyphs,
+            text: if has_text { text.to_string() } else { String::new() },
+            clusters: if has_text {
+                vec![cairo::TextCluster::new(text.len(), num_glyphs)]
+            } else {
+                Vec::new()
+            },
+        };
+        result.elements.push((Vec2::zero(), Element::Text(text_elem)));
+        Ok(result)
+    }
+
+    fn symbol(&mut self, text: &str, style: Style) -> Result<MathBox> {
+        let size = self.size(style)?;
+        let glyphs = self.shape(text, style)?;
+        self.glyph_box(text, &glyphs, size)
+    }
+
+    /// Typesets a single-glyph symbol in the smallest vertical variant that
+    /// is at least `target` high, or the largest one if none is.
+    fn stretched(&mut self, text: &str, target: f64, style: Style) -> Result<MathBox> {
+        let size = self.size(style)?;
+        let mut glyphs = self.shape(text, style)?;
+        if glyphs.len() == 1 {
+            let hb_font = self.font_map.get_hb_font(&self.font)?;
+            let variants = hb_font.math_glyph_variants(glyphs[0].0, Direction::TopToBottom);
+            let chosen = variants.iter()
+                                 .find(|&&(_, advance)| to_points(advance, size) >= target)
+                                 .or(variants.last())
+                                 .cloned();
+            if let Some((index, _)) = chosen {
+                glyphs[0] = (index, hb_font.glyph_h_advance(index), 0, 0);
+            }
+        }
+        self.glyph_box(text, &glyphs, size)
+    }
+
+    /// Shifts a box vertically so it is centered on the math axis, the
+    /// height of the fraction bar.
+    fn center_on_axis(&mut self, b: MathBox, style: Style) -> Result<MathBox> {
+        let axis = self.constant(MathConstant::AxisHeight, style)?;
+        let shift_up = axis - (b.ascent - b.descent) * 0.5;
+        let mut result = MathBox::empty();
+        result.place(b, 0.0, shift_up);
+        Ok(result)
+    }
+
+    fn list(&mut self, nodes: &[Node], style: Style) -> Result<MathBox> {
+        let size = self.size(style)?;
+        let classes = resolve_classes(nodes);
+        let mut result = MathBox::empty();
+        let mut x = 0.0;
+        let mut prev = None;
+        for (node, &class) in nodes.iter().zip(classes.iter()) {
+            if let (Some(left), Some(right)) = (prev, class) {
+                let (eighteenths, in_scripts) = spacing(left, right);
+                if style.level == 0 || in_scripts {
+                    x += eighteenths as f64 / 18.0 * size;
+                }
+            }
+            let b = self.node(node, style)?;
+            let width = b.width;
+            result.place(b, x, 0.0);
+            x += width;
+            if class.is_some() {
+                prev = class;
+            }
+        }
+        // Negative space can make the list narrower than its content.
+        result.width = x;
+        Ok(result)
+    }
+
+    fn node(&mut self, node: &Node, style: Style) -> Result<MathBox> {
+        match *node {
+            Node::Symbol(ref text, _) => self.symbol(text, style),
+            Node::Operator { ref text, large, .. } => self.operator(text, large, style),
+            Node::Group(ref nodes) => self.list(nodes, style),
+            Node::Scripts { ref base, ref sub, ref sup } => {
+                self.scripts(base, sub.as_ref().map(|n| &**n), sup.as_ref().map(|n| &**n), style)
+            }
+            Node::Fraction(ref num, ref den) => self.fraction(num, den, style),
+            Node::Radical { ref body, ref degree } => {
+                self.radical(body, degree.as_ref().map(|n| &**n), style)
+            }
+            Node::Delimited { open, ref body, close } => self.delimited(open, body, close, style),
+            Node::Matrix(ref rows) => self.matrix(rows, style),
+            Node::Space(em) => {
+                let mut result = MathBox::empty();
+                result.width = em * self.size(style)?;
+                Ok(result)
+            }
+        }
+    }
+
+    fn operator(&mut self, text: &str, large: bool, style: Style) -> Result<MathBox> {
+        if !large {
+            return self.symbol(text, style)
+        }
+        // In display style, large operators use a larger variant.
+        let b = if style.display {
+            let min_height = self.constant(MathConstant::DisplayOperatorMinHeight, style)?;
+            self.stretched(text, min_height, style)?
+        } else {
+            self.symbol(text, style)?
+        };
+        self.center_on_axis(b, style)
+    }
+
+    fn scripts(&mut self,
+               base: &Node,
+               sub: Option<&Node>,
+               sup: Option<&Node>,
+               style: Style)
+               -> Result<MathBox> {
+        let limits = match *base {
+            Node::Operator { limits, .. } => limits && style.display,
+            _ => false,
+        };
+        let base_box = self.node(base, style)?;
+        let sub_box = match sub {
+            Some(n) => Some(self.node(n, style.subscript())?),
+            None => None,
+        };
+        let sup_box = match sup {
+            Some(n) => Some(self.node(n, style.superscript())?),
+            None => None,
+        };
+        if limits {
+            self.limits(base_box, sub_box, sup_box, style)
+        } else {
+            self.attach_scripts(base_box, sub_box, sup_box, style)
+        }
+    }
+
+    fn attach_scripts(&mut self,
+                      base: MathBox,
+                      sub: Option<MathBox>,
+                      sup: Option<MathBox>,
+                      style: Style)
+                      -> Result<MathBox> {
+        let (base_width, base_ascent, base_descent) = (base.width, base.ascent, base.descent);
+        let mut shift_up = 0.0;
+        if let Some(ref sup) = sup {
+            let shift = if style.cramped {
+                MathConstant::SuperscriptShiftUpCramped
+            } else {
+                MathConstant::SuperscriptShiftUp
+            };
+            shift_up = self.constant(shift, style)?
+                .max(base_ascent - self.constant(MathConstant::SuperscriptBaselineDropMax, style)?)
+                .max(self.constant(MathConstant::SuperscriptBottomMin, style)? + sup.descent);
+        }
+        let mut shift_down = 0.0;
+        if let Some(ref sub) = sub {
+            shift_down = self.constant(MathConstant::SubscriptShiftDown, style)?
+                .max(base_descent + self.constant(MathConstant::SubscriptBaselineDropMin, style)?)
+                .max(sub.ascent - self.constant(MathConstant::SubscriptTopMax, style)?);
+        }
+        if let (&Some(ref sub), &Some(ref sup)) = (&sub, &sup) {
+            let gap = (shift_up - sup.descent) - (sub.ascent - shift_down);
+            let min_gap = self.constant(MathConstant::SubSuperscriptGapMin, style)?;
+            if gap < min_gap {
+                shift_down += min_gap - gap;
+            }
+        }
+
+        let space_after = self.constant(MathConstant::SpaceAfterScript, style)?;
+        let mut result = MathBox::empty();
+        result.place(base, 0.0, 0.0);
+        let mut width = base_width;
+        if let Some(sup) = sup {
+            width = width.max(base_width + sup.width);
+            result.place(sup, base_width, shift_up);
+        }
+        if let Some(sub) = sub {
+            width = width.max(base_width + sub.width);
+            result.place(sub, base_width, -shift_down);
+        }
+        result.width = width + space_after;
+        Ok(result)
+    }
+
+    /// Places scripts centered above and below the base, as for a sum in
+    /// display style.
+    fn limits(&mut self,
+              base: MathBox,
+              sub: Option<MathBox>,
+              sup: Option<MathBox>,
+              style: Style)
+              -> Result<MathBox> {
+        let mut width = base.width;
+        if let Some(ref sub) = sub { width = width.max(sub.width); }
+        if let Some(ref sup) = sup { width = width.max(sup.width); }
+        let (base_ascent, base_descent) = (base.ascent, base.descent);
+        let mut result = MathBox::empty();
+        let base_x = (width - base.width) * 0.5;
+        result.place(base, base_x, 0.0);
+        if let Some(sup) = sup {
+            let gap = self.constant(MathConstant::UpperLimitGapMin, style)?;
+            let rise = self.constant(MathConstant::UpperLimitBaselineRiseMin, style)?;
+            let shift_up = base_ascent + (gap + sup.descent).max(rise);
+            let x = (width - sup.width) * 0.5;
+            result.place(sup, x, shift_up);
+        }
+        if let Some(sub) = sub {
+            let gap = self.constant(MathConstant::LowerLimitGapMin, style)?;
+            let drop = self.constant(MathConstant::LowerLimitBaselineDropMin, style)?;
+            let shift_down = base_descent + (gap + sub.ascent).max(drop);
+            let x = (width - sub.width) * 0.5;
+            result.place(sub, x, -shift_down);
+        }
+        Ok(result)
+    }
+
+    fn fraction(&mut self, num: &Node, den: &Node, style: Style) -> Result<MathBox> {
+        let num = self.node(num, style.numerator())?;
+        let den = self.node(den, style.denominator())?;
+
+        let (shift_up, shift_down, num_gap, den_gap) = if style.display {
+            (MathConstant::FractionNumeratorDisplayStyleShiftUp,
+             MathConstant::FractionDenominatorDisplayStyleShiftDown,
+             MathConstant::FractionNumDisplayStyleGapMin,
+             MathConstant::FractionDenomDisplayStyleGapMin)
+        } else {
+            (MathConstant::FractionNumeratorShiftUp,
+             MathConstant::FractionDenominatorShiftDown,
+             MathConstant::FractionNumeratorGapMin,
+             MathConstant::FractionDenominatorGapMin)
+        };
+        let axis = self.constant(MathConstant::AxisHeight, style)?;
+        let thickness = self.constant(MathConstant::FractionRuleThickness, style)?;
+        let shift_up = self.constant(shift_up, style)?
+            .max(axis + thickness * 0.5 + self.constant(num_gap, style)? + num.descent);
+        let shift_down = self.constant(shift_down, style)?
+            .max(den.ascent - (axis - thickness * 0.5) + self.constant(den_gap, style)?);
+
+        // Leave a bit of space next to the bar, like TeX's null delimiters.
+        let pad = 0.1 * self.size(style)?;
+        let width = num.width.max(den.width);
+        let mut result = MathBox::empty();
+        let num_x = pad + (width - num.width) * 0.5;
+        let den_x = pad + (width - den.width) * 0.5;
+        result.place(num, num_x, shift_up);
+        result.place(den, den_x, -shift_down);
+        result.rule(self.color, pad, pad + width, axis - thickness * 0.5, axis + thickness * 0.5);
+        result.width = width + 2.0 * pad;
+        Ok(result)
+    }
+
+    fn radical(&mut self, body: &Node, degree: Option<&Node>, style: Style) -> Result<MathBox> {
+        let body = self.node(body, style.cramped())?;
+        let gap = if style.display {
+            self.constant(MathConstant::RadicalDisplayStyleVerticalGap, style)?
+        } else {
+            self.constant(MathConstant::RadicalVerticalGap, style)?
+        };
+        let thickness = self.constant(MathConstant::RadicalRuleThickness, style)?;
+        let extra_ascender = self.constant(MathConstant::RadicalExtraAscender, style)?;
+
+        let target = body.ascent + body.descent + gap + thickness;
+        let sign = self.stretched("√", target, style)?;
+        // If the sign is taller than needed, divide the excess over the gap.
+        let sign_height = sign.ascent + sign.descent;
+        let gap = gap + (sign_height - target).max(0.0) * 0.5;
+        let rule_top = body.ascent + gap + thickness;
+        let sign_shift = rule_top - sign.ascent;
+
+        let mut result = MathBox::empty();
+        let mut sign_x = 0.0;
+        if let Some(degree) = degree {
+            let degree_style = Style { display: false, level: 2, cramped: true };
+            let degree = self.node(degree, degree_style)?;
+            let kern_before = self.constant(MathConstant::RadicalKernBeforeDegree, style)?;
+            let kern_after = self.constant(MathConstant::RadicalKernAfterDegree, style)?;
+            let raise = self.raw_constant(MathConstant::RadicalDegreeBottomRaisePercent)? as f64 / 100.0;
+            let degree_shift = sign_shift - sign.descent + raise * sign_height + degree.descent;
+            sign_x = (kern_before + degree.width + kern_after).max(0.0);
+            result.place(degree, kern_before, degree_shift);
+        }
+
+        let body_x = sign_x + sign.width;
+        let body_width = body.width;
+        result.place(sign, sign_x, sign_shift);
+        result.rule(self.color, body_x, body_x + body_width, rule_top - thickness, rule_top);
+        result.place(body, body_x, 0.0);
+        result.ascent = result.ascent.max(rule_top + extra_ascender);
+        Ok(result)
+    }
+
+    fn delimited(&mut self,
+                 open: Option<char>,
+                 body: &[Node],
+                 close: Option<char>,
+                 style: Style)
+                 -> Result<MathBox> {
+        let body = self.list(body, style)?;
+        // The delimiters are symmetric around the axis, and cover the body.
+        let axis = self.constant(MathConstant::AxisHeight, style)?;
+        let target = 2.0 * (body.ascent - axis).max(body.descent + axis);
+
+        let mut result = MathBox::empty();
+        let mut x = 0.0;
+        if let Some(c) = open {
+            let delimiter = self.stretched(&c.to_string(), target, style)?;
+            let delimiter = self.center_on_axis(delimiter, style)?;
+            let width = delimiter.width;
+            result.place(delimiter, x, 0.0);
+            x += width;
+        }
+        let body_width = body.width;
+        result.place(body, x, 0.0);
+        x += body_width;
+        if let Some(c) = close {
+            let delimiter = self.stretched(&c.to_string(), target, style)?;
+            let delimiter = self.center_on_axis(delimiter, style)?;
+            let width = delimiter.width;
+            result.place(delimiter, x, 0.0);
+            x += width;
+        }
+        result.width = x;
+        Ok(result)
+    }
+
+    fn matrix(&mut self, rows: &[Vec<Node>], style: Style) -> Result<MathBox> {
+        let cell_style = Style { display: false, ..style };
+        let size = self.size(style)?;
+        let column_gap = size;
+        let row_gap = 0.25 * size;
+
+        let mut cells = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut row_cells = Vec::with_capacity(row.len());
+            for cell in row {
+                row_cells.push(self.node(cell, cell_style)?);
+            }
+            cells.push(row_cells);
+        }
+
+        let columns = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut col_widths = vec![0.0_f64; columns];
+        for row in &cells {
+            for (c, cell) in row.iter().enumerate() {
+                col_widths[c] = col_widths[c].max(cell.width);
+            }
+        }
+
+        // Lay out the rows from the top down, with the first baseline at 0.
+        let mut grid = MathBox::empty();
+        let mut baseline = 0.0;
+        for (r, row) in cells.into_iter().enumerate() {
+            let ascent = row.iter().fold(0.0, |a: f64, cell| a.max(cell.ascent));
+            let descent = row.iter().fold(0.0, |d: f64, cell| d.max(cell.descent));
+            if r > 0 {
+                baseline -= ascent + row_gap;
+            }
+            let mut x = 0.0;
+            for (c, cell) in row.into_iter().enumerate() {
+                let cell_x = x + (col_widths[c] - cell.width) * 0.5;
+                grid.place(cell, cell_x, baseline);
+                x += col_widths[c] + column_gap;
+            }
+            baseline -= descent;
+        }
+        grid.width = col_widths.iter().sum::<f64>() + column_gap * (cmp::max(columns, 1) - 1) as f64;
+
+        self.center_on_axis(grid, style)
+    }
+}
+
+/// Converts a length from Harfbuzz, in units of 1/1000 em because of the
+/// character size that fonts are loaded at, to a length at `size`.
+fn to_points(units: i32, size: f64) -> f64 {
+    units as f64 / 1000.0 * size
+}
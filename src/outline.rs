@@ -0,0 +1,154 @@
+// Pris -- A language for designing slides
+// Copyright 2017 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module converts text into vector paths, using the glyph outlines
+//! that FreeType provides.
+
+use freetype;
+use std::slice;
+
+use elements::{FillPath, PathCommand, Text, Vec2};
+use error::Result;
+use runtime::FontMap;
+
+/// Converts the glyphs of a text element into a filled path.
+pub fn outline_text(font_map: &mut FontMap, text: &Text) -> Result<FillPath> {
+    let ft_face = font_map.get(&text.font)?;
+
+    // The character size of the face is 1000/64 points per em, so the scaled
+    // outline coordinates, which FreeType gives in 26.6 fixed point, are in
+    // units of 1/1000 em.
+    let size_factor = text.font_size / 1000.0;
+
+    let mut commands = Vec::new();
+    for glyph in &text.glyphs {
+        // Glyphs that fail to load are not drawn by Cairo either. Hinting
+        // would fit the outline to the pixel grid of the small em we load at.
+        if ft_face.load_glyph(glyph.index() as u32, freetype::face::NO_HINTING).is_err() {
+            continue
+        }
+
+        let (points, tags, contour_ends) = unsafe {
+            let outline = &(*ft_face.raw_mut().glyph).outline;
+            let points = slice::from_raw_parts(outline.points, outline.n_points as usize);
+            let tags = slice::from_raw_parts(outline.tags, outline.n_points as usize);
+            let ends = slice::from_raw_parts(outline.contours, outline.n_contours as usize);
+
+            // FreeType has the y-axis pointing up, whereas ours points down.
+            let points: Vec<Vec2> = points.iter().map(|p| {
+                Vec2::new(glyph.x() + p.x as f64 * size_factor,
+                          glyph.y() - p.y as f64 * size_factor)
+            }).collect();
+            let tags: Vec<u8> = tags.iter().map(|&t| t as u8).collect();
+            let ends: Vec<usize> = ends.iter().map(|&e| e as usize).collect();
+            (points, tags, ends)
+        };
+
+        let mut start = 0;
+        for end in contour_ends {
+            decompose_contour(&points[start..end + 1], &tags[start..end + 1], &mut commands);
+            start = end + 1;
+        }
+    }
+
+    let path = FillPath {
+        color: text.color,
+        commands: commands,
+    };
+    Ok(path)
+}
+
+/// Converts a quadratic Bézier curve into an equivalent cubic one.
+fn quad_to_cubic(from: Vec2, control: Vec2, to: Vec2) -> PathCommand {
+    let c1 = from + (control - from) * (2.0 / 3.0);
+    let c2 = to + (control - to) * (2.0 / 3.0);
+    PathCommand::CurveTo(c1, c2, to)
+}
+
+/// Converts a single closed contour of a FreeType outline into path commands.
+///
+/// Bit 0 of a tag indicates that the point is on the curve. Points off the
+/// curve are control points of a cubic curve if bit 1 is set, and of a
+/// quadratic curve otherwise. Between two consecutive quadratic control
+/// points, there is an implied on-curve point halfway.
+fn decompose_contour(points: &[Vec2], tags: &[u8], commands: &mut Vec<PathCommand>) {
+    let n = points.len();
+    if n == 0 {
+        return
+    }
+
+    let is_on = |i: usize| tags[i] & 1 != 0;
+    let is_cubic = |i: usize| tags[i] & 2 != 0;
+
+    // Start at a point on the curve. If there is none, the contour consists of
+    // quadratic control points only, and it starts at an implied point.
+    let (start, order): (Vec2, Vec<usize>) = match (0..n).find(|&i| is_on(i)) {
+        Some(s) => (points[s], (1..n).map(|i| (s + i) % n).collect()),
+        None => ((points[n - 1] + points[0]) * 0.5, (0..n).collect()),
+    };
+
+    commands.push(PathCommand::MoveTo(start));
+    let mut current = start;
+    let mut quad_control: Option<Vec2> = None;
+    let mut cubic_controls: Vec<Vec2> = Vec::new();
+
+    // Visit all points, and finally the start point again to close the curve.
+    for i in order.into_iter().map(Some).chain(Some(None)) {
+        let (p, on) = match i {
+            Some(i) => (points[i], is_on(i)),
+            None => (start, true),
+        };
+        if on {
+            if let Some(c) = quad_control.take() {
+                commands.push(quad_to_cubic(current, c, p));
+            } else if cubic_controls.len() == 2 {
+                commands.push(PathCommand::CurveTo(cubic_controls[0], cubic_controls[1], p));
+            } else {
+                commands.push(PathCommand::LineTo(p));
+            }
+            cubic_controls.clear();
+            current = p;
+        } else if is_cubic(i.unwrap()) {
+            cubic_controls.push(p);
+        } else {
+            if let Some(c) = quad_control {
+                let mid = (c + p) * 0.5;
+                commands.push(quad_to_cubic(current, c, mid));
+                current = mid;
+            }
+            quad_control = Some(p);
+        }
+    }
+
+    commands.push(PathCommand::Close);
+}
+
+#[test]
+fn decompose_contour_handles_lines_and_implied_points() {
+    let points = [
+        Vec2::new(0.0, 0.0),
+        Vec2::new(2.0, 0.0),
+        Vec2::new(2.0, 2.0),
+        Vec2::new(0.0, 2.0),
+    ];
+    // A square with two straight sides, and two quadratic control points
+    // with an implied on-curve point between them.
+    let tags = [1, 1, 0, 0];
+    let mut commands = Vec::new();
+    decompose_contour(&points, &tags, &mut commands);
+
+    let ends: Vec<(f64, f64)> = commands.iter().filter_map(|c| match *c {
+        PathCommand::MoveTo(p) | PathCommand::LineTo(p) => Some((p.x, p.y)),
+        PathCommand::CurveTo(_, _, p) => Some((p.x, p.y)),
+        PathCommand::Close => None,
+    }).collect();
+    assert_eq!(&ends, &[(0.0, 0.0), (2.0, 0.0), (1.0, 2.0), (0.0, 0.0)]);
+    match commands.last() {
+        Some(&PathCommand::Close) => {}
+        _ => panic!("Expected the contour to be closed."),
+    }
+}
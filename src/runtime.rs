@@ -6,17 +6,22 @@
 // of the License is available in the root of the repository.
 
 use freetype;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-use std::rc::Rc;
+use std::os::raw::c_ulong;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
 
 use ast::{FnDef, Idents};
 use builtins;
-use elements::{Color, Element, PlacedElement, Vec2};
-use error::{Error, Result};
+use cairo;
+use elements::{Color, Element, FontSpec, PlacedElement, Slant, Vec2};
+use error::{Error, Result, Warning};
 use fontconfig;
+use harfbuzz;
 use pretty::{Formatter, Print};
 use types::{LenDim, ValType};
+use variations;
 
 // TODO: Put that somewhere else.
 use interpreter::ExprInterpreter;
@@ -28,8 +33,28 @@ pub enum Val<'a> {
     Col(Color),
     Coord(f64, f64, LenDim),
     Frame(Rc<Frame<'a>>),
-    FnExtrin(&'a FnDef<'a>),
+    FnExtrin(Rc<Closure<'a>>),
     FnIntrin(Builtin),
+    List(Vec<Val<'a>>),
+    /// Named fields, in the order in which they were defined.
+    Record(Vec<(String, Val<'a>)>),
+}
+
+/// A user-defined function, together with the environment it was defined in.
+pub struct Closure<'a> {
+    pub def: &'a FnDef<'a>,
+
+    /// The variables at the point where the function was defined.
+    bindings: HashMap<&'a str, Val<'a>>,
+
+    /// The variables assigned in the defining block, including those assigned
+    /// after the function. The block holds the function in turn, so this is
+    /// a weak reference, to not make a cycle.
+    scope: Weak<RefCell<HashMap<&'a str, Val<'a>>>>,
+
+    /// The variables that the defining block had assigned before the
+    /// function, for when the block is gone.
+    assigned: Vec<&'a str>,
 }
 
 #[derive(Clone)]
@@ -52,6 +77,11 @@ pub struct Frame<'a> {
 #[derive(Clone)]
 pub struct Env<'a> {
     bindings: HashMap<&'a str, Val<'a>>,
+
+    /// The variables assigned so far in the block that this environment
+    /// belongs to. Functions defined in the block share it, so they can refer
+    /// to themselves and to variables assigned after them.
+    scope: Rc<RefCell<HashMap<&'a str, Val<'a>>>>,
 }
 
 #[derive(Clone)]
@@ -70,14 +100,35 @@ pub struct Subframe {
 /// A "builtin" function is a function that takes an environment and a vector of
 /// arguments, and produces a new value. We make a wrapper type to be able to
 /// implement a no-op clone on it.
-pub struct Builtin(pub for<'i, 'a> fn(&mut ExprInterpreter<'i, 'a>, Vec<Val<'a>>) -> Result<Val<'a>>);
+pub struct Builtin(pub for<'i, 'a> fn(&mut ExprInterpreter<'i, 'a>, Vec<Val<'a>>, NamedArgs<'a>) -> Result<Val<'a>>);
+
+/// Arguments passed by name, in the order in which they appear in the call.
+pub type NamedArgs<'a> = Vec<(&'a str, Val<'a>)>;
 
-/// Keeps track of loaded Freetype fonts, indexed by (family name, style) pairs.
+/// Keeps track of loaded Freetype fonts, indexed by font spec.
+///
+/// Also caches the Harfbuzz and Cairo fonts derived from the Freetype faces,
+/// and the result of shaping, because slides tend to repeat the same strings.
 pub struct FontMap {
     freetype: freetype::Library,
-    fonts: HashMap<(String, String), freetype::Face<'static>>,
+    fonts: HashMap<FontSpec, freetype::Face<'static>>,
+    hb_fonts: HashMap<FontSpec, harfbuzz::Font>,
+    cr_faces: HashMap<FontSpec, cairo::FontFace>,
+
+    /// Shaped glyphs by font, direction, OpenType features, and text. This
+    /// holds at most `MAX_SHAPINGS` entries.
+    shapings: HashMap<(FontSpec, harfbuzz::Direction, Vec<String>, String), Vec<harfbuzz::Glyph>>,
+
+    /// Directories that have been added to the Fontconfig configuration.
+    font_dirs: Vec<PathBuf>,
+
+    /// Whether to treat font warnings as errors.
+    strict: bool,
 }
 
+/// The number of shaped strings to cache, after which the cache is cleared.
+const MAX_SHAPINGS: usize = 4096;
+
 impl<'a> Val<'a> {
     pub fn get_type(&self) -> ValType {
         match *self {
@@ -88,6 +139,8 @@ impl<'a> Val<'a> {
             Val::Frame(..) => ValType::Frame,
             Val::FnExtrin(..) => ValType::Fn,
             Val::FnIntrin(..) => ValType::Fn,
+            Val::List(..) => ValType::List,
+            Val::Record(..) => ValType::Record,
         }
     }
 }
@@ -127,11 +180,28 @@ impl<'a> Frame<'a> {
 
         // When the identifier matches one of the read-only fields, we compute
         // it here on the fly.
+        let bb = &self.bounding_box;
+        let (x0, x1, y0, y1) = (bb.x, bb.x + bb.width, bb.y, bb.y + bb.height);
+        let (xm, ym) = (bb.x + bb.width * 0.5, bb.y + bb.height * 0.5);
         let ro_field = match idents.0[0] {
-            "width" => Some(Val::Num(self.bounding_box.width, 1)),
-            "height" => Some(Val::Num(self.bounding_box.height, 1)),
-            "size" => Some(Val::Coord(self.bounding_box.width, self.bounding_box.height, 1)),
-            "offset" => Some(Val::Coord(self.bounding_box.x, self.bounding_box.y, 1)),
+            "width" => Some(Val::Num(bb.width, 1)),
+            "height" => Some(Val::Num(bb.height, 1)),
+            "size" => Some(Val::Coord(bb.width, bb.height, 1)),
+            "offset" => Some(Val::Coord(bb.x, bb.y, 1)),
+            // Points on the bounding box, in the coordinates of the frame.
+            "top_left" => Some(Val::Coord(x0, y0, 1)),
+            "top" => Some(Val::Coord(xm, y0, 1)),
+            "top_right" => Some(Val::Coord(x1, y0, 1)),
+            "left" => Some(Val::Coord(x0, ym, 1)),
+            "center" => Some(Val::Coord(xm, ym, 1)),
+            "right" => Some(Val::Coord(x1, ym, 1)),
+            "bottom_left" => Some(Val::Coord(x0, y1, 1)),
+            "bottom" => Some(Val::Coord(xm, y1, 1)),
+            "bottom_right" => Some(Val::Coord(x1, y1, 1)),
+            // The left end of the baseline, which for text is the baseline of
+            // the first line, and the point where adjoined frames attach.
+            "baseline" => Some(Val::Coord(x0, 0.0, 1)),
+            "anchor" => Some(Val::Coord(self.anchor.x, self.anchor.y, 1)),
             _ => None
         };
 
@@ -147,8 +217,9 @@ impl<'a> Frame<'a> {
         self.env.lookup(idents)
     }
 
-    pub fn put_in_env(&mut self, ident: &'a str, val: Val<'a>) {
-        self.env.put(ident, val);
+    /// Assign a variable in the block that this frame is the result of.
+    pub fn assign_in_env(&mut self, ident: &'a str, val: Val<'a>) {
+        self.env.assign(ident, val);
     }
 
     pub fn get_subframes(&self) -> &[Subframe] {
@@ -163,6 +234,32 @@ impl<'a> Frame<'a> {
         self.subframes.push(subframe);
     }
 
+    /// Copy the elements of another frame onto this one, subframe by subframe,
+    /// and extend the bounding box to include the other frame.
+    ///
+    /// If the other frame has more subframes, subframes are added.
+    pub fn place_frame(&mut self, frame: &Frame<'a>, offset: Vec2) {
+        for (i, sf) in frame.get_subframes().iter().enumerate() {
+            if self.subframes.len() <= i {
+                self.subframes.push(Subframe::new());
+            }
+            let subframe = &mut self.subframes[i];
+            for pe in sf.get_elements() {
+                subframe.place_element(offset + pe.position, pe.element.clone());
+            }
+        }
+        self.union_bounding_box(&frame.get_bounding_box().offset(offset));
+    }
+
+    /// Returns a copy of the frame, with the origin moved to `origin`, a point
+    /// in the coordinates of this frame.
+    pub fn with_origin(&self, origin: Vec2) -> Frame<'a> {
+        let mut frame = Frame::from_env(self.env.clone());
+        frame.place_frame(self, Vec2::zero() - origin);
+        frame.set_anchor(self.anchor - origin);
+        frame
+    }
+
     pub fn place_element_on_last_subframe(&mut self, position: Vec2, elem: Element) {
         if self.subframes.len() == 0 {
             self.subframes.push(Subframe::new());
@@ -189,55 +286,175 @@ impl<'a> Frame<'a> {
     }
 }
 
+impl<'a> Closure<'a> {
+    pub fn new(def: &'a FnDef<'a>, env: &Env<'a>) -> Closure<'a> {
+        Closure {
+            def: def,
+            bindings: env.bindings.clone(),
+            scope: Rc::downgrade(&env.scope),
+            assigned: env.scope.borrow().keys().cloned().collect(),
+        }
+    }
+
+    /// Returns the environment to evaluate the body of the function in, when
+    /// it is called from the given environment.
+    ///
+    /// Variables are looked up in the block where the function was defined,
+    /// at the time of the call. Therefore a function can call itself, and
+    /// helpers that are assigned later in the same block. After the block
+    /// has been evaluated, the function sees the variables as they were when
+    /// it was defined.
+    ///
+    /// Style variables that the defining block does not assign have the value
+    /// that they have at the call site. These are all variables that Pris
+    /// defines by default, apart from the builtin functions: `color` and
+    /// `font_size`, but also settings such as `chart_colors` and
+    /// `math_font_family`.
+    pub fn env_for_call(&self, caller: &Env<'a>) -> Env<'a> {
+        let mut env = Env {
+            bindings: self.bindings.clone(),
+            scope: Rc::new(RefCell::new(HashMap::new())),
+        };
+        for &name in STYLE_VARS {
+            if let Some(val) = caller.bindings.get(name) {
+                env.bindings.insert(name, val.clone());
+            }
+        }
+        match self.scope.upgrade() {
+            Some(scope) => {
+                for (&name, val) in scope.borrow().iter() {
+                    env.bindings.insert(name, val.clone());
+                }
+            }
+            None => {
+                for &name in &self.assigned {
+                    env.bindings.insert(name, self.bindings[name].clone());
+                }
+            }
+        }
+        env
+    }
+}
+
 impl<'a> Env<'a> {
     pub fn new() -> Env<'a> {
         let mut bindings = HashMap::new();
         // Default font size is 0.1h.
         bindings.insert("font_size", Val::Num(108.0, 1));
-        // The default font is "sans roman", which is usually DejaVu Sans Book.
+        // The default font is "sans" at regular weight, which is usually
+        // DejaVu Sans Book.
         bindings.insert("font_family", Val::Str("sans".to_string()));
-        bindings.insert("font_style", Val::Str("roman".to_string()));
+        bindings.insert("font_weight", Val::Num(400.0, 0));
+        bindings.insert("font_slant", Val::Str("roman".to_string()));
+        bindings.insert("font_stretch", Val::Str("normal".to_string()));
+        // Coordinates for variable fonts, e.g. "wght=650, opsz=24".
+        bindings.insert("font_variations", Val::Str("".to_string()));
+        // Families to try first when the font lacks a glyph, before asking
+        // Fontconfig for any font that has it.
+        bindings.insert("font_fallback", Val::List(Vec::new()));
+        // Directories with fonts to use in addition to the installed fonts.
+        bindings.insert("font_dirs", Val::List(Vec::new()));
         bindings.insert("fill_rectangle", Val::FnIntrin(Builtin(builtins::fill_rectangle)));
         bindings.insert("text_align", Val::Str("left".to_string()));
+        // Bullet lists: the marker and numbering (either one for all levels,
+        // or a list with one per level), the indent per level, extra space
+        // between items, and whether to reveal the items one subframe at a
+        // time.
+        bindings.insert("bullet", Val::Str("•".to_string()));
+        bindings.insert("bullet_style", Val::Str("bullet".to_string()));
+        bindings.insert("bullet_indent", Val::Num(86.4, 1));
+        bindings.insert("bullet_spacing", Val::Num(0.0, 1));
+        bindings.insert("bullet_reveal", Val::Str("all".to_string()));
+        // Code blocks: the font, the color theme, and which lines to number
+        // and highlight.
+        bindings.insert("code_font_family", Val::Str("monospace".to_string()));
+        bindings.insert("code_theme", Val::Str("light".to_string()));
+        bindings.insert("code_line_numbers", Val::Str("off".to_string()));
+        bindings.insert("code_highlight_lines", Val::List(Vec::new()));
+        // Formulas: the font, which must have an OpenType MATH table, and
+        // whether to lay out as in running text ("inline") or on its own.
+        bindings.insert("math_font_family", Val::Str("Latin Modern Math".to_string()));
+        bindings.insert("math_style", Val::Str("inline".to_string()));
+        // Charts: the size of the plot area, colors of the series (in order,
+        // repeating), the color of gridlines or "none", and the lines and
+        // markers for the data.
+        bindings.insert("chart_size", Val::Coord(1152.0, 540.0, 1));
+        bindings.insert("chart_colors", Val::List(vec![
+            Val::Col(Color::new(0.306, 0.475, 0.655)), // #4e79a7
+            Val::Col(Color::new(0.949, 0.557, 0.169)), // #f28e2b
+            Val::Col(Color::new(0.882, 0.341, 0.349)), // #e15759
+            Val::Col(Color::new(0.463, 0.718, 0.698)), // #76b7b2
+            Val::Col(Color::new(0.349, 0.631, 0.310)), // #59a14f
+            Val::Col(Color::new(0.929, 0.788, 0.282)), // #edc948
+        ]));
+        bindings.insert("chart_grid_color", Val::Col(Color::new(0.867, 0.867, 0.867)));
+        bindings.insert("chart_line_width", Val::Num(4.0, 1));
+        bindings.insert("chart_marker_size", Val::Num(16.0, 1));
+        bindings.insert("writing_mode", Val::Str("horizontal".to_string()));
         bindings.insert("line_height", Val::Num(128.0, 1));
+        // Extra space between letters and after spaces, and whether to kern.
+        bindings.insert("letter_spacing", Val::Num(0.0, 1));
+        bindings.insert("word_spacing", Val::Num(0.0, 1));
+        bindings.insert("kerning", Val::Str("on".to_string()));
+        // Lines through the text, and a color to highlight the text with.
+        bindings.insert("text_decoration", Val::Str("none".to_string()));
+        bindings.insert("text_background", Val::Str("none".to_string()));
         bindings.insert("line_width", Val::Num(10.8, 1));
         bindings.insert("color", Val::Col(Color::new(0.0, 0.0, 0.0)));
+        bindings.insert("abs", Val::FnIntrin(Builtin(builtins::abs)));
+        bindings.insert("align", Val::FnIntrin(Builtin(builtins::align)));
+        bindings.insert("bar_chart", Val::FnIntrin(Builtin(builtins::bar_chart)));
+        bindings.insert("box", Val::FnIntrin(Builtin(builtins::box_frame)));
+        bindings.insert("bullets", Val::FnIntrin(Builtin(builtins::bullets)));
+        bindings.insert("code", Val::FnIntrin(Builtin(builtins::code)));
+        bindings.insert("cos", Val::FnIntrin(Builtin(builtins::cos)));
         bindings.insert("fit", Val::FnIntrin(Builtin(builtins::fit)));
+        bindings.insert("get", Val::FnIntrin(Builtin(builtins::get)));
         bindings.insert("image", Val::FnIntrin(Builtin(builtins::image)));
+        bindings.insert("keys", Val::FnIntrin(Builtin(builtins::keys)));
+        bindings.insert("lerp", Val::FnIntrin(Builtin(builtins::lerp)));
         bindings.insert("line", Val::FnIntrin(Builtin(builtins::line)));
+        bindings.insert("line_chart", Val::FnIntrin(Builtin(builtins::line_chart)));
+        bindings.insert("math", Val::FnIntrin(Builtin(builtins::math)));
+        bindings.insert("max", Val::FnIntrin(Builtin(builtins::max)));
+        bindings.insert("merge", Val::FnIntrin(Builtin(builtins::merge)));
+        bindings.insert("min", Val::FnIntrin(Builtin(builtins::min)));
+        bindings.insert("pad", Val::FnIntrin(Builtin(builtins::pad)));
+        bindings.insert("outline", Val::FnIntrin(Builtin(builtins::outline)));
+        bindings.insert("read_csv", Val::FnIntrin(Builtin(builtins::read_csv)));
+        bindings.insert("read_json", Val::FnIntrin(Builtin(builtins::read_json)));
+        bindings.insert("round", Val::FnIntrin(Builtin(builtins::round)));
+        bindings.insert("scatter", Val::FnIntrin(Builtin(builtins::scatter)));
+        bindings.insert("sin", Val::FnIntrin(Builtin(builtins::sin)));
+        bindings.insert("sqrt", Val::FnIntrin(Builtin(builtins::sqrt)));
         bindings.insert("str", Val::FnIntrin(Builtin(builtins::str)));
         bindings.insert("t", Val::FnIntrin(Builtin(builtins::t)));
+        bindings.insert("table", Val::FnIntrin(Builtin(builtins::table)));
+        bindings.insert("text_on_path", Val::FnIntrin(Builtin(builtins::text_on_path)));
         bindings.insert("glyph", Val::FnIntrin(Builtin(builtins::glyph)));
-        Env { bindings: bindings }
+        bindings.insert("grid", Val::FnIntrin(Builtin(builtins::grid)));
+        bindings.insert("hstack", Val::FnIntrin(Builtin(builtins::hstack)));
+        bindings.insert("vstack", Val::FnIntrin(Builtin(builtins::vstack)));
+        bindings.insert("zstack", Val::FnIntrin(Builtin(builtins::zstack)));
+        Env {
+            bindings: bindings,
+            scope: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Returns a copy of the environment for a new block, in which
+    /// assignments do not affect this environment.
+    pub fn for_block(&self) -> Env<'a> {
+        Env {
+            bindings: self.bindings.clone(),
+            scope: Rc::new(RefCell::new(HashMap::new())),
+        }
     }
 
     pub fn lookup(&self, idents: &Idents<'a>) -> Result<Val<'a>> {
         assert!(idents.0.len() > 0);
         match self.bindings.get(idents.0[0]) {
-            Some(val) => {
-                if idents.0.len() == 1 {
-                    Ok(val.clone())
-                } else {
-                    match *val {
-                        Val::Frame(ref frame) => {
-                            let mut more = idents.0.clone();
-                            more.remove(0);
-                            frame.lookup(&Idents(more))
-                        }
-                        _ => {
-                            let mut f = Formatter::new();
-                            f.print("Type error while reading variable '");
-                            f.print(idents);
-                            f.print("'. Cannot look up '");
-                            f.print(idents.0[1]);
-                            f.print("' in '");
-                            f.print(idents.0[0]);
-                            f.print("' because it is not a frame.");
-                            Err(Error::Other(f.into_string()))
-                        }
-                    }
-                }
-            }
+            Some(val) => lookup_path(val, idents, 1),
             None => Err(Error::Other(format!("Variable '{}' does not exist.", idents.0[0]))),
         }
     }
@@ -270,11 +487,41 @@ impl<'a> Env<'a> {
         }
     }
 
+    pub fn lookup_str_list(&self, idents: &Idents<'a>) -> Result<Vec<String>> {
+        let vals = match self.lookup(idents)? {
+            Val::List(vals) => vals,
+            other => return Err(Error::var_type(idents, ValType::List, other.get_type())),
+        };
+        let mut strings = Vec::with_capacity(vals.len());
+        for val in vals {
+            match val {
+                Val::Str(s) => strings.push(s),
+                other => {
+                    let mut f = Formatter::new();
+                    f.print("Expected '");
+                    f.print(idents);
+                    f.print("' to contain only values of type 'str', but found '");
+                    f.print(other.get_type());
+                    f.print("'.");
+                    return Err(Error::value(f.into_string()))
+                }
+            }
+        }
+        Ok(strings)
+    }
+
     pub fn put(&mut self, ident: &'a str, val: Val<'a>) {
         // TODO: Validate types for known variables, disallow assigning to
         // constants.
         self.bindings.insert(ident, val);
     }
+
+    /// Puts a variable, and records it as assigned in the block, so functions
+    /// defined earlier in the block can see it.
+    pub fn assign(&mut self, ident: &'a str, val: Val<'a>) {
+        self.scope.borrow_mut().insert(ident, val.clone());
+        self.put(ident, val);
+    }
 }
 
 impl BoundingBox {
@@ -371,28 +618,237 @@ impl FontMap {
         FontMap {
             freetype: freetype::Library::init().expect("Failed to initialize Freetype."),
             fonts: HashMap::new(),
+            hb_fonts: HashMap::new(),
+            cr_faces: HashMap::new(),
+            shapings: HashMap::new(),
+            font_dirs: Vec::new(),
+            strict: false,
+        }
+    }
+
+    /// Make the fonts in a directory available, in addition to the fonts
+    /// installed on the system.
+    ///
+    /// This only affects fonts that have not been loaded yet.
+    pub fn add_font_dir(&mut self, dir: &Path) -> Result<()> {
+        if self.font_dirs.iter().any(|d| d == dir) {
+            return Ok(())
+        }
+        if !fontconfig::add_font_dir(dir) {
+            return Err(Error::missing_file(dir.to_string_lossy().into_owned()))
         }
+        self.font_dirs.push(dir.to_path_buf());
+        Ok(())
     }
 
-    pub fn get(&mut self, family: &str, style: &str) -> Option<&mut freetype::Face<'static>> {
-        let key = (family.to_string(), style.to_string());
+    /// Make font warnings, such as a missing font or glyph, errors.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
 
-        let entry = match self.fonts.entry(key) {
-            Entry::Occupied(x) => return Some(x.into_mut()),
-            Entry::Vacant(x) => x,
-        };
+    /// Report a font-related warning, or fail in strict mode.
+    pub fn warn(&self, warning: Warning) -> Result<()> {
+        if self.strict {
+            Err(Error::Warning(warning))
+        } else {
+            warning.print();
+            Ok(())
+        }
+    }
+
+    pub fn get(&mut self, spec: &FontSpec) -> Result<&mut freetype::Face<'static>> {
+        if !self.fonts.contains_key(spec) && is_font_file(&spec.family) {
+            // The family is a path to a font file, which we load directly,
+            // bypassing Fontconfig. The weight, slant and stretch are ignored;
+            // the file determines them.
+            let font_fname = PathBuf::from(&spec.family);
+            if !font_fname.is_file() {
+                return Err(Error::missing_file(spec.family.clone()))
+            }
+            let ft_face = match self.freetype.new_face(&font_fname, 0) {
+                Ok(face) => face,
+                Err(..) => {
+                    let msg = format!("Failed to load font file '{}'.", spec.family);
+                    return Err(Error::Other(msg))
+                }
+            };
+            ft_face.set_char_size(0, 1000, 72, 72).unwrap();
+            self.insert_face(spec, ft_face, true)?;
+        }
+
+        if !self.fonts.contains_key(spec) {
+            // We don't have the font already, look up the file and load it
+            // with Freetype.
+            let font_match = match fontconfig::get_font_location(spec) {
+                Some(font_match) => font_match,
+                None => return Err(Error::missing_font(spec)),
+            };
+
+            // Fontconfig always returns the closest match, even when it is
+            // quite different from what was requested. Warn about that
+            // once, when the font is loaded.
+            if !font_match.has_family(&spec.family) ||
+               font_match.spec.weight != spec.weight ||
+               font_match.spec.slant != spec.slant ||
+               font_match.spec.stretch != spec.stretch {
+                self.warn(Warning::font_mismatch(spec.clone(), font_match.spec.clone()))?;
+            }
+
+            let ft_face = self.load_face(font_match.path);
+            self.insert_face(spec, ft_face, true)?;
+        }
+
+        Ok(self.fonts.get_mut(spec).unwrap())
+    }
+
+    /// Shapes a string using a single font, without any fallback.
+    ///
+    /// Features use the Harfbuzz syntax, see `harfbuzz::Buffer::shape`.
+    pub fn shape(&mut self,
+                 spec: &FontSpec,
+                 direction: harfbuzz::Direction,
+                 features: &[&str],
+                 text: &str)
+                 -> Result<Vec<harfbuzz::Glyph>> {
+        let key = (spec.clone(),
+                   direction,
+                   features.iter().map(|f| f.to_string()).collect(),
+                   text.to_string());
+        if let Some(glyphs) = self.shapings.get(&key) {
+            return Ok(glyphs.clone())
+        }
+
+        self.get_hb_font(spec)?;
+        let hb_font = self.hb_fonts.get_mut(spec).unwrap();
+
+        // Shape the text using Harfbuzz: convert the UTF-8 string and input
+        // font into a list of glyphs with offsets.
+        let mut hb_buffer = harfbuzz::Buffer::new(direction);
+        hb_buffer.add_str(text);
+        hb_buffer.shape(hb_font, features);
+        let glyphs = hb_buffer.glyphs();
 
-        // We don't have the font already, look up the file and load it with
-        // Freetype.
+        if self.shapings.len() >= MAX_SHAPINGS {
+            self.shapings.clear();
+        }
+        self.shapings.insert(key, glyphs.clone());
+        Ok(glyphs)
+    }
 
-        let mut query = family.to_string();
-        query.push_str(":style=");
-        query.push_str(style);
-        let font_fname = match fontconfig::get_font_location(&query) {
-            Some(fname) => fname,
+    /// Returns the Harfbuzz font for a spec, loading the font if needed.
+    pub fn get_hb_font(&mut self, spec: &FontSpec) -> Result<&mut harfbuzz::Font> {
+        if !self.hb_fonts.contains_key(spec) {
+            let mut hb_font = harfbuzz::Font::from_ft_face(self.get(spec)?);
+            hb_font.set_variations(&spec.variations);
+            self.hb_fonts.insert(spec.clone(), hb_font);
+        }
+        Ok(self.hb_fonts.get_mut(spec).unwrap())
+    }
+
+    /// Returns the Cairo font face for a font that has been loaded before.
+    pub fn get_cairo_face(&mut self, spec: &FontSpec) -> Result<&cairo::FontFace> {
+        if !self.cr_faces.contains_key(spec) {
+            let cr_face = cairo::FontFace::from_ft_face(self.get(spec)?.clone());
+            self.cr_faces.insert(spec.clone(), cr_face);
+        }
+        Ok(self.cr_faces.get(spec).unwrap())
+    }
+
+    /// Returns the spec of a font that has a glyph for `ch`.
+    ///
+    /// The families in `preferred` are tried first, in order. If none of them
+    /// has the glyph, Fontconfig is asked for a font that does, preferring
+    /// fonts similar to `spec`. The returned spec can be passed to `get` to
+    /// obtain the face.
+    pub fn get_fallback(&mut self,
+                        ch: char,
+                        spec: &FontSpec,
+                        preferred: &[String])
+                        -> Option<FontSpec> {
+        for family in preferred {
+            let candidate = spec.with_family(family);
+
+            if !self.fonts.contains_key(&candidate) {
+                // Only consider preferred families that are installed, do not
+                // let Fontconfig substitute a different family, unless the
+                // family is a generic one such as "serif".
+                match fontconfig::get_font_location(&candidate) {
+                    Some(ref font_match) if font_match.has_family(family) => {
+                        let ft_face = self.load_face(font_match.path.clone());
+                        self.insert_face(&candidate, ft_face, false).unwrap();
+                    }
+                    _ => continue,
+                }
+            }
+
+            if has_glyph(self.fonts.get_mut(&candidate).unwrap(), ch) {
+                return Some(candidate)
+            }
+        }
+
+        let font_match = match fontconfig::get_font_location_for_char(spec, ch) {
+            Some(font_match) => font_match,
             None => return None,
         };
 
+        // Register the face under its own family name, so later lookups (when
+        // rendering, for instance) find this face without querying again.
+        let fallback = spec.with_family(&font_match.spec.family);
+        if !self.fonts.contains_key(&fallback) {
+            let ft_face = self.load_face(font_match.path);
+            self.insert_face(&fallback, ft_face, false).unwrap();
+        }
+
+        Some(fallback)
+    }
+
+    /// Applies the variations of the spec to the face, and stores the face.
+    ///
+    /// If `validate` is true, an axis that the font does not have, or a value
+    /// outside of the range of an axis, is an error. Otherwise such axes are
+    /// ignored and values are clamped, which is what we want for fallback
+    /// fonts, which were not chosen by the user.
+    fn insert_face(&mut self,
+                   spec: &FontSpec,
+                   mut ft_face: freetype::Face<'static>,
+                   validate: bool)
+                   -> Result<()> {
+        if spec.variations.len() > 0 {
+            let axes = variations::get_axes(&mut ft_face);
+            let mut coords: Vec<f64> = axes.iter().map(|a| a.default).collect();
+
+            for variation in &spec.variations {
+                let value = variations::from_fixed(variation.value);
+                match axes.iter().position(|a| a.tag == variation.tag) {
+                    Some(i) => {
+                        let axis = &axes[i];
+                        if validate && (value < axis.minimum || value > axis.maximum) {
+                            let msg = format!("Axis '{}' of font {} must be between {} and {}, \
+                                               but it is {}.",
+                                              variations::tag_to_string(axis.tag),
+                                              describe_font(spec), axis.minimum,
+                                              axis.maximum, value);
+                            return Err(Error::value(msg))
+                        }
+                        coords[i] = value.max(axis.minimum).min(axis.maximum);
+                    }
+                    None if validate => {
+                        return Err(unknown_axis_error(spec, variation.tag, &axes))
+                    }
+                    None => {}
+                }
+            }
+
+            if axes.len() > 0 {
+                variations::set_coordinates(&mut ft_face, &coords);
+            }
+        }
+
+        self.fonts.insert(spec.clone(), ft_face);
+        Ok(())
+    }
+
+    fn load_face(&self, font_fname: PathBuf) -> freetype::Face<'static> {
         let ft_face = self.freetype
             .new_face(font_fname, 0)
             .expect("Failed to load font using Freetype.");
@@ -403,11 +859,60 @@ impl FontMap {
         // Freetype bug tracker.
         ft_face.set_char_size(0, 1000, 72, 72).unwrap();
 
-        let ft_face_ref = entry.insert(ft_face);
-        Some(ft_face_ref)
+        ft_face
+    }
+}
+
+impl Drop for FontMap {
+    fn drop(&mut self) {
+        // The Harfbuzz fonts and Cairo font faces hold pointers to the
+        // FreeType faces, which in turn belong to the FreeType library. Fields
+        // are dropped in declaration order, so release them explicitly, in the
+        // reverse order of their dependencies.
+        self.hb_fonts.clear();
+        self.cr_faces.clear();
+        self.fonts.clear();
     }
 }
 
+fn describe_font(spec: &FontSpec) -> String {
+    let mut f = Formatter::new();
+    f.print(spec);
+    f.into_string()
+}
+
+fn unknown_axis_error(spec: &FontSpec, tag: u32, axes: &[variations::Axis]) -> Error {
+    let mut msg = format!("Font {} has no variation axis '{}'. ",
+                          describe_font(spec), variations::tag_to_string(tag));
+    if axes.len() == 0 {
+        msg.push_str("It is not a variable font.");
+    } else {
+        msg.push_str("Available axes are ");
+        for (i, axis) in axes.iter().enumerate() {
+            if i > 0 { msg.push_str(", "); }
+            msg.push_str(&format!("'{}' ({} to {})",
+                                  variations::tag_to_string(axis.tag),
+                                  axis.minimum, axis.maximum));
+        }
+        msg.push_str(".");
+    }
+    Error::value(msg)
+}
+
+/// Returns whether a font family refers to a font file rather than a family.
+pub fn is_font_file(family: &str) -> bool {
+    let lower = family.to_lowercase();
+    lower.ends_with(".ttf") || lower.ends_with(".otf") || lower.ends_with(".ttc")
+}
+
+/// Returns whether the font has a glyph for the character.
+fn has_glyph(ft_face: &mut freetype::Face<'static>, ch: char) -> bool {
+    let index = unsafe {
+        freetype::freetype_sys::FT_Get_Char_Index(ft_face.raw_mut(), ch as c_ulong)
+    };
+    index != 0
+}
+
 // Pretty printers for values and interpreter data structures.
 
 impl<'a> Print for Val<'a> {
@@ -443,14 +948,173 @@ impl<'a> Print for Val<'a> {
             Val::Frame(ref frame) => {
                 f.print(frame);
             }
-            Val::FnExtrin(ref fndef) => {
-                f.print(fndef);
+            Val::FnExtrin(ref closure) => {
+                f.print(closure.def);
             }
             Val::FnIntrin(..) => {
                 f.print("function(...) { <built-in> }");
             }
+            Val::List(ref vals) => {
+                f.print("[");
+                let mut first = true;
+                for val in vals {
+                    if !first { f.print(", "); }
+                    f.print(val);
+                    first = false;
+                }
+                f.print("]");
+            }
+            Val::Record(ref fields) => {
+                f.print("{ ");
+                for &(ref key, ref val) in fields {
+                    f.print(&key[..]);
+                    f.print(" = ");
+                    f.print(val);
+                    f.print(", ");
+                }
+                f.print("}");
+            }
+        }
+    }
+}
+
+impl Print for FontSpec {
+    fn print(&self, f: &mut Formatter) {
+        f.print("'");
+        f.print(&self.family[..]);
+        f.print("' (weight ");
+        f.print(self.weight);
+        f.print(match self.slant {
+            Slant::Roman => ", roman",
+            Slant::Italic => ", italic",
+            Slant::Oblique => ", oblique",
+        });
+        f.print(", stretch ");
+        f.print(self.stretch);
+        f.print("%");
+        for variation in &self.variations {
+            f.print(", ");
+            f.print(&variations::tag_to_string(variation.tag)[..]);
+            f.print(" ");
+            f.print(variations::from_fixed(variation.value));
+        }
+        f.print(")");
+    }
+}
+
+/// The variables that the default environment defines, apart from the
+/// builtin functions. In a function body these come from the call site.
+const STYLE_VARS: &'static [&'static str] = &[
+    "font_size", "font_family", "font_weight", "font_slant", "font_stretch",
+    "font_variations", "font_fallback", "font_dirs", "text_align", "bullet",
+    "bullet_style", "bullet_indent", "bullet_spacing", "bullet_reveal",
+    "code_font_family", "code_theme", "code_line_numbers",
+    "code_highlight_lines", "math_font_family", "math_style", "chart_size",
+    "chart_colors", "chart_grid_color", "chart_line_width", "chart_marker_size",
+    "writing_mode", "line_height", "letter_spacing", "word_spacing", "kerning",
+    "text_decoration", "text_background", "line_width", "color",
+];
+
+#[test]
+fn style_vars_are_the_default_variables() {
+    let env = Env::new();
+    let is_style_var = |name: &str| STYLE_VARS.iter().any(|&var| var == name);
+    let mut num_vars = 0;
+    for (&name, val) in &env.bindings {
+        match *val {
+            Val::FnIntrin(..) => assert!(!is_style_var(name), "'{}' is a builtin.", name),
+            _ => {
+                assert!(is_style_var(name), "'{}' is missing from STYLE_VARS.", name);
+                num_vars += 1;
+            }
         }
     }
+    assert_eq!(STYLE_VARS.len(), num_vars);
+}
+
+/// Looks up the parts of a dotted identifier from index `i` onwards in a
+/// value. Frames look up the parts in their environment, records in their
+/// fields.
+fn lookup_path<'a>(val: &Val<'a>, idents: &Idents<'a>, i: usize) -> Result<Val<'a>> {
+    if i == idents.0.len() {
+        return Ok(val.clone())
+    }
+    match *val {
+        Val::Frame(ref frame) => frame.lookup(&Idents(idents.0[i..].to_vec())),
+        Val::Record(ref fields) => match get_field(fields, idents.0[i]) {
+            Some(field) => lookup_path(field, idents, i + 1),
+            None => {
+                let mut f = Formatter::new();
+                f.print("Cannot read variable '");
+                f.print(idents);
+                f.print("', because the record '");
+                f.print(&Idents(idents.0[..i].to_vec()));
+                f.print("' has no field '");
+                f.print(idents.0[i]);
+                f.print("'.");
+                Err(Error::Other(f.into_string()))
+            }
+        },
+        _ => {
+            let mut f = Formatter::new();
+            f.print("Type error while reading variable '");
+            f.print(idents);
+            f.print("'. Cannot look up '");
+            f.print(idents.0[i]);
+            f.print("' in '");
+            f.print(&Idents(idents.0[..i].to_vec()));
+            f.print("' because it is not a frame or record.");
+            Err(Error::Other(f.into_string()))
+        }
+    }
+}
+
+/// Matches the arguments of a call to the parameters of a function.
+///
+/// Positional arguments are matched to the parameters in order, and named
+/// arguments to the parameter with that name. Returns a value for every
+/// parameter, which is `None` if no argument was passed for it.
+pub fn bind_args<'a>(fn_name: &str,
+                     params: &[&str],
+                     args: Vec<Val<'a>>,
+                     named: NamedArgs<'a>)
+                     -> Result<Vec<Option<Val<'a>>>> {
+    if args.len() > params.len() {
+        return Err(Error::arity(fn_name, params.len() as u32, args.len() as u32))
+    }
+    let mut bound: Vec<Option<Val<'a>>> = args.into_iter().map(Some).collect();
+    while bound.len() < params.len() {
+        bound.push(None);
+    }
+    for (name, val) in named {
+        let i = match params.iter().position(|&p| p == name) {
+            Some(i) => i,
+            None => {
+                let msg = format!("'{}' has no parameter named '{}'.", fn_name, name);
+                return Err(Error::Other(msg))
+            }
+        };
+        if bound[i].is_some() {
+            let msg = format!("The argument '{}' of '{}' was given more than once.", name, fn_name);
+            return Err(Error::Other(msg))
+        }
+        bound[i] = Some(val);
+    }
+    Ok(bound)
+}
+
+/// Returns the value of the field with the given name in a record.
+pub fn get_field<'r, 'a>(fields: &'r [(String, Val<'a>)], name: &str) -> Option<&'r Val<'a>> {
+    fields.iter().find(|&&(ref key, _)| key == name).map(|&(_, ref val)| val)
+}
+
+/// Sets a field in a record, replacing the value if the field exists, and
+/// adding the field at the end otherwise.
+pub fn set_field<'a>(fields: &mut Vec<(String, Val<'a>)>, name: String, val: Val<'a>) {
+    match fields.iter().position(|&(ref key, _)| *key == name) {
+        Some(i) => fields[i].1 = val,
+        None => fields.push((name, val)),
+    }
 }
 
 impl Print for ValType {
@@ -462,6 +1126,8 @@ impl Print for ValType {
             ValType::Coord(d) => { f.print("coord of "); print_unit(f, d); }
             ValType::Frame => f.print("frame"),
             ValType::Fn => f.print("function"),
+            ValType::List => f.print("list"),
+            ValType::Record => f.print("record"),
         }
     }
 }
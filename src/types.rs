@@ -12,7 +12,9 @@ pub enum ValType {
     Color,
     Coord(LenDim),
     Frame,
-    Fn
+    Fn,
+    List,
+    Record,
 }
 
 /// Represents a number of length dimensions.
@@ -0,0 +1,215 @@
+// Pris -- A language for designing slides
+// Copyright 2017 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module contains support for variable fonts. It parses variation
+//! settings such as "wght=650, opsz=24", and applies them to FreeType faces
+//! through the FreeType multiple masters API, which is not exposed by the
+//! `freetype` crate.
+
+use freetype;
+use freetype::freetype_sys::{FT_Error, FT_Face, FT_Fixed, FT_Library, FT_String, FT_UInt, FT_ULong};
+use std::os::raw::c_void;
+use std::ptr;
+use std::result;
+use std::slice;
+
+use elements::Variation;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct FT_Var_Axis {
+    name: *mut FT_String,
+    minimum: FT_Fixed,
+    def: FT_Fixed,
+    maximum: FT_Fixed,
+    tag: FT_ULong,
+    strid: FT_UInt,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct FT_MM_Var {
+    num_axis: FT_UInt,
+    num_designs: FT_UInt,
+    num_namedstyles: FT_UInt,
+    axis: *mut FT_Var_Axis,
+    // This is a pointer to FT_Var_Named_Style, but we do not need named styles.
+    namedstyle: *mut c_void,
+}
+
+#[link(name = "freetype")]
+extern {
+    fn FT_Get_MM_Var(face: FT_Face, amaster: *mut *mut FT_MM_Var) -> FT_Error;
+    fn FT_Done_MM_Var(library: FT_Library, amaster: *mut FT_MM_Var) -> FT_Error;
+    fn FT_Set_Var_Design_Coordinates(face: FT_Face, num_coords: FT_UInt, coords: *mut FT_Fixed) -> FT_Error;
+}
+
+/// A variation axis declared by a font, such as weight or optical size.
+#[derive(Copy, Clone, Debug)]
+pub struct Axis {
+    pub tag: u32,
+    pub minimum: f64,
+    pub default: f64,
+    pub maximum: f64,
+}
+
+/// Packs a tag of at most four ASCII characters into a u32, the first
+/// character in the most significant byte. Shorter tags are padded with
+/// spaces, as OpenType prescribes.
+pub fn tag_from_str(tag: &str) -> Option<u32> {
+    if tag.len() == 0 || tag.len() > 4 || !tag.bytes().all(|b| b > 0x20 && b < 0x7f) {
+        return None
+    }
+    let mut result = 0;
+    for b in tag.bytes().chain(b"    ".iter().cloned()).take(4) {
+        result = (result << 8) | b as u32;
+    }
+    Some(result)
+}
+
+/// Converts a packed tag back into a string, without trailing spaces.
+pub fn tag_to_string(tag: u32) -> String {
+    let bytes = [(tag >> 24) as u8, (tag >> 16) as u8, (tag >> 8) as u8, tag as u8];
+    String::from_utf8_lossy(&bytes).trim_right().to_string()
+}
+
+#[test]
+fn tag_from_str_pads_with_spaces() {
+    assert_eq!(tag_from_str("wght"), Some(0x77676874));
+    assert_eq!(tag_from_str("ab"), Some(0x61622020));
+    assert_eq!(tag_to_string(0x61622020), "ab");
+}
+
+#[test]
+fn tag_from_str_rejects_invalid_tags() {
+    assert_eq!(tag_from_str(""), None);
+    assert_eq!(tag_from_str("weight"), None);
+    assert_eq!(tag_from_str("w t"), None);
+}
+
+/// Parses variation settings of the form "wght=650, opsz=24".
+///
+/// On failure, returns a message that describes the problem.
+pub fn parse(settings: &str) -> result::Result<Vec<Variation>, String> {
+    let mut variations: Vec<Variation> = Vec::new();
+
+    for part in settings.split(',') {
+        let part = part.trim();
+        if part.len() == 0 {
+            continue
+        }
+
+        let mut tag_value = part.splitn(2, '=');
+        let tag_str = tag_value.next().unwrap().trim();
+        let value_str = match tag_value.next() {
+            Some(v) => v.trim(),
+            None => return Err(format!("Expected 'axis=value', but found '{}'.", part)),
+        };
+
+        let tag = match tag_from_str(tag_str) {
+            Some(t) => t,
+            None => return Err(format!("'{}' is not a valid axis tag. \
+                                        Axis tags consist of four characters, \
+                                        such as 'wght'.", tag_str)),
+        };
+        let value = match value_str.parse::<f64>() {
+            Ok(v) => v,
+            Err(..) => return Err(format!("Expected a number for axis '{}', \
+                                           but found '{}'.", tag_str, value_str)),
+        };
+
+        // If an axis is specified twice, the last value wins.
+        variations.retain(|v| v.tag != tag);
+        variations.push(Variation {
+            tag: tag,
+            value: to_fixed(value),
+        });
+    }
+
+    Ok(variations)
+}
+
+#[test]
+fn parse_handles_multiple_axes() {
+    let vs = parse("wght=650, opsz=24.5").unwrap();
+    assert_eq!(vs.len(), 2);
+    assert_eq!(tag_to_string(vs[0].tag), "wght");
+    assert_eq!(from_fixed(vs[0].value), 650.0);
+    assert_eq!(tag_to_string(vs[1].tag), "opsz");
+    assert_eq!(from_fixed(vs[1].value), 24.5);
+}
+
+#[test]
+fn parse_accepts_empty_settings() {
+    assert_eq!(parse("").unwrap().len(), 0);
+}
+
+#[test]
+fn to_settings_string_roundtrips() {
+    let vs = parse("wght = 650,opsz=24.5").unwrap();
+    assert_eq!(to_settings_string(&vs), "wght=650,opsz=24.5");
+}
+
+#[test]
+fn parse_rejects_missing_value() {
+    assert!(parse("wght").is_err());
+    assert!(parse("wght=bold").is_err());
+}
+
+/// Formats variations in the way Cairo expects them, e.g. "wght=650,opsz=24".
+pub fn to_settings_string(variations: &[Variation]) -> String {
+    let parts: Vec<String> = variations.iter().map(|v| {
+        format!("{}={}", tag_to_string(v.tag), from_fixed(v.value))
+    }).collect();
+    parts.join(",")
+}
+
+/// Converts to 16.16 fixed point, the representation that FreeType uses.
+pub fn to_fixed(x: f64) -> i32 {
+    (x * 65536.0).round() as i32
+}
+
+/// Converts from 16.16 fixed point.
+pub fn from_fixed(x: i32) -> f64 {
+    x as f64 / 65536.0
+}
+
+/// Returns the variation axes of the face. Returns an empty list for fonts
+/// that are not variable fonts.
+pub fn get_axes(ft_face: &mut freetype::Face<'static>) -> Vec<Axis> {
+    unsafe {
+        let face: FT_Face = ft_face.raw_mut();
+        let mut mm_var: *mut FT_MM_Var = ptr::null_mut();
+        if FT_Get_MM_Var(face, &mut mm_var) != 0 {
+            return Vec::new()
+        }
+
+        let axes = slice::from_raw_parts((*mm_var).axis, (*mm_var).num_axis as usize);
+        let result = axes.iter().map(|a| Axis {
+            tag: a.tag as u32,
+            minimum: from_fixed(a.minimum as i32),
+            default: from_fixed(a.def as i32),
+            maximum: from_fixed(a.maximum as i32),
+        }).collect();
+
+        // The library that owns the face is referenced by its glyph slot.
+        FT_Done_MM_Var((*(*face).glyph).library, mm_var);
+
+        result
+    }
+}
+
+/// Sets the design coordinates of the face, one per axis, in axis order.
+pub fn set_coordinates(ft_face: &mut freetype::Face<'static>, coords: &[f64]) {
+    let mut fixed_coords: Vec<FT_Fixed> = coords.iter().map(|&c| to_fixed(c) as FT_Fixed).collect();
+    let error = unsafe {
+        FT_Set_Var_Design_Coordinates(ft_face.raw_mut(),
+                                      fixed_coords.len() as FT_UInt,
+                                      fixed_coords.as_mut_ptr())
+    };
+    assert_eq!(error, 0, "Failed to set font variation coordinates.");
+}
@@ -13,6 +13,12 @@ import os.path
 
 root_dir = os.path.join(os.path.dirname(__file__), '..')
 
+# Examples that need a font which is not available as a package, and which must
+# be put in examples/fonts manually. These are skipped if the font is missing.
+required_fonts = {
+    'font_variations.pris': 'fonts/Cantarell-VF.otf',
+}
+
 
 def run_pris(fname):
     """ Run Pris on the given file. Stops at a nonzero exit code. """
@@ -29,5 +35,10 @@ def run_pris(fname):
 
 
 for fname in os.listdir(os.path.join(root_dir, 'examples')):
-    if fname.endswith('.pris'):
-        run_pris(os.path.normpath(os.path.join(root_dir, 'examples', fname)))
+    if not fname.endswith('.pris'):
+        continue
+    font = required_fonts.get(fname)
+    if font is not None and not os.path.isfile(os.path.join(root_dir, 'examples', font)):
+        print('SKIPPED {} (requires examples/{})'.format(fname, font))
+        continue
+    run_pris(os.path.normpath(os.path.join(root_dir, 'examples', fname)))